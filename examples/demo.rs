@@ -0,0 +1,436 @@
+use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use winit::application::ApplicationHandler;
+use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Window, WindowId};
+
+use cinder::ash::util::read_spv;
+use cinder::ash::vk;
+use cinder::trace::ChromeTrace;
+use cinder::{
+    Buffer, ColorSpace, DecodedImage, DrawIndexed, IndexBuffer, Indices, MeshPipeline,
+    MeshPipelineDesc, Mipmaps, Options, SamplerDesc, SequenceOutput, ShaderSource, Texture,
+    TextureSet, VertexAttribute, VertexBinding, Vulkan,
+};
+
+// Set to a file path to record a Chrome trace of the run, written on exit.
+const TRACE_ENV_VAR: &str = "CINDER_TRACE";
+// Saves the next frame to a PNG in the working directory.
+const SCREENSHOT_KEY: KeyCode = KeyCode::F12;
+// Compiled from the GLSL next to them with `shaders/compile.sh`.
+const INSTANCED_VERTEX_SHADER: &[u8] = include_bytes!("shaders/instanced.vert.spv");
+const INSTANCED_FRAGMENT_SHADER: &[u8] = include_bytes!("shaders/instanced.frag.spv");
+// The quads are laid out in a square grid.
+const QUAD_GRID_SIZE: u32 = 5;
+// Texels along each side of the checkerboard the quads are textured with.
+const CHECKERBOARD_SIZE: u32 = 4;
+// Texels along each side of the skybox's faces.
+const SKYBOX_FACE_SIZE: u32 = 512;
+// How fast the view of the skybox turns, in radians per second.
+const SKYBOX_TURN_RATE: f32 = 0.1;
+
+fn to_bytes(values: &[f32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect()
+}
+
+// A grid of textured quads drawn as instances of one indexed mesh, each instance with its own
+// offset and colour.
+struct InstancedQuads {
+    pipeline: MeshPipeline,
+    corners: Buffer,
+    instances: Buffer,
+    indices: IndexBuffer,
+    texture: Texture,
+    texture_set: TextureSet,
+}
+
+impl InstancedQuads {
+    // The quads show `image` if given, else a checkerboard.
+    fn new(vulkan: &mut Vulkan, image: Option<&DecodedImage>) -> Result<Self, anyhow::Error> {
+        let vertex_shader = read_spv(&mut Cursor::new(INSTANCED_VERTEX_SHADER))?;
+        let fragment_shader = read_spv(&mut Cursor::new(INSTANCED_FRAGMENT_SHADER))?;
+        let pipeline = vulkan.create_mesh_pipeline(&MeshPipelineDesc {
+            vertex_shader: ShaderSource::Spirv(&vertex_shader),
+            fragment_shader: ShaderSource::Spirv(&fragment_shader),
+            bindings: &[
+                VertexBinding {
+                    stride: 8,
+                    input_rate: vk::VertexInputRate::VERTEX,
+                    attributes: vec![VertexAttribute {
+                        location: 0,
+                        format: vk::Format::R32G32_SFLOAT,
+                        offset: 0,
+                    }],
+                },
+                VertexBinding {
+                    stride: 20,
+                    input_rate: vk::VertexInputRate::INSTANCE,
+                    attributes: vec![
+                        VertexAttribute {
+                            location: 1,
+                            format: vk::Format::R32G32_SFLOAT,
+                            offset: 0,
+                        },
+                        VertexAttribute {
+                            location: 2,
+                            format: vk::Format::R32G32B32_SFLOAT,
+                            offset: 8,
+                        },
+                    ],
+                },
+            ],
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            options: Default::default(),
+            textures: 1,
+            bindless: false,
+            push_constants: 0,
+        })?;
+        let corners = vulkan.create_vertex_buffer(&to_bytes(&[
+            -0.05, -0.05, 0.05, -0.05, 0.05, 0.05, -0.05, 0.05,
+        ]))?;
+        let mut instances = Vec::new();
+        for row in 0..QUAD_GRID_SIZE {
+            for column in 0..QUAD_GRID_SIZE {
+                let x = column as f32 / (QUAD_GRID_SIZE - 1) as f32;
+                let y = row as f32 / (QUAD_GRID_SIZE - 1) as f32;
+                instances.extend([x * 1.2 - 0.6, y * 1.2 - 0.6, x, y, 1.0 - x]);
+            }
+        }
+        let instances = vulkan.create_vertex_buffer(&to_bytes(&instances))?;
+        let indices = vulkan.create_index_buffer(Indices::U16(&[0, 1, 2, 2, 3, 0]))?;
+        let (texture, mag_filter) = match image {
+            Some(image) => (
+                vulkan.create_texture_from_image(image, Mipmaps::Generate)?,
+                vk::Filter::LINEAR,
+            ),
+            None => {
+                let mut texels = Vec::new();
+                for row in 0..CHECKERBOARD_SIZE {
+                    for column in 0..CHECKERBOARD_SIZE {
+                        let value = if (row + column) % 2 == 0 { 255 } else { 96 };
+                        texels.extend([value, value, value, 255]);
+                    }
+                }
+                let checkerboard = vulkan.create_texture(
+                    vk::Extent2D {
+                        width: CHECKERBOARD_SIZE,
+                        height: CHECKERBOARD_SIZE,
+                    },
+                    vk::Format::R8G8B8A8_UNORM,
+                    &texels,
+                    Mipmaps::None,
+                )?;
+                (checkerboard, vk::Filter::NEAREST)
+            }
+        };
+        let sampler = vulkan.sampler(&SamplerDesc {
+            mag_filter,
+            ..SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        })?;
+        let texture_set = vulkan.create_texture_set(&pipeline, &[(&texture, sampler)])?;
+        Ok(Self {
+            pipeline,
+            corners,
+            instances,
+            indices,
+            texture,
+            texture_set,
+        })
+    }
+
+    fn record(&self, vulkan: &Vulkan, commandbuffer: vk::CommandBuffer) {
+        vulkan.bind_mesh_pipeline(commandbuffer, &self.pipeline);
+        vulkan.bind_texture_set(commandbuffer, &self.pipeline, &self.texture_set);
+        vulkan.bind_vertex_buffers(commandbuffer, 0, &[&self.corners, &self.instances]);
+        vulkan.bind_index_buffer(commandbuffer, &self.indices);
+        vulkan.draw_indexed(
+            commandbuffer,
+            &DrawIndexed {
+                instance_count: QUAD_GRID_SIZE * QUAD_GRID_SIZE,
+                ..DrawIndexed::all(&self.indices)
+            },
+        );
+    }
+
+    fn destroy(self, vulkan: &mut Vulkan) {
+        vulkan.destroy_mesh_pipeline(self.pipeline);
+        vulkan.destroy_buffer(self.corners);
+        vulkan.destroy_buffer(self.instances);
+        vulkan.destroy_buffer(self.indices.buffer);
+        vulkan.destroy_texture_set(self.texture_set);
+        vulkan.destroy_texture(self.texture);
+    }
+}
+
+// Renders `--frames N` frames headlessly at `--fps` (default 60) and `--size WxH` (default
+// 1280x720), writing numbered PNGs to `--output DIR` (default `frames`) or, with `--ffmpeg FILE`,
+// encoding them with ffmpeg.  Without `--frames`, opens a window as usual.
+struct OfflineOptions {
+    frames: u64,
+    fps: f64,
+    extent: vk::Extent2D,
+    output: SequenceOutput,
+}
+
+impl OfflineOptions {
+    fn parse(args: &[String]) -> Result<Option<Self>, anyhow::Error> {
+        let value = |flag: &str| {
+            args.iter()
+                .position(|arg| arg == flag)
+                .map(|index| {
+                    args.get(index + 1)
+                        .ok_or_else(|| anyhow::anyhow!("{} needs a value.", flag))
+                })
+                .transpose()
+        };
+        let Some(frames) = value("--frames")? else {
+            return Ok(None);
+        };
+        let extent = match value("--size")? {
+            Some(size) => {
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or_else(|| anyhow::anyhow!("--size must look like 1280x720."))?;
+                vk::Extent2D {
+                    width: width.parse()?,
+                    height: height.parse()?,
+                }
+            }
+            None => vk::Extent2D {
+                width: 1280,
+                height: 720,
+            },
+        };
+        let output = match value("--ffmpeg")? {
+            Some(path) => SequenceOutput::Ffmpeg {
+                path: PathBuf::from(path),
+            },
+            None => SequenceOutput::Images {
+                directory: PathBuf::from(value("--output")?.map_or("frames", |output| output)),
+            },
+        };
+        Ok(Some(Self {
+            frames: frames.parse()?,
+            fps: value("--fps")?.map_or(Ok(60.0), |fps| fps.parse())?,
+            extent,
+            output,
+        }))
+    }
+}
+
+fn render_offline(options: OfflineOptions) -> Result<(), anyhow::Error> {
+    let mut vulkan = Vulkan::new_headless(
+        options.extent,
+        Options {
+            msaa_samples: 4,
+            ..Default::default()
+        },
+    )?;
+    vulkan.render_sequence(
+        options.frames,
+        Duration::from_secs_f64(1.0 / options.fps),
+        &options.output,
+    )?;
+    println!("Rendered {} frames.", options.frames);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(options) = OfflineOptions::parse(&args)? {
+        return render_offline(options);
+    }
+
+    let event_loop = EventLoop::new().expect("Failed to create event loop.");
+    event_loop.set_control_flow(ControlFlow::Poll);
+
+    let mut app = Application::default();
+    if let Some(path) = args
+        .iter()
+        .position(|arg| arg == "--texture")
+        .and_then(|index| args.get(index + 1))
+    {
+        app.texture_image = Some(load_texture_image(PathBuf::from(path)).await?);
+    }
+    app.skybox_path = args
+        .iter()
+        .position(|arg| arg == "--skybox")
+        .and_then(|index| args.get(index + 1))
+        .map(PathBuf::from);
+    // cinder's warnings and validation messages are logged through `tracing`.
+    app.trace =
+        std::env::var_os(TRACE_ENV_VAR).map(|path| (ChromeTrace::new(), PathBuf::from(path)));
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(app.trace.as_ref().map(|(trace, _)| trace.layer()));
+    tracing::subscriber::set_global_default(subscriber)?;
+    event_loop.run_app(&mut app)?;
+
+    Ok(())
+}
+
+// Images are decoded on tokio's blocking pool when cinder is built with the `async` feature.
+async fn load_texture_image(path: PathBuf) -> Result<DecodedImage, anyhow::Error> {
+    #[cfg(feature = "async")]
+    return cinder::load_image_async(path, ColorSpace::Srgb).await;
+    #[cfg(not(feature = "async"))]
+    cinder::load_image(&path, ColorSpace::Srgb)
+}
+
+#[derive(Default)]
+struct Application {
+    window: Option<Window>,
+    vulkan: Option<Vulkan>,
+    quads: Option<Rc<InstancedQuads>>,
+    // Shown on the quads instead of a checkerboard, from `--texture FILE`.
+    texture_image: Option<DecodedImage>,
+    // An equirectangular image shown behind the quads, from `--skybox FILE`.
+    skybox_path: Option<PathBuf>,
+    skybox: Option<Texture>,
+    trace: Option<(ChromeTrace, PathBuf)>,
+}
+
+// From https://docs.rs/winit/0.30.9/winit/index.html
+impl ApplicationHandler for Application {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let window = event_loop
+            .create_window(Window::default_attributes())
+            .expect("Failed to create window.");
+
+        let raw_display_handle = window
+            .display_handle()
+            .expect("Failed to get display handle.");
+        let raw_window_handle = window
+            .window_handle()
+            .expect("Failed to get window handle.");
+
+        let mut vulkan = Vulkan::new(
+            &raw_display_handle,
+            &raw_window_handle,
+            Options {
+                msaa_samples: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let quads = Rc::new(
+            InstancedQuads::new(&mut vulkan, self.texture_image.as_ref())
+                .expect("Failed to create quads."),
+        );
+        let scene_quads = quads.clone();
+        vulkan.set_scene_draws(move |vulkan, commandbuffer| {
+            scene_quads.record(vulkan, commandbuffer)
+        });
+
+        if let Some(path) = &self.skybox_path {
+            let cubemap = vulkan
+                .load_equirect_cubemap(path, SKYBOX_FACE_SIZE, Mipmaps::Generate)
+                .expect("Failed to load the skybox.");
+            let sampler = vulkan
+                .sampler(
+                    &SamplerDesc::default()
+                        .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                )
+                .expect("Failed to create the skybox sampler.");
+            vulkan
+                .set_skybox(&cubemap, sampler)
+                .expect("Failed to set the skybox.");
+            self.skybox = Some(cubemap);
+        }
+
+        self.window = Some(window);
+        self.vulkan = Some(vulkan);
+        self.quads = Some(quads);
+
+        // Thanks https://github.com/adrian-afl/vengine-rs/blob/main/src/window/window.rs
+        self.window.as_ref().unwrap().request_redraw();
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => {
+                event_loop.exit();
+            }
+            WindowEvent::Resized(size) => {
+                self.vulkan
+                    .as_mut()
+                    .unwrap()
+                    .resize_to(vk::Extent2D {
+                        width: size.width,
+                        height: size.height,
+                    })
+                    .expect("Failed to resize swapchain.");
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(SCREENSHOT_KEY),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let vulkan = self.vulkan.as_mut().unwrap();
+                let path = PathBuf::from(format!("cinder-{}.png", vulkan.frame_number() + 1));
+                match vulkan
+                    .capture_frame()
+                    .and_then(|screenshot| screenshot.save_png(&path))
+                {
+                    Ok(()) => println!("Saved screenshot to {}.", path.display()),
+                    Err(err) => println!("Failed to save screenshot: {}", err),
+                }
+            }
+            WindowEvent::RedrawRequested => {
+                let vulkan = self.vulkan.as_mut().unwrap();
+                // Turns slowly about the vertical axis, looking along +Z to begin with.
+                let angle = vulkan.frame_time().time.as_secs_f32() * SKYBOX_TURN_RATE;
+                let (sin, cos) = angle.sin_cos();
+                vulkan.set_skybox_view([
+                    cos, 0.0, -sin, 0.0, 0.0, 1.0, 0.0, 0.0, sin, 0.0, cos, 0.0, 0.0, 0.0, 0.0, 1.0,
+                ]);
+                vulkan.render();
+                if let Some((trace, _)) = &self.trace {
+                    trace.add_gpu_timelines(vulkan.take_gpu_timelines());
+                }
+                self.window.as_ref().unwrap().request_redraw();
+            }
+            _ => (),
+        }
+
+        self.window.as_ref().unwrap().request_redraw();
+    }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        if let (Some(vulkan), Some(quads)) = (self.vulkan.as_mut(), self.quads.take()) {
+            // Dropping the scene draws releases their hold on the quads.
+            vulkan.set_scene_draws(|_, _| ());
+            if let Ok(quads) = Rc::try_unwrap(quads) {
+                quads.destroy(vulkan);
+            }
+        }
+        if let (Some(vulkan), Some(skybox)) = (self.vulkan.as_mut(), self.skybox.take()) {
+            vulkan.clear_skybox();
+            vulkan.destroy_texture(skybox);
+        }
+        if let Some((trace, path)) = &self.trace {
+            match trace.write(path) {
+                Ok(()) => println!("Wrote trace to {}.", path.display()),
+                Err(err) => println!("Failed to write trace to {}: {}", path.display(), err),
+            }
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#version 450
+
+layout (set=0, binding=0) uniform sampler2D checkerboard;
+
+layout (location=0) in vec3 fragColour;
+layout (location=1) in vec2 fragTexCoord;
+
+layout (location=0) out vec4 theColour;
+
+void main() {
+    theColour = vec4(fragColour * texture(checkerboard, fragTexCoord).rgb, 1.0);
+}
@@ -0,0 +1,15 @@
+#version 450
+
+layout (location=0) in vec2 corner;
+layout (location=1) in vec2 offset;
+layout (location=2) in vec3 colour;
+
+layout (location=0) out vec3 fragColour;
+layout (location=1) out vec2 fragTexCoord;
+
+void main() {
+    gl_Position = vec4(corner + offset, 0.5, 1.0);
+    fragColour = colour;
+    // The quad's corners are at +-0.05.
+    fragTexCoord = corner * 10.0 + 0.5;
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Compiles cinder's shaders, and the demo's, to the SPIR-V built into them.  Needs glslc from the
+# Vulkan SDK.
+set -e
+cd "$(dirname "$0")/.."
+for source in shaders/*.vert shaders/*.frag shaders/*.comp examples/shaders/*.vert examples/shaders/*.frag; do
+    glslc --target-env=vulkan1.2 -O -o "$source.spv" "$source"
+done
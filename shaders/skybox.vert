@@ -0,0 +1,17 @@
+#version 450
+
+// One triangle covering the target, on the far plane.  The inverse view-projection matrix turns
+// each corner back into the direction it looks in, which is interpolated across the triangle.
+
+layout (push_constant) uniform Camera {
+    mat4 inverse_view_projection;
+};
+
+layout (location = 0) out vec3 direction;
+
+void main() {
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    gl_Position = vec4(position, 1.0, 1.0);
+    vec4 world = inverse_view_projection * vec4(position, 1.0, 1.0);
+    direction = world.xyz / world.w;
+}
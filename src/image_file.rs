@@ -0,0 +1,145 @@
+// Decoding PNG, JPEG and Radiance HDR files into pixels a texture can be created from.  The
+// format chosen depends on what the file holds and what it is for: colors meant to be seen are
+// sRGB encoded and are decoded to sRGB formats, which the sampler converts back to linear, while
+// data such as normal maps is sampled as it is stored.  KTX2 files are read as they are stored,
+// in the Vulkan format they name, including block-compressed formats and prebuilt mip chains.
+
+use anyhow::anyhow;
+use ash::vk;
+use image::{ColorType, DynamicImage, ImageReader};
+use std::io::Cursor;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    // Colors, e.g. albedo.  8-bit formats are decoded as sRGB.
+    Srgb,
+    // Data, e.g. normals or roughness, sampled as it is stored.
+    Linear,
+}
+
+// A decoded image, tightly packed rows top to bottom, ready for `Vulkan::create_texture`.
+pub struct DecodedImage {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub pixels: Vec<u8>,
+}
+
+pub fn decode_image(bytes: &[u8], color_space: ColorSpace) -> Result<DecodedImage, anyhow::Error> {
+    let image = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?;
+    Ok(convert(image, color_space))
+}
+
+pub fn load_image(path: &Path, color_space: ColorSpace) -> Result<DecodedImage, anyhow::Error> {
+    let image = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| anyhow!("Failed to open {}: {}", path.display(), err))?
+        .decode()
+        .map_err(|err| anyhow!("Failed to decode {}: {}", path.display(), err))?;
+    Ok(convert(image, color_space))
+}
+
+// As `load_image`, decoding on tokio's blocking thread pool so the caller's task isn't held up.
+// The result still has to be uploaded on the thread rendering with `Vulkan`.
+#[cfg(feature = "async")]
+pub async fn load_image_async(
+    path: std::path::PathBuf,
+    color_space: ColorSpace,
+) -> Result<DecodedImage, anyhow::Error> {
+    tokio::task::spawn_blocking(move || load_image(&path, color_space)).await?
+}
+
+// The levels of a KTX2 file, largest first, each tightly packed rows (or rows of blocks) top to
+// bottom.  Each level of a cubemap holds its six faces back to back.
+pub struct Ktx2Image {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    // 1, or 6 for cubemaps.
+    pub faces: u32,
+    pub levels: Vec<Vec<u8>>,
+    // The file holds only the base level and asks for the rest of the chain to be generated.
+    pub generate_mipmaps: bool,
+}
+
+// Only 2D images and cubemaps without supercompression can be read.  Basis Universal files, which have no
+// Vulkan format until transcoded, aren't supported.
+pub fn decode_ktx2(bytes: &[u8]) -> Result<Ktx2Image, anyhow::Error> {
+    let reader = ktx2::Reader::new(bytes)?;
+    let header = reader.header();
+    if let Some(scheme) = header.supercompression_scheme {
+        return Err(anyhow!(
+            "KTX2 supercompression {:?} isn't supported.",
+            scheme
+        ));
+    }
+    let format = header
+        .format
+        .map(|format| vk::Format::from_raw(format.value() as i32))
+        .ok_or_else(|| anyhow!("KTX2 files without a Vulkan format aren't supported."))?;
+    if header.pixel_height == 0
+        || header.pixel_depth > 1
+        || header.layer_count > 1
+        || (header.face_count != 1 && header.face_count != 6)
+    {
+        return Err(anyhow!("Only 2D KTX2 images and cubemaps are supported."));
+    }
+    Ok(Ktx2Image {
+        extent: vk::Extent2D {
+            width: header.pixel_width,
+            height: header.pixel_height,
+        },
+        format,
+        faces: header.face_count,
+        levels: reader.levels().map(|level| level.data.to_vec()).collect(),
+        generate_mipmaps: header.level_count == 0,
+    })
+}
+
+pub fn load_ktx2(path: &Path) -> Result<Ktx2Image, anyhow::Error> {
+    let bytes =
+        std::fs::read(path).map_err(|err| anyhow!("Failed to open {}: {}", path.display(), err))?;
+    decode_ktx2(&bytes).map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))
+}
+
+// Every image becomes four channels except single-channel data, since three-channel formats are
+// rarely supported for sampling.  HDR images are linear whatever `color_space` says.
+fn convert(image: DynamicImage, color_space: ColorSpace) -> DecodedImage {
+    let extent = vk::Extent2D {
+        width: image.width(),
+        height: image.height(),
+    };
+    let (format, pixels) = match (image.color(), color_space) {
+        (ColorType::Rgb32F | ColorType::Rgba32F, _) => (
+            vk::Format::R32G32B32A32_SFLOAT,
+            image
+                .to_rgba32f()
+                .into_raw()
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect(),
+        ),
+        (ColorType::L8, ColorSpace::Linear) => (vk::Format::R8_UNORM, image.to_luma8().into_raw()),
+        // There are no 16-bit sRGB formats, so 16-bit colors are reduced to 8 bits.
+        (
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16,
+            ColorSpace::Linear,
+        ) => (
+            vk::Format::R16G16B16A16_UNORM,
+            image
+                .to_rgba16()
+                .into_raw()
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect(),
+        ),
+        (_, ColorSpace::Srgb) => (vk::Format::R8G8B8A8_SRGB, image.to_rgba8().into_raw()),
+        (_, ColorSpace::Linear) => (vk::Format::R8G8B8A8_UNORM, image.to_rgba8().into_raw()),
+    };
+    DecodedImage {
+        extent,
+        format,
+        pixels,
+    }
+}
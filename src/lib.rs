@@ -0,0 +1,38 @@
+// cinder renders through Vulkan, to a window's swapchain or headlessly.  `Vulkan` owns the
+// instance, device and swapchain; each frame runs its render graph, to which applications add
+// passes with `Vulkan::set_passes`.  The host owns the window and its event loop, calling
+// `render` and `resize_to` as it dispatches events; `Vulkan::with_device` also shares the host's
+// Vulkan device.  `examples/demo.rs` shows a windowed application.
+//
+// Pipelines are created from SPIR-V.  The `runtime-shaders` feature also lets them be given GLSL,
+// compiled with shaderc; building it links a shaderc library from the Vulkan SDK or the system, or
+// builds one from source with cmake if there is none, see `Cargo.toml`.
+
+mod image_file;
+mod shader;
+pub mod trace;
+mod vulkan;
+
+// Re-exported so applications use the same version of the Vulkan bindings as cinder.
+pub use ash;
+
+pub use vulkan::{
+    BindlessBuffer, BindlessTexture, Buffer, BufferHandle, BufferUsage, Clock, ComputePipeline,
+    ComputePipelineDesc, ComputeSet, DepthBuffer, DeviceFeatures, Draw, DrawIndexed,
+    ExternalDevice, FrameTime, GpuFrameTimeline, GpuProfiler, GpuQueries, GpuScopeTiming, Handle,
+    HeapStats, ImageUsage, IndexBuffer, Indices, MeshPipeline, MeshPipelineDesc, Mipmaps, Options,
+    PassBuilder, PassResources, PassSetup, PipelineHandle, PipelineOptions, PipelineStatistics,
+    RenderGraph, ResourceId, SamplerDesc, SamplerHandle, SceneDraws, Screenshot, SequenceOutput,
+    Texture, TextureHandle, TextureSet, TransientAllocation, TransientImageDesc, VertexAttribute,
+    VertexBinding, Vulkan,
+};
+
+#[cfg(feature = "async")]
+pub use image_file::load_image_async;
+pub use image_file::{
+    decode_image, decode_ktx2, load_image, load_ktx2, ColorSpace, DecodedImage, Ktx2Image,
+};
+pub use shader::ShaderSource;
+
+// The renderer under the name applications usually know it by.
+pub type Renderer = Vulkan;
@@ -0,0 +1,80 @@
+// Shaders, which pipelines are created from as SPIR-V.  cinder's own are compiled ahead of time
+// from the GLSL in `shaders/` with `shaders/compile.sh`, and the SPIR-V is built into the library,
+// so using them needs no shader compiler.  With the `runtime-shaders` feature, pipelines can also
+// be given GLSL, which shaderc compiles when they are created.
+
+#[cfg(feature = "runtime-shaders")]
+mod glsl;
+
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::io;
+#[cfg(feature = "runtime-shaders")]
+use std::path::Path;
+
+static BUILTIN_SHADERS: &[(&str, &[u8])] = &[
+    (
+        "equirect_to_cube.comp",
+        include_bytes!("../shaders/equirect_to_cube.comp.spv"),
+    ),
+    ("shader.frag", include_bytes!("../shaders/shader.frag.spv")),
+    ("shader.vert", include_bytes!("../shaders/shader.vert.spv")),
+    ("skybox.frag", include_bytes!("../shaders/skybox.frag.spv")),
+    ("skybox.vert", include_bytes!("../shaders/skybox.vert.spv")),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+// Where a pipeline's shader comes from.
+#[derive(Clone, Copy, Debug)]
+pub enum ShaderSource<'a> {
+    // SPIR-V words, as read with `ash::util::read_spv`.
+    Spirv(&'a [u32]),
+    // GLSL compiled when the pipeline is created.  `path` is relative to `directory`, where
+    // `#include <file>` looks too; `#include "file"` looks next to the including file first.
+    #[cfg(feature = "runtime-shaders")]
+    Glsl {
+        directory: &'a Path,
+        path: &'a str,
+    },
+}
+
+impl<'a> ShaderSource<'a> {
+    #[cfg_attr(not(feature = "runtime-shaders"), allow(unused_variables))]
+    pub(crate) fn spirv(&self, stage: ShaderStage) -> Result<Cow<'a, [u32]>, anyhow::Error> {
+        match *self {
+            ShaderSource::Spirv(code) => Ok(Cow::Borrowed(code)),
+            #[cfg(feature = "runtime-shaders")]
+            ShaderSource::Glsl { directory, path } => glsl::ShaderCompiler::new(directory)?
+                .compile(path, stage, &[])
+                .map(Cow::Owned),
+        }
+    }
+}
+
+// The SPIR-V of one of cinder's own shaders, named after its GLSL source.
+pub fn builtin(name: &str) -> Result<Vec<u32>, anyhow::Error> {
+    let (_, code) = BUILTIN_SHADERS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .ok_or_else(|| anyhow!("No builtin shader {}.", name))?;
+    Ok(ash::util::read_spv(&mut io::Cursor::new(code))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_shaders_are_spirv() {
+        for (name, _) in BUILTIN_SHADERS {
+            let code = builtin(name).unwrap();
+            assert_eq!(code[0], 0x0723_0203, "{}", name);
+        }
+    }
+}
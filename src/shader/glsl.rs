@@ -0,0 +1,601 @@
+// GLSL compiled at run time, with preprocessing on top of shaderc.  `#include` directives are resolved here rather than in
+// glslang so that include guards, injected defines and error locations are all under our control.
+//
+// Directives inside comments are ignored, but conditionals are left to glslang: an `#include`,
+// `#pragma once` or include guard inside an `#if` block takes effect whichever branch glslang
+// goes on to take.
+
+use anyhow::anyhow;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io;
+#[cfg(test)]
+use std::path::Component;
+use std::path::{Path, PathBuf};
+
+use super::ShaderStage;
+
+impl ShaderStage {
+    fn shader_kind(self) -> shaderc::ShaderKind {
+        match self {
+            ShaderStage::Vertex => shaderc::ShaderKind::Vertex,
+            ShaderStage::Fragment => shaderc::ShaderKind::Fragment,
+            ShaderStage::Compute => shaderc::ShaderKind::Compute,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+// The flattened source handed to shaderc, along with the original location of every line in it.
+#[derive(Debug, Default)]
+pub struct PreprocessedShader {
+    pub source: String,
+    line_map: Vec<SourceLocation>,
+}
+
+impl PreprocessedShader {
+    // `line` is 1-based, as reported by glslang.
+    pub fn original_location(&self, line: usize) -> Option<&SourceLocation> {
+        line.checked_sub(1)
+            .and_then(|index| self.line_map.get(index))
+    }
+
+    // Rewrites glslang messages of the form `name:LINE: message` to point at the original file.
+    fn map_messages(&self, input_file_name: &str, messages: &str) -> String {
+        let prefix = format!("{}:", input_file_name);
+        let mut mapped = String::new();
+        for message in messages.lines() {
+            let location = message.strip_prefix(&prefix).and_then(|rest| {
+                let (line, rest) = rest.split_once(':')?;
+                let line = line.trim().parse::<usize>().ok()?;
+                Some((self.original_location(line)?, rest))
+            });
+            match location {
+                Some((location, rest)) => writeln!(
+                    mapped,
+                    "{}:{}:{}",
+                    location.file.display(),
+                    location.line,
+                    rest
+                ),
+                None => writeln!(mapped, "{}", message),
+            }
+            .expect("writing to a String cannot fail");
+        }
+        mapped
+    }
+}
+
+// Where shader sources and the files they include are read from.
+enum ShaderFiles {
+    Directory(PathBuf),
+    // Paths relative to an imaginary root, with their contents.
+    #[cfg(test)]
+    Embedded(&'static [(&'static str, &'static str)]),
+}
+
+impl ShaderFiles {
+    fn root(&self) -> &Path {
+        match self {
+            ShaderFiles::Directory(directory) => directory,
+            #[cfg(test)]
+            ShaderFiles::Embedded(_) => Path::new(""),
+        }
+    }
+
+    // The name `path` is known by once `.` and `..` are resolved, so that a file included twice
+    // through different paths is recognised as the same file.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        match self {
+            ShaderFiles::Directory(_) => path.canonicalize(),
+            #[cfg(test)]
+            ShaderFiles::Embedded(files) => {
+                let mut normalized = PathBuf::new();
+                for component in path.components() {
+                    match component {
+                        Component::CurDir => {}
+                        Component::ParentDir => {
+                            normalized.pop();
+                        }
+                        component => normalized.push(component),
+                    }
+                }
+                if files.iter().any(|(name, _)| Path::new(name) == normalized) {
+                    Ok(normalized)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::NotFound))
+                }
+            }
+        }
+    }
+
+    // `path` must have come from `canonicalize`.
+    fn read(&self, path: &Path) -> io::Result<String> {
+        match self {
+            ShaderFiles::Directory(_) => std::fs::read_to_string(path),
+            #[cfg(test)]
+            ShaderFiles::Embedded(files) => files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    // Paths in messages are shown relative to the shader directory where possible.
+    fn display_path(&self, path: &Path) -> PathBuf {
+        let directory = self.root();
+        let directory = directory
+            .canonicalize()
+            .unwrap_or_else(|_| directory.to_path_buf());
+        path.strip_prefix(&directory)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+pub struct ShaderCompiler {
+    compiler: shaderc::Compiler,
+    files: ShaderFiles,
+}
+
+impl ShaderCompiler {
+    pub fn new(shader_directory: impl Into<PathBuf>) -> Result<Self, anyhow::Error> {
+        let compiler = shaderc::Compiler::new()?;
+        let files = ShaderFiles::Directory(shader_directory.into());
+        Ok(Self { compiler, files })
+    }
+
+    // `path` is relative to the shader directory.  Each `(name, value)` pair in `defines` is
+    // injected directly after the `#version` line; an empty value produces a bare `#define name`.
+    pub fn compile(
+        &self,
+        path: &str,
+        stage: ShaderStage,
+        defines: &[(&str, &str)],
+    ) -> Result<Vec<u32>, anyhow::Error> {
+        let preprocessed = self.preprocess(path, defines)?;
+
+        let mut options = shaderc::CompileOptions::new()?;
+        options.set_target_env(
+            shaderc::TargetEnv::Vulkan,
+            shaderc::EnvVersion::Vulkan1_2 as u32,
+        );
+
+        let artifact = self
+            .compiler
+            .compile_into_spirv(
+                &preprocessed.source,
+                stage.shader_kind(),
+                path,
+                "main",
+                Some(&options),
+            )
+            .map_err(|err| match err {
+                shaderc::Error::CompilationError(count, messages) => anyhow!(
+                    "{} compilation error(s) in {}:\n{}",
+                    count,
+                    path,
+                    preprocessed.map_messages(path, &messages)
+                ),
+                err => anyhow!("Failed to compile {}: {}", path, err),
+            })?;
+        if artifact.get_num_warnings() > 0 {
+            tracing::warn!(
+                "{}",
+                preprocessed
+                    .map_messages(path, &artifact.get_warning_messages())
+                    .trim_end()
+            );
+        }
+
+        Ok(artifact.as_binary().to_vec())
+    }
+
+    pub fn preprocess(
+        &self,
+        path: &str,
+        defines: &[(&str, &str)],
+    ) -> Result<PreprocessedShader, anyhow::Error> {
+        preprocess(&self.files, path, defines)
+    }
+}
+
+fn preprocess(
+    files: &ShaderFiles,
+    path: &str,
+    defines: &[(&str, &str)],
+) -> Result<PreprocessedShader, anyhow::Error> {
+    let mut preprocessor = Preprocessor {
+        files,
+        defines,
+        output: PreprocessedShader::default(),
+        include_stack: Vec::new(),
+        included_once: HashSet::new(),
+        include_guards: HashSet::new(),
+    };
+    preprocessor.process_file(&files.root().join(path), None)?;
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor<'a> {
+    files: &'a ShaderFiles,
+    defines: &'a [(&'a str, &'a str)],
+    output: PreprocessedShader,
+    // Files currently being expanded, used to report include cycles.
+    include_stack: Vec<PathBuf>,
+    // Files containing `#pragma once` that have already been expanded.
+    included_once: HashSet<PathBuf>,
+    // Macros guarding files written with the classic `#ifndef X / #define X / #endif` pattern.
+    include_guards: HashSet<String>,
+}
+
+impl Preprocessor<'_> {
+    fn process_file(
+        &mut self,
+        path: &Path,
+        included_from: Option<&SourceLocation>,
+    ) -> Result<(), anyhow::Error> {
+        let path = self
+            .files
+            .canonicalize(path)
+            .map_err(|err| match included_from {
+                Some(location) => anyhow!(
+                    "{}:{}: cannot open include file {}: {}",
+                    location.file.display(),
+                    location.line,
+                    path.display(),
+                    err
+                ),
+                None => anyhow!("Cannot open shader {}: {}", path.display(), err),
+            })?;
+        if self.included_once.contains(&path) {
+            return Ok(());
+        }
+        let source = self.files.read(&path)?;
+        let guard = Self::include_guard(&source);
+        if let Some(guard) = &guard {
+            if self.include_guards.contains(guard) {
+                return Ok(());
+            }
+        }
+        if self.include_stack.contains(&path) {
+            let location = included_from.expect("the root file cannot be on the include stack");
+            return Err(anyhow!(
+                "{}:{}: include cycle through {}",
+                location.file.display(),
+                location.line,
+                path.display()
+            ));
+        }
+
+        self.include_stack.push(path.clone());
+        let is_root = included_from.is_none();
+        let mut defines_injected = false;
+        let mut in_comment = false;
+        for (index, line) in source.lines().enumerate() {
+            let location = SourceLocation {
+                file: self.files.display_path(&path),
+                line: index + 1,
+            };
+            let code = Self::strip_comments(line, &mut in_comment);
+            let directive = code.trim_start();
+
+            if let Some(rest) = Self::directive(directive, "include") {
+                let target = self.resolve_include(&path, rest, &location)?;
+                self.process_file(&target, Some(&location))?;
+                continue;
+            }
+            if let Some(rest) = Self::directive(directive, "pragma") {
+                if rest.trim() == "once" {
+                    self.included_once.insert(path.clone());
+                    continue;
+                }
+            }
+            if let (Some(guard), Some(rest)) = (&guard, Self::directive(directive, "define")) {
+                if rest.split_whitespace().next() == Some(guard.as_str()) {
+                    self.include_guards.insert(guard.clone());
+                }
+            }
+
+            self.emit(line, location.clone());
+            if is_root && !defines_injected && Self::directive(directive, "version").is_some() {
+                self.inject_defines(&location);
+                defines_injected = true;
+            }
+        }
+        if is_root && !defines_injected {
+            // No `#version` line; glslang will complain, but the defines should still be visible.
+            let location = SourceLocation {
+                file: self.files.display_path(&path),
+                line: 1,
+            };
+            let body = std::mem::take(&mut self.output);
+            self.inject_defines(&location);
+            self.output.source.push_str(&body.source);
+            self.output.line_map.extend(body.line_map);
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    fn emit(&mut self, line: &str, location: SourceLocation) {
+        self.output.source.push_str(line);
+        self.output.source.push('\n');
+        self.output.line_map.push(location);
+    }
+
+    fn inject_defines(&mut self, location: &SourceLocation) {
+        for (name, value) in self.defines {
+            let line = if value.is_empty() {
+                format!("#define {}", name)
+            } else {
+                format!("#define {} {}", name, value)
+            };
+            self.emit(&line, location.clone());
+        }
+    }
+
+    // `#include "file"` is looked up next to the including file first, then in the shader
+    // directory.  `#include <file>` only looks in the shader directory.
+    fn resolve_include(
+        &self,
+        including_file: &Path,
+        argument: &str,
+        location: &SourceLocation,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let argument = argument.trim();
+        let malformed = || {
+            anyhow!(
+                "{}:{}: malformed #include {}",
+                location.file.display(),
+                location.line,
+                argument
+            )
+        };
+        if let Some(name) = argument.strip_prefix('<') {
+            let name = name.strip_suffix('>').ok_or_else(malformed)?;
+            return Ok(self.files.root().join(name));
+        }
+        let name = argument
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .ok_or_else(malformed)?;
+        let relative = including_file
+            .parent()
+            .map(|directory| directory.join(name))
+            .filter(|candidate| self.files.canonicalize(candidate).is_ok());
+        Ok(relative.unwrap_or_else(|| self.files.root().join(name)))
+    }
+
+    fn directive<'l>(line: &'l str, name: &str) -> Option<&'l str> {
+        let rest = line.strip_prefix('#')?.trim_start().strip_prefix(name)?;
+        if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    // `line` with its comments replaced by spaces.  `in_comment` says whether the line starts
+    // inside a block comment, and is updated to whether the next one does.
+    fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+        let mut code = String::new();
+        let mut rest = line;
+        loop {
+            if *in_comment {
+                let Some(end) = rest.find("*/") else {
+                    return code;
+                };
+                rest = &rest[end + 2..];
+                code.push(' ');
+                *in_comment = false;
+            }
+            let line_comment = rest.find("//");
+            match rest.find("/*") {
+                Some(start) if line_comment.is_none_or(|line_comment| start < line_comment) => {
+                    code.push_str(&rest[..start]);
+                    rest = &rest[start + 2..];
+                    *in_comment = true;
+                }
+                _ => {
+                    code.push_str(&rest[..line_comment.unwrap_or(rest.len())]);
+                    return code;
+                }
+            }
+        }
+    }
+
+    // Recognises a file wrapped entirely in `#ifndef GUARD` / `#define GUARD` / `#endif`.
+    fn include_guard(source: &str) -> Option<String> {
+        let mut in_comment = false;
+        let code: Vec<_> = source
+            .lines()
+            .map(|line| Self::strip_comments(line, &mut in_comment))
+            .collect();
+        let mut directives = code
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty());
+        let guard = Self::directive(directives.next()?, "ifndef")?.trim();
+        let defined = Self::directive(directives.next()?, "define")?
+            .split_whitespace()
+            .next()?;
+        let last = directives.next_back()?;
+        if guard.is_empty() || defined != guard || Self::directive(last, "endif").is_none() {
+            return None;
+        }
+        Some(guard.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess_embedded(
+        files: &'static [(&'static str, &'static str)],
+        path: &str,
+    ) -> Result<PreprocessedShader, anyhow::Error> {
+        preprocess(&ShaderFiles::Embedded(files), path, &[])
+    }
+
+    fn location(file: &str, line: usize) -> SourceLocation {
+        SourceLocation {
+            file: PathBuf::from(file),
+            line,
+        }
+    }
+
+    #[test]
+    fn includes_are_resolved_next_to_the_including_file_then_in_the_root() {
+        static FILES: &[(&str, &str)] = &[
+            (
+                "main.frag",
+                "#version 450\n#include \"common.glsl\"\n#include <lib/util.glsl>\nvoid main() {}\n",
+            ),
+            ("common.glsl", "float common_value;\n"),
+            ("lib/util.glsl", "#include \"helper.glsl\"\nfloat util_value;\n"),
+            ("lib/helper.glsl", "float helper_value;\n"),
+            ("helper.glsl", "float wrong_helper;\n"),
+        ];
+        let preprocessed = preprocess_embedded(FILES, "main.frag").unwrap();
+        assert_eq!(
+            preprocessed.source,
+            "#version 450\nfloat common_value;\nfloat helper_value;\nfloat util_value;\n\
+             void main() {}\n"
+        );
+        assert_eq!(
+            preprocessed.original_location(3),
+            Some(&location("lib/helper.glsl", 1))
+        );
+        assert_eq!(
+            preprocessed.original_location(5),
+            Some(&location("main.frag", 4))
+        );
+    }
+
+    #[test]
+    fn missing_includes_report_the_including_line() {
+        static FILES: &[(&str, &str)] =
+            &[("main.frag", "#version 450\n#include \"missing.glsl\"\n")];
+        let err = preprocess_embedded(FILES, "main.frag").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("main.frag:2: cannot open include file"));
+    }
+
+    #[test]
+    fn guarded_and_pragma_once_files_are_expanded_once() {
+        static FILES: &[(&str, &str)] = &[
+            (
+                "main.frag",
+                "#version 450\n#include \"guarded.glsl\"\n#include \"once.glsl\"\n\
+                 #include \"guarded.glsl\"\n#include \"./once.glsl\"\n",
+            ),
+            (
+                "guarded.glsl",
+                "// Guarded.\n#ifndef GUARDED\n#define GUARDED\nfloat guarded;\n#endif\n",
+            ),
+            ("once.glsl", "#pragma once\nfloat once;\n"),
+        ];
+        let preprocessed = preprocess_embedded(FILES, "main.frag").unwrap();
+        assert_eq!(preprocessed.source.matches("float guarded;").count(), 1);
+        assert_eq!(preprocessed.source.matches("float once;").count(), 1);
+        assert!(!preprocessed.source.contains("#pragma once"));
+    }
+
+    #[test]
+    fn directives_in_comments_are_ignored() {
+        static FILES: &[(&str, &str)] = &[
+            (
+                "main.frag",
+                "#version 450\n// #include \"missing.glsl\"\n/* Not included:\n#include \"missing.glsl\"\n\
+                 */ #include \"once.glsl\"\n#include \"guarded.glsl\" // Twice.\n\
+                 #include \"once.glsl\"\n#include \"guarded.glsl\"\n",
+            ),
+            (
+                "once.glsl",
+                "/* #pragma once */ float once;\n/*\n#pragma once\n*/\n",
+            ),
+            (
+                "guarded.glsl",
+                "/* Guarded,\n * with a header. */\n#ifndef GUARDED // Guard.\n#define GUARDED\n\
+                 float guarded;\n#endif /* GUARDED */\n",
+            ),
+        ];
+        let preprocessed = preprocess_embedded(FILES, "main.frag").unwrap();
+        assert_eq!(preprocessed.source.matches("float once;").count(), 2);
+        assert_eq!(preprocessed.source.matches("float guarded;").count(), 1);
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        static FILES: &[(&str, &str)] = &[
+            ("main.frag", "#version 450\n#include \"a.glsl\"\n"),
+            ("a.glsl", "#include \"b.glsl\"\n"),
+            ("b.glsl", "\n#include \"a.glsl\"\n"),
+        ];
+        let err = preprocess_embedded(FILES, "main.frag").unwrap_err();
+        assert_eq!(err.to_string(), "b.glsl:2: include cycle through a.glsl");
+    }
+
+    #[test]
+    fn defines_follow_the_version_line() {
+        static FILES: &[(&str, &str)] = &[("main.frag", "#version 450\nvoid main() {}\n")];
+        let preprocessed = preprocess(
+            &ShaderFiles::Embedded(FILES),
+            "main.frag",
+            &[("SAMPLES", "4"), ("DEBUG", "")],
+        )
+        .unwrap();
+        assert_eq!(
+            preprocessed.source,
+            "#version 450\n#define SAMPLES 4\n#define DEBUG\nvoid main() {}\n"
+        );
+        assert_eq!(
+            preprocessed.original_location(4),
+            Some(&location("main.frag", 2))
+        );
+    }
+
+    #[test]
+    fn builtin_shaders_preprocess() {
+        let files = ShaderFiles::Directory(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders").into());
+        for name in [
+            "equirect_to_cube.comp",
+            "shader.frag",
+            "shader.vert",
+            "skybox.frag",
+            "skybox.vert",
+        ] {
+            preprocess(&files, name, &[]).unwrap();
+        }
+    }
+
+    #[test]
+    fn messages_point_at_the_original_file_and_line() {
+        static FILES: &[(&str, &str)] = &[
+            (
+                "main.frag",
+                "#version 450\n#include \"common.glsl\"\nvoid main() {}\n",
+            ),
+            ("common.glsl", "float a;\nfloat b\n"),
+        ];
+        let preprocessed = preprocess_embedded(FILES, "main.frag").unwrap();
+        let messages = "main.frag:3: error: 'float' : syntax error\n\
+                        main.frag:4: warning: unused\n\
+                        1 error generated.\n";
+        assert_eq!(
+            preprocessed.map_messages("main.frag", messages),
+            "common.glsl:2: error: 'float' : syntax error\n\
+             main.frag:3: warning: unused\n\
+             1 error generated.\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+// Chrome trace export of CPU spans and GPU scopes, viewable in chrome://tracing or Perfetto.
+// CPU spans come from `tracing`; GPU scopes come from the profiler's frame timelines and are drawn
+// on a track of their own.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::vulkan::GpuFrameTimeline;
+
+const PROCESS_ID: u32 = 1;
+// CPU threads are numbered from 1 in order of their first span.
+const GPU_THREAD_ID: u64 = 0;
+
+struct CpuSpan {
+    name: &'static str,
+    thread: u64,
+    // Microseconds since the trace began.
+    begin_us: f64,
+    duration_us: f64,
+    args: Vec<(&'static str, String)>,
+}
+
+struct GpuScope {
+    label: String,
+    frame: u64,
+    // Microseconds on the GPU clock; lined up with CPU time on export.
+    begin_us: f64,
+    duration_us: f64,
+}
+
+struct Recording {
+    start: Instant,
+    cpu_spans: Vec<CpuSpan>,
+    gpu_scopes: Vec<GpuScope>,
+    threads: HashMap<ThreadId, (u64, String)>,
+    // Added to GPU times to get CPU times.  A frame can't start on the GPU before it was
+    // submitted, so the largest gap between the two seen so far is the best estimate.
+    gpu_offset_us: Option<f64>,
+}
+
+impl Recording {
+    fn since_start_us(&self, instant: Instant) -> f64 {
+        instant.saturating_duration_since(self.start).as_secs_f64() * 1_000_000.0
+    }
+
+    fn current_thread(&mut self) -> u64 {
+        let thread = std::thread::current();
+        let next_id = self.threads.len() as u64 + 1;
+        self.threads
+            .entry(thread.id())
+            .or_insert_with(|| {
+                let name = thread
+                    .name()
+                    .map_or_else(|| format!("thread {}", next_id), |name| name.to_string());
+                (next_id, name)
+            })
+            .0
+    }
+}
+
+// Collects events until `write` is called.  Clones share the same recording.
+#[derive(Clone)]
+pub struct ChromeTrace {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Default for ChromeTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChromeTrace {
+    pub fn new() -> Self {
+        Self {
+            recording: Arc::new(Mutex::new(Recording {
+                start: Instant::now(),
+                cpu_spans: Vec::new(),
+                gpu_scopes: Vec::new(),
+                threads: HashMap::new(),
+                gpu_offset_us: None,
+            })),
+        }
+    }
+
+    // A `tracing` layer recording every span entered while it is installed.
+    pub fn layer(&self) -> ChromeTraceLayer {
+        ChromeTraceLayer {
+            trace: self.clone(),
+        }
+    }
+
+    pub fn add_gpu_timelines(&self, timelines: Vec<GpuFrameTimeline>) {
+        let mut recording = self.recording.lock().expect("Trace recording poisoned.");
+        for timeline in timelines {
+            let gpu_begin_us = timeline.gpu_begin_ns / 1000.0;
+            let offset_us = recording.since_start_us(timeline.submitted) - gpu_begin_us;
+            recording.gpu_offset_us = Some(
+                recording
+                    .gpu_offset_us
+                    .map_or(offset_us, |offset| offset.max(offset_us)),
+            );
+            for scope in timeline.scopes {
+                recording.gpu_scopes.push(GpuScope {
+                    label: scope.label,
+                    frame: timeline.frame,
+                    begin_us: gpu_begin_us + scope.begin_ns / 1000.0,
+                    duration_us: (scope.end_ns - scope.begin_ns) / 1000.0,
+                });
+            }
+        }
+    }
+
+    // Writes everything recorded so far in the Chrome trace event format.
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let recording = self.recording.lock().expect("Trace recording poisoned.");
+        let mut events = vec![json!({
+            "ph": "M",
+            "name": "thread_name",
+            "pid": PROCESS_ID,
+            "tid": GPU_THREAD_ID,
+            "args": { "name": "GPU" },
+        })];
+        for (id, name) in recording.threads.values() {
+            events.push(json!({
+                "ph": "M",
+                "name": "thread_name",
+                "pid": PROCESS_ID,
+                "tid": id,
+                "args": { "name": name },
+            }));
+        }
+        for span in &recording.cpu_spans {
+            let args: serde_json::Map<String, Value> = span
+                .args
+                .iter()
+                .map(|(name, value)| (name.to_string(), Value::String(value.clone())))
+                .collect();
+            events.push(json!({
+                "ph": "X",
+                "cat": "cpu",
+                "name": span.name,
+                "pid": PROCESS_ID,
+                "tid": span.thread,
+                "ts": span.begin_us,
+                "dur": span.duration_us,
+                "args": args,
+            }));
+        }
+        let gpu_offset_us = recording.gpu_offset_us.unwrap_or(0.0);
+        for scope in &recording.gpu_scopes {
+            events.push(json!({
+                "ph": "X",
+                "cat": "gpu",
+                "name": scope.label,
+                "pid": PROCESS_ID,
+                "tid": GPU_THREAD_ID,
+                "ts": scope.begin_us + gpu_offset_us,
+                "dur": scope.duration_us,
+                "args": { "frame": scope.frame },
+            }));
+        }
+        let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+        std::fs::write(path, serde_json::to_string(&trace)?)?;
+        Ok(())
+    }
+}
+
+pub struct ChromeTraceLayer {
+    trace: ChromeTrace,
+}
+
+// Stored in a span's extensions while it is open.
+struct OpenSpan {
+    entered: Option<Instant>,
+    args: Vec<(&'static str, String)>,
+}
+
+struct ArgsVisitor<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for ArgsVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+impl<S> Layer<S> for ChromeTraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut args = Vec::new();
+        attributes.record(&mut ArgsVisitor(&mut args));
+        span.extensions_mut().insert(OpenSpan {
+            entered: None,
+            args,
+        });
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open_span) = span.extensions_mut().get_mut::<OpenSpan>() {
+                open_span.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    // Every time a span is exited counts as one event, so a span entered repeatedly shows up
+    // once per entry.
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let exited = Instant::now();
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(open_span) = extensions.get_mut::<OpenSpan>() else {
+            return;
+        };
+        let Some(entered) = open_span.entered.take() else {
+            return;
+        };
+        let mut recording = self
+            .trace
+            .recording
+            .lock()
+            .expect("Trace recording poisoned.");
+        let thread = recording.current_thread();
+        let begin_us = recording.since_start_us(entered);
+        recording.cpu_spans.push(CpuSpan {
+            name: span.name(),
+            thread,
+            begin_us,
+            duration_us: exited.duration_since(entered).as_secs_f64() * 1_000_000.0,
+            args: open_span.args.clone(),
+        });
+    }
+}
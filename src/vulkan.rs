@@ -5,27 +5,252 @@ use ash::khr::swapchain;
 use ash::vk;
 use ash::{Entry, Instance};
 use raw_window_handle::{DisplayHandle, WindowHandle};
+use std::cell::RefCell;
 use std::ffi::CStr;
-use vk_shader_macros;
+use std::time::Instant;
+
+use crate::shader;
+
+mod allocator;
+mod barriers;
+mod bindless;
+mod buffer;
+mod capture;
+mod clock;
+mod compute;
+mod cubemap;
+mod depth;
+mod dynamic_rendering;
+mod external;
+mod features;
+mod headless;
+mod image;
+mod mesh;
+mod mipmap;
+mod msaa;
+mod profiler;
+mod queries;
+mod registry;
+mod render_graph;
+mod ring;
+mod sampler;
+mod sequence;
+mod skybox;
+mod texture;
+mod timeline;
+mod transfer;
+
+use allocator::Allocator;
+pub use allocator::HeapStats;
+pub use barriers::{BufferUsage, ImageUsage};
+use barriers::{ImageState, ResourceStateTracker};
+use bindless::BindlessTable;
+pub use bindless::{BindlessBuffer, BindlessTexture};
+pub use buffer::Buffer;
+use capture::PendingCapture;
+pub use capture::Screenshot;
+use clock::FrameClock;
+pub use clock::{Clock, FrameTime};
+use compute::ComputeSetPools;
+pub use compute::{ComputePipeline, ComputePipelineDesc, ComputeSet};
+pub use depth::DepthBuffer;
+pub use external::ExternalDevice;
+pub use features::DeviceFeatures;
+use image::Image;
+pub use mesh::{
+    Draw, DrawIndexed, IndexBuffer, Indices, MeshPipeline, MeshPipelineDesc, VertexAttribute,
+    VertexBinding,
+};
+pub use profiler::{GpuFrameTimeline, GpuProfiler, GpuScopeTiming};
+pub use queries::{GpuQueries, PipelineStatistics};
+use registry::Registry;
+pub use registry::{
+    BufferHandle, Handle, PipelineHandle, ResourceId, SamplerHandle, TextureHandle,
+};
+pub use render_graph::{PassBuilder, PassResources, RenderGraph, TransientImageDesc};
+pub use ring::TransientAllocation;
+use ring::TransientRing;
+use sampler::SamplerCache;
+pub use sampler::SamplerDesc;
+pub use sequence::SequenceOutput;
+use skybox::Skybox;
+use texture::TextureSetPools;
+pub use texture::{Mipmaps, Texture, TextureSet};
+use timeline::{RetireQueue, Timeline};
+use transfer::{Acquires, Transfers};
 
 static ENGINE_NAME: &CStr = c"Engine";
 static APP_NAME: &CStr = c"Application";
 
 static VALIDATION_LAYER_NAME: &CStr = c"VK_LAYER_KHRONOS_validation";
 
+// Adds an application's passes to the render graph, after cinder's own "scene" pass.  Called
+// whenever the graph is rebuilt, e.g. on resize, with the extent frames are now rendered at, which
+// transient images the size of the frame should be created with.  The graph's images are
+// "swapchain" (the image being rendered into) plus "msaa_color" and "depth" when those attachments
+// exist.
+pub type PassSetup = Box<dyn Fn(&mut RenderGraph, vk::Extent2D)>;
+
+// Records an application's draws inside the scene pass, after cinder's own, with pipelines from
+// `Vulkan::create_mesh_pipeline`.
+pub type SceneDraws = Box<dyn Fn(&Vulkan, vk::CommandBuffer)>;
+
+// How many frames the CPU may record ahead of the GPU.
+const FRAMES_IN_FLIGHT: usize = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub depth_buffer: DepthBuffer,
+    // 1, 2, 4 or 8; clamped to what the device supports for the chosen attachments.
+    pub msaa_samples: u32,
+    // Render with VK_KHR_dynamic_rendering instead of a render pass and framebuffers.  Falls back
+    // to a render pass if the device doesn't support it.
+    pub dynamic_rendering: bool,
+    pub pipeline: PipelineOptions,
+    // Where `Vulkan::frame_time` comes from.
+    pub clock: Clock,
+    // Create the bindless table, see `Vulkan::add_bindless_texture`.  Ignored if the device lacks
+    // descriptor indexing.
+    pub bindless: bool,
+    // Bytes per frame for `Vulkan::allocate_transient`; 0 for none.
+    pub transient_buffer_size: vk::DeviceSize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            depth_buffer: DepthBuffer::Depth,
+            msaa_samples: 1,
+            dynamic_rendering: false,
+            pipeline: PipelineOptions::default(),
+            clock: Clock::RealTime,
+            bindless: false,
+            transient_buffer_size: 4 << 20,
+        }
+    }
+}
+
+// Depth settings only take effect when the render pass has a depth attachment.
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineOptions {
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    // Enables sample-rate shading with the given minimum fraction of samples shaded.  Ignored
+    // without MSAA or when the device lacks the `sampleRateShading` feature.
+    pub min_sample_shading: Option<f32>,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS,
+            min_sample_shading: None,
+        }
+    }
+}
+
+// Formats and sample count shared by the render pass, framebuffers and pipeline.
+#[derive(Clone, Copy, Debug)]
+struct AttachmentFormats {
+    color: vk::Format,
+    depth: Option<vk::Format>,
+    samples: vk::SampleCountFlags,
+}
+
+impl AttachmentFormats {
+    fn is_multisampled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+}
+
+// Images rendered into alongside each swapchain image; recreated whenever the swapchain is.
+struct AttachmentImages {
+    // Only present with MSAA, in which case it is resolved into the swapchain image.
+    color: Option<Image>,
+    depth: Option<Image>,
+}
+
+impl AttachmentImages {
+    fn new(
+        allocator: &Allocator,
+        logical_device: &ash::Device,
+        attachment_formats: &AttachmentFormats,
+        extent: vk::Extent2D,
+    ) -> Result<Self, anyhow::Error> {
+        let color = if attachment_formats.is_multisampled() {
+            Some(msaa::create_color_image(
+                allocator,
+                logical_device,
+                attachment_formats.color,
+                extent,
+                attachment_formats.samples,
+            )?)
+        } else {
+            None
+        };
+        let depth = attachment_formats
+            .depth
+            .map(|format| {
+                depth::create_depth_image(
+                    allocator,
+                    logical_device,
+                    format,
+                    extent,
+                    attachment_formats.samples,
+                )
+            })
+            .transpose()?;
+        Ok(Self { color, depth })
+    }
+
+    fn destroy(&mut self, logical_device: &ash::Device) {
+        if let Some(color) = self.color.as_mut() {
+            color.destroy(logical_device);
+        }
+        if let Some(depth) = self.depth.as_mut() {
+            depth.destroy(logical_device);
+        }
+    }
+}
+
 pub struct Vulkan {
     #[allow(unused)]
     entry: Entry,
     instance: Instance,
     debug_utils: ash::ext::debug_utils::Instance,
+    // Null for an external device, whose instance cinder didn't create.
     debug_utils_messenger: vk::DebugUtilsMessengerEXT,
-    surface_instance: ash::khr::surface::Instance,
-    surface: vk::SurfaceKHR,
+    // Set when the instance and device belong to the host, so they outlive cinder.
+    external_device: bool,
+    // None when rendering headlessly.
+    presentation: Option<Presentation>,
+    physical_device: vk::PhysicalDevice,
+    queue_family_indices: QueueFamilyIndices,
     logical_device: ash::Device,
+    // What was enabled on `logical_device`.
+    device_features: DeviceFeatures,
     queues: Queues,
-    swapchain_loader: swapchain::Device,
-    swapchain: vk::SwapchainKHR,
+    // Memory for every buffer and image but the render graph's transient ones.
+    allocator: Allocator,
+    extent: vk::Extent2D,
+    // The images frames are rendered into: the swapchain's, or the headless target's.
+    swapchain_images: Vec<vk::Image>,
     swapchain_image_views: Vec<vk::ImageView>,
+    // Owns the images above when rendering headlessly; empty otherwise.
+    headless_images: Vec<Image>,
+    attachment_formats: AttachmentFormats,
+    attachment_images: AttachmentImages,
+    // Set when rendering without a render pass, in which case `render_pass` is null and there are
+    // no framebuffers.
+    dynamic_rendering: Option<ash::khr::dynamic_rendering::Device>,
+    synchronization2: ash::khr::synchronization2::Device,
+    resource_states: ResourceStateTracker,
+    render_graph: RenderGraph,
+    pass_setup: Option<PassSetup>,
+    scene_draws: Option<SceneDraws>,
     render_pass: vk::RenderPass,
     vertex_shader_module: vk::ShaderModule,
     fragment_shader_module: vk::ShaderModule,
@@ -33,15 +258,51 @@ pub struct Vulkan {
     pipeline: vk::Pipeline,
     framebuffers: Vec<vk::Framebuffer>,
     command_pools: CommandPools,
+    // One per frame in flight, re-recorded every frame.
     commandbuffers: Vec<vk::CommandBuffer>,
     semaphores: Semaphores,
-    current_image: usize,
+    // Reaches N once the GPU has finished frame N.
+    frame_timeline: Timeline,
+    clock: FrameClock,
+    // Resources destroyed once the frame they were retired in has finished.
+    retired: RetireQueue,
+    // Resources owned through handles, see `Vulkan::register_buffer`.
+    registry: Registry,
+    transfers: Transfers,
+    samplers: SamplerCache,
+    texture_set_pools: TextureSetPools,
+    compute_set_pools: ComputeSetPools,
+    // Created when the first skybox is set.
+    skybox: Option<Skybox>,
+    // Only with `Options::bindless`.
+    bindless: Option<BindlessTable>,
+    transient_ring: Option<TransientRing>,
+    profiler: GpuProfiler,
+    queries: GpuQueries,
+    // Only set while `capture_frame` renders.
+    capture: Option<PendingCapture>,
     image_count: usize,
 }
 
+// The window surface and its swapchain.
+struct Presentation {
+    surface_instance: ash::khr::surface::Instance,
+    surface: vk::SurfaceKHR,
+    swapchain_loader: swapchain::Device,
+    swapchain: vk::SwapchainKHR,
+    // The window's size as last reported by the host, used when the surface leaves the swapchain
+    // extent up to the application.
+    window_extent: Option<vk::Extent2D>,
+}
+
+// What `Vulkan::create` renders to.
+enum Target<'a> {
+    Window(&'a DisplayHandle<'a>, &'a WindowHandle<'a>),
+    Headless(vk::Extent2D),
+}
+
 struct Queues {
     graphics_queue: vk::Queue,
-    #[allow(unused)]
     transfer_queue: vk::Queue,
 }
 
@@ -64,10 +325,14 @@ impl CommandPools {
     }
 }
 
+// The binary semaphores the swapchain requires; everything else is paced by `frame_timeline`.
+// Both are empty when rendering headlessly.
+#[derive(Default)]
 struct Semaphores {
+    // One per frame in flight, signalled by `acquire_next_image`.
     image_available: Vec<vk::Semaphore>,
+    // One per swapchain image, waited on by `queue_present`.
     rendering_finished: Vec<vk::Semaphore>,
-    may_begin_rendering: Vec<vk::Fence>,
 }
 
 impl Semaphores {
@@ -82,11 +347,6 @@ impl Semaphores {
                 logical_device.destroy_semaphore(*semaphore, None);
             }
         }
-        for fence in self.may_begin_rendering.iter() {
-            unsafe {
-                logical_device.destroy_fence(*fence, None);
-            }
-        }
     }
 }
 
@@ -94,93 +354,368 @@ impl Vulkan {
     pub fn new(
         display_handle: &DisplayHandle,
         window_handle: &WindowHandle,
+        options: Options,
+    ) -> Result<Self, anyhow::Error> {
+        Self::create(Target::Window(display_handle, window_handle), None, options)
+    }
+
+    // Renders into images of its own instead of a window's swapchain.  Nothing is presented;
+    // frames are read back with `capture_frame`.
+    pub fn new_headless(extent: vk::Extent2D, options: Options) -> Result<Self, anyhow::Error> {
+        Self::create(Target::Headless(extent), None, options)
+    }
+
+    // Like `new`, but renders with the host's instance, device and queues instead of creating
+    // its own.  Dropping it still waits for the whole device to go idle.
+    pub fn with_device(
+        device: ExternalDevice,
+        display_handle: &DisplayHandle,
+        window_handle: &WindowHandle,
+        options: Options,
+    ) -> Result<Self, anyhow::Error> {
+        Self::create(
+            Target::Window(display_handle, window_handle),
+            Some(device),
+            options,
+        )
+    }
+
+    // Like `new_headless`, but renders with the host's instance, device and queues.
+    pub fn with_device_headless(
+        device: ExternalDevice,
+        extent: vk::Extent2D,
+        options: Options,
     ) -> Result<Self, anyhow::Error> {
-        let entry = Entry::linked();
-        let instance: Instance = Self::create_instance(display_handle, &entry)?;
-        let (debug_utils, debug_utils_messenger) =
-            Self::create_debug_utils_and_messenger(&entry, &instance)?;
+        Self::create(Target::Headless(extent), Some(device), options)
+    }
+
+    fn create(
+        target: Target,
+        external_device: Option<ExternalDevice>,
+        options: Options,
+    ) -> Result<Self, anyhow::Error> {
+        let _span = tracing::info_span!("Vulkan::new").entered();
+        let display_handle = match target {
+            Target::Window(display_handle, _) => Some(display_handle),
+            Target::Headless(_) => None,
+        };
+        let (entry, instance, debug_utils, debug_utils_messenger) = match &external_device {
+            Some(external_device) => (
+                external_device.entry.clone(),
+                external_device.instance.clone(),
+                ash::ext::debug_utils::Instance::new(
+                    &external_device.entry,
+                    &external_device.instance,
+                ),
+                vk::DebugUtilsMessengerEXT::null(),
+            ),
+            None => {
+                let entry = Entry::linked();
+                let instance: Instance = Self::create_instance(display_handle, &entry)?;
+                let (debug_utils, debug_utils_messenger) =
+                    Self::create_debug_utils_and_messenger(&entry, &instance)?;
+                (entry, instance, debug_utils, debug_utils_messenger)
+            }
+        };
 
-        let surface_instance = ash::khr::surface::Instance::new(&entry, &instance);
-        let surface = Self::create_surface(&entry, &instance, &display_handle, &window_handle)?;
+        let surface = match target {
+            Target::Window(display_handle, window_handle) => Some((
+                ash::khr::surface::Instance::new(&entry, &instance),
+                Self::create_surface(&entry, &instance, &display_handle, &window_handle)?,
+            )),
+            Target::Headless(_) => None,
+        };
 
-        let physical_device: vk::PhysicalDevice = Self::create_physical_device(&instance)?;
+        let physical_device: vk::PhysicalDevice = match &external_device {
+            Some(external_device) => external_device.physical_device,
+            None => Self::create_physical_device(&instance)?,
+        };
         let physical_device_properties = unsafe { instance.get_physical_device_properties(physical_device) };
         let api_version = physical_device_properties.api_version;
         let major_version = ash::vk::api_version_major(api_version);
         let minor_version = ash::vk::api_version_minor(api_version);
         let patch_version = ash::vk::api_version_patch(api_version);
-        println!("Vulkan API version: {}.{}.{}", major_version, minor_version, patch_version); 
+        tracing::info!("Vulkan API version: {}.{}.{}", major_version, minor_version, patch_version);
 
-        let extent = Self::get_surface_extent(&physical_device, &surface_instance, &surface)?;
+        let extent = match (&surface, &target) {
+            (Some((surface_instance, surface)), _) => {
+                Self::get_surface_extent(&physical_device, surface_instance, surface, None)?
+            }
+            (None, Target::Headless(extent)) => *extent,
+            (None, Target::Window(..)) => unreachable!("Windows always have a surface."),
+        };
 
-        let queue_family_indices = Self::get_queue_family_indices(
-            &instance,
-            &physical_device,
-            &surface_instance,
-            &surface,
-        )?;
+        let queue_family_indices = match &external_device {
+            Some(external_device) => {
+                if let Some((surface_instance, surface)) = &surface {
+                    let surface_support = unsafe {
+                        surface_instance.get_physical_device_surface_support(
+                            physical_device,
+                            external_device.graphics_queue_family,
+                            *surface,
+                        )?
+                    };
+                    if !surface_support {
+                        return Err(anyhow!(
+                            "The external graphics queue family can't present to the window."
+                        ));
+                    }
+                }
+                QueueFamilyIndices {
+                    graphics: external_device.graphics_queue_family,
+                    transfer: external_device.transfer_queue_family,
+                }
+            }
+            None => Self::get_queue_family_indices(
+                &instance,
+                &physical_device,
+                surface
+                    .as_ref()
+                    .map(|(surface_instance, surface)| (surface_instance, surface)),
+            )?,
+        };
 
-        let logical_device =
-            Self::create_logcal_device(&instance, physical_device, &queue_family_indices)?;
-        let queues = Self::get_queues(&logical_device, &queue_family_indices);
+        // An external device has whatever features the host enabled; cinder can only use fewer.
+        let supported_features = match &external_device {
+            Some(external_device) => external_device.features,
+            None => DeviceFeatures::query(&instance, &physical_device)?,
+        };
+        let mut device_features = supported_features;
+        if surface.is_some() && !supported_features.swapchain {
+            return Err(anyhow!("VK_KHR_swapchain is not supported by this device."));
+        }
+        device_features.swapchain &= surface.is_some();
+        let mut pipeline_options = options.pipeline;
+        if pipeline_options.min_sample_shading.is_some() && !supported_features.sample_rate_shading
+        {
+            tracing::warn!("Sample rate shading is not supported by this device; ignoring.");
+            pipeline_options.min_sample_shading = None;
+        }
+        if options.dynamic_rendering && !supported_features.dynamic_rendering {
+            tracing::warn!(
+                "Dynamic rendering is not supported by this device; using a render pass."
+            );
+        }
+        device_features.dynamic_rendering &= options.dynamic_rendering;
+        if options.bindless && !supported_features.descriptor_indexing {
+            tracing::warn!(
+                "Descriptor indexing is not supported by this device; bindless is disabled."
+            );
+        }
+        device_features.descriptor_indexing &= options.bindless;
+        if !supported_features.synchronization2 {
+            return Err(anyhow!(
+                "VK_KHR_synchronization2 is not supported by this device."
+            ));
+        }
+        if !supported_features.timeline_semaphore {
+            return Err(anyhow!(
+                "Timeline semaphores are not supported by this device."
+            ));
+        }
 
-        let (swapchain_loader, swapchain, swapchain_image_views) =
-            Self::create_swapchain_and_image_views(
+        let (logical_device, queues) = match &external_device {
+            Some(external_device) => (
+                external_device.device.clone(),
+                Queues {
+                    graphics_queue: external_device.graphics_queue,
+                    transfer_queue: external_device.transfer_queue,
+                },
+            ),
+            None => {
+                let logical_device = Self::create_logcal_device(
+                    &instance,
+                    physical_device,
+                    &queue_family_indices,
+                    &device_features,
+                )?;
+                let queues = Self::get_queues(&logical_device, &queue_family_indices);
+                (logical_device, queues)
+            }
+        };
+
+        let allocator = Allocator::new(&instance, &physical_device);
+
+        let (presentation, color_format, swapchain_images, swapchain_image_views, headless_images) =
+            match surface {
+                Some((surface_instance, surface)) => {
+                    let swapchain_loader = swapchain::Device::new(&instance, &logical_device);
+                    let (swapchain, swapchain_images, swapchain_image_views) =
+                        Self::create_swapchain_and_image_views(
+                            &physical_device,
+                            &logical_device,
+                            &surface_instance,
+                            &surface,
+                            &queue_family_indices,
+                            &swapchain_loader,
+                            extent,
+                            vk::SwapchainKHR::null(),
+                        )?;
+                    let color_format =
+                        Self::get_surface_format(&surface_instance, &surface, &physical_device)?
+                            .format;
+                    let presentation = Presentation {
+                        surface_instance,
+                        surface,
+                        swapchain_loader,
+                        swapchain,
+                        window_extent: None,
+                    };
+                    (
+                        Some(presentation),
+                        color_format,
+                        swapchain_images,
+                        swapchain_image_views,
+                        Vec::new(),
+                    )
+                }
+                None => {
+                    let headless_images =
+                        headless::create_target_images(&allocator, &logical_device, extent)?;
+                    (
+                        None,
+                        headless::TARGET_FORMAT,
+                        headless_images.iter().map(|image| image.image).collect(),
+                        headless_images.iter().map(|image| image.view).collect(),
+                        headless_images,
+                    )
+                }
+            };
+
+        let depth_format =
+            depth::select_depth_format(&instance, &physical_device, options.depth_buffer)?;
+        let attachment_formats = AttachmentFormats {
+            color: color_format,
+            depth: depth_format,
+            samples: msaa::select_sample_count(
                 &instance,
                 &physical_device,
-                &logical_device,
-                &surface_instance,
-                &surface,
-                &queue_family_indices,
-                extent,
-            )?;
-
-        let render_pass = Self::create_render_pass(
-            &logical_device,
+                options.msaa_samples,
+                depth_format.is_some(),
+            ),
+        };
+        let attachment_images =
+            AttachmentImages::new(&allocator, &logical_device, &attachment_formats, extent)?;
+
+        let dynamic_rendering = device_features
+            .dynamic_rendering
+            .then(|| ash::khr::dynamic_rendering::Device::new(&instance, &logical_device));
+        let synchronization2 = ash::khr::synchronization2::Device::new(&instance, &logical_device);
+        let mut resource_states = ResourceStateTracker::default();
+        let target_usage = Self::target_usage(presentation.is_some());
+        Self::track_swapchain_and_attachments(
+            &mut resource_states,
+            &swapchain_images,
+            target_usage,
+            &attachment_formats,
+            &attachment_images,
+        );
+        let render_graph = Self::create_render_graph(
+            &instance,
             &physical_device,
-            &surface_instance,
-            &surface,
+            &logical_device,
+            &attachment_images,
+            target_usage,
+            extent,
+            None,
+            &mut resource_states,
         )?;
+        let render_pass = if dynamic_rendering.is_some() {
+            vk::RenderPass::null()
+        } else {
+            Self::create_render_pass(&logical_device, &attachment_formats)?
+        };
 
         let (vertex_shader_module, fragment_shader_module, pipeline_layout, pipeline) =
-            Self::create_shaders_and_pipeline(&logical_device, &render_pass, extent)?;
+            Self::create_shaders_and_pipeline(
+                &logical_device,
+                &render_pass,
+                &attachment_formats,
+                &pipeline_options,
+            )?;
 
         let framebuffers = Self::create_framebuffers(
             &render_pass,
             &logical_device,
             &swapchain_image_views,
+            &attachment_images,
             extent,
         )?;
 
-        let image_count = framebuffers.len();
+        let image_count = swapchain_image_views.len();
 
         let command_pools = Self::create_command_pools(&logical_device, &queue_family_indices)?;
 
         let commandbuffers =
-            Self::create_commandbuffers(&logical_device, &command_pools, image_count)?;
-        Self::fill_commandbuffers(
-            &commandbuffers,
+            Self::create_commandbuffers(&logical_device, &command_pools, FRAMES_IN_FLIGHT)?;
+
+        let semaphores = if presentation.is_some() {
+            Self::create_semaphores(&logical_device, image_count)?
+        } else {
+            Semaphores::default()
+        };
+        let frame_timeline = Timeline::new(&logical_device)?;
+        let transfers = Transfers::new(&logical_device)?;
+        let profiler = GpuProfiler::new(
+            &instance,
+            &physical_device,
             &logical_device,
-            &render_pass,
-            &framebuffers,
-            extent,
-            &pipeline,
+            queue_family_indices.graphics,
+            FRAMES_IN_FLIGHT,
         )?;
-
-        let semaphores = Self::create_semaphores(&logical_device, image_count)?;
+        let queries = GpuQueries::new(
+            &instance,
+            &allocator,
+            &logical_device,
+            &device_features,
+            FRAMES_IN_FLIGHT,
+        )?;
+        let bindless = if device_features.descriptor_indexing {
+            Some(BindlessTable::new(
+                &instance,
+                &physical_device,
+                &logical_device,
+            )?)
+        } else {
+            None
+        };
+        let transient_ring = if options.transient_buffer_size > 0 {
+            Some(TransientRing::new(
+                &instance,
+                &physical_device,
+                &allocator,
+                &logical_device,
+                options.transient_buffer_size,
+            )?)
+        } else {
+            None
+        };
 
         Ok(Self {
             entry,
             instance,
             debug_utils,
             debug_utils_messenger,
-            surface_instance,
-            surface,
+            external_device: external_device.is_some(),
+            presentation,
+            physical_device,
+            queue_family_indices,
             logical_device,
+            device_features,
             queues,
-            swapchain_loader,
-            swapchain,
+            allocator,
+            extent,
+            swapchain_images,
             swapchain_image_views,
+            headless_images,
+            attachment_formats,
+            attachment_images,
+            dynamic_rendering,
+            synchronization2,
+            resource_states,
+            render_graph,
+            pass_setup: None,
+            scene_draws: None,
             render_pass,
             vertex_shader_module,
             fragment_shader_module,
@@ -190,24 +725,50 @@ impl Vulkan {
             command_pools,
             commandbuffers,
             semaphores,
-            current_image: 0,
+            frame_timeline,
+            clock: FrameClock::new(options.clock),
+            retired: RetireQueue::default(),
+            registry: Registry::default(),
+            transfers,
+            samplers: SamplerCache::default(),
+            texture_set_pools: TextureSetPools::default(),
+            compute_set_pools: ComputeSetPools::default(),
+            skybox: None,
+            bindless,
+            transient_ring,
+            profiler,
+            queries,
+            capture: None,
             image_count,
         })
     }
 
+    // Some surfaces, e.g. on Wayland, have no extent of their own and take the swapchain's.  The
+    // window's size is used for those when the host has reported it, else the smallest allowed.
     fn get_surface_extent(
         physical_device: &vk::PhysicalDevice,
         surface_instance: &ash::khr::surface::Instance,
         surface: &vk::SurfaceKHR,
+        window_extent: Option<vk::Extent2D>,
     ) -> Result<vk::Extent2D, anyhow::Error> {
         let surface_capabilities = unsafe {
             surface_instance.get_physical_device_surface_capabilities(*physical_device, *surface)
         }?;
-        Ok(surface_capabilities.current_extent)
+        if surface_capabilities.current_extent.width != u32::MAX {
+            return Ok(surface_capabilities.current_extent);
+        }
+        let min = surface_capabilities.min_image_extent;
+        let max = surface_capabilities.max_image_extent;
+        let window_extent = window_extent.unwrap_or(min);
+        Ok(vk::Extent2D {
+            width: window_extent.width.clamp(min.width, max.width),
+            height: window_extent.height.clamp(min.height, max.height),
+        })
     }
 
+    #[tracing::instrument(skip_all)]
     fn create_instance(
-        display_handle: &DisplayHandle,
+        display_handle: Option<&DisplayHandle>,
         entry: &Entry,
     ) -> Result<Instance, anyhow::Error> {
         let app_info = vk::ApplicationInfo::default()
@@ -228,10 +789,12 @@ impl Vulkan {
 
         extension_names.push(vk::EXT_DEBUG_UTILS_NAME.as_ptr());
 
-        let raw_display_handle = display_handle.as_raw();
-        let window_required_extensions =
-            ash_window::enumerate_required_extensions(raw_display_handle)?;
-        extension_names.extend(window_required_extensions);
+        if let Some(display_handle) = display_handle {
+            let raw_display_handle = display_handle.as_raw();
+            let window_required_extensions =
+                ash_window::enumerate_required_extensions(raw_display_handle)?;
+            extension_names.extend(window_required_extensions);
+        }
 
         let instance_create_info = vk::InstanceCreateInfo::default()
             .application_info(&app_info)
@@ -250,10 +813,16 @@ impl Vulkan {
         p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
         _p_user_data: *mut std::ffi::c_void,
     ) -> vk::Bool32 {
-        let message = std::ffi::CStr::from_ptr((*p_callback_data).p_message);
-        let severity = format!("{:?}", message_severity).to_lowercase();
+        let message = std::ffi::CStr::from_ptr((*p_callback_data).p_message).to_string_lossy();
         let ty = format!("{:?}", message_type).to_lowercase();
-        println!("[Debug][{}][{}] {:?}", severity, ty, message);
+        match message_severity {
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => tracing::error!("[{}] {}", ty, message),
+            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => {
+                tracing::warn!("[{}] {}", ty, message)
+            }
+            vk::DebugUtilsMessageSeverityFlagsEXT::INFO => tracing::debug!("[{}] {}", ty, message),
+            _ => tracing::trace!("[{}] {}", ty, message),
+        }
         vk::FALSE
     }
 
@@ -288,24 +857,26 @@ impl Vulkan {
         Ok(physical_device)
     }
 
+    // Without a surface, any graphics queue family will do.
     fn get_queue_family_indices(
         instance: &Instance,
         physical_device: &vk::PhysicalDevice,
-        surface_instance: &ash::khr::surface::Instance,
-        surface: &vk::SurfaceKHR,
+        surface: Option<(&ash::khr::surface::Instance, &vk::SurfaceKHR)>,
     ) -> Result<QueueFamilyIndices, anyhow::Error> {
         let queue_family_properties =
             unsafe { instance.get_physical_device_queue_family_properties(*physical_device) };
-        dbg!(&queue_family_properties);
         let mut found_graphics_queue_family_indices: Vec<u32> = Vec::new();
         let mut found_transfer_queue_family_indices: Vec<u32> = Vec::new();
         for (index, queue_family_property) in queue_family_properties.iter().enumerate() {
-            let surface_support = unsafe {
-                surface_instance.get_physical_device_surface_support(
-                    *physical_device,
-                    index as u32,
-                    *surface,
-                )?
+            let surface_support = match surface {
+                Some((surface_instance, surface)) => unsafe {
+                    surface_instance.get_physical_device_surface_support(
+                        *physical_device,
+                        index as u32,
+                        *surface,
+                    )?
+                },
+                None => true,
             };
             if queue_family_property.queue_count > 0
                 && queue_family_property
@@ -331,10 +902,11 @@ impl Vulkan {
             .into_iter()
             // https://hoj-senna.github.io/ashen-aetna/text/005_Queues.html claims that the
             // graphics and transfer queue families should be different, but I only have one queue
-            // family on my Mac.
+            // family on my Mac.  Devices with a single queue family, such as lavapipe, use it for
+            // both.
             .find(|index| *index != graphics_queue_family_index)
             //.next()
-            .ok_or_else(|| anyhow!("No valid transfer queue family index found."))?;
+            .unwrap_or(graphics_queue_family_index);
         let queue_family_indices = QueueFamilyIndices {
             // TODO handle errors and convert to anyhow
             graphics: graphics_queue_family_index,
@@ -344,10 +916,12 @@ impl Vulkan {
         Ok(queue_family_indices)
     }
 
+    #[tracing::instrument(name = "create_logical_device", skip_all)]
     fn create_logcal_device(
         instance: &Instance,
         physical_device: vk::PhysicalDevice,
         queue_family_indices: &QueueFamilyIndices,
+        device_features: &DeviceFeatures,
     ) -> Result<ash::Device, anyhow::Error> {
         let priorities = [1.0f32];
 
@@ -357,16 +931,59 @@ impl Vulkan {
         let transfer_queue_info = vk::DeviceQueueCreateInfo::default()
             .queue_family_index(queue_family_indices.transfer)
             .queue_priorities(&priorities);
-        let queue_infos = vec![graphics_queue_info, transfer_queue_info];
-
-        let extension_names = vec![
-            ash::khr::swapchain::NAME.as_ptr(),
-            ash::khr::portability_subset::NAME.as_ptr(),
-        ];
+        let mut queue_infos = vec![graphics_queue_info];
+        if queue_family_indices.transfer != queue_family_indices.graphics {
+            queue_infos.push(transfer_queue_info);
+        }
 
-        let device_create_info = vk::DeviceCreateInfo::default()
+        let extension_names = device_features.extension_names();
+
+        let features = vk::PhysicalDeviceFeatures::default()
+            .sample_rate_shading(device_features.sample_rate_shading)
+            .sampler_anisotropy(device_features.sampler_anisotropy)
+            .texture_compression_bc(device_features.texture_compression_bc)
+            .texture_compression_etc2(device_features.texture_compression_etc2)
+            .texture_compression_astc_ldr(device_features.texture_compression_astc_ldr)
+            .pipeline_statistics_query(device_features.pipeline_statistics_query)
+            .occlusion_query_precise(device_features.occlusion_query_precise);
+        let mut dynamic_rendering_features =
+            vk::PhysicalDeviceDynamicRenderingFeatures::default().dynamic_rendering(true);
+        let mut synchronization2_features =
+            vk::PhysicalDeviceSynchronization2Features::default().synchronization2(true);
+        let mut timeline_semaphore_features =
+            vk::PhysicalDeviceTimelineSemaphoreFeatures::default().timeline_semaphore(true);
+        let mut conditional_rendering_features =
+            vk::PhysicalDeviceConditionalRenderingFeaturesEXT::default()
+                .conditional_rendering(true);
+        let mut descriptor_indexing_features =
+            vk::PhysicalDeviceDescriptorIndexingFeatures::default()
+                .shader_sampled_image_array_non_uniform_indexing(true)
+                .shader_storage_buffer_array_non_uniform_indexing(true)
+                .descriptor_binding_sampled_image_update_after_bind(true)
+                .descriptor_binding_storage_buffer_update_after_bind(true)
+                .descriptor_binding_update_unused_while_pending(true)
+                .descriptor_binding_partially_bound(true)
+                .runtime_descriptor_array(true);
+
+        let mut device_create_info = vk::DeviceCreateInfo::default()
             .queue_create_infos(&queue_infos)
-            .enabled_extension_names(&extension_names);
+            .enabled_extension_names(&extension_names)
+            .enabled_features(&features);
+        if device_features.dynamic_rendering {
+            device_create_info = device_create_info.push_next(&mut dynamic_rendering_features);
+        }
+        if device_features.synchronization2 {
+            device_create_info = device_create_info.push_next(&mut synchronization2_features);
+        }
+        if device_features.timeline_semaphore {
+            device_create_info = device_create_info.push_next(&mut timeline_semaphore_features);
+        }
+        if device_features.conditional_rendering {
+            device_create_info = device_create_info.push_next(&mut conditional_rendering_features);
+        }
+        if device_features.descriptor_indexing {
+            device_create_info = device_create_info.push_next(&mut descriptor_indexing_features);
+        }
 
         let logical_device =
             unsafe { instance.create_device(physical_device, &device_create_info, None)? };
@@ -383,9 +1000,6 @@ impl Vulkan {
         let transfer_queue =
             unsafe { logical_device.get_device_queue(queue_family_indices.transfer, 0) };
 
-        dbg!(&graphics_queue);
-        dbg!(&transfer_queue);
-
         Queues {
             graphics_queue,
             transfer_queue,
@@ -430,15 +1044,18 @@ impl Vulkan {
         Ok(*surface_format)
     }
 
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip_all)]
     fn create_swapchain_and_image_views(
-        instance: &Instance,
         physical_device: &vk::PhysicalDevice,
         logical_device: &ash::Device,
         surface_instance: &ash::khr::surface::Instance,
         surface: &vk::SurfaceKHR,
         queue_family_indices: &QueueFamilyIndices,
+        swapchain_loader: &swapchain::Device,
         extent: vk::Extent2D,
-    ) -> Result<(swapchain::Device, vk::SwapchainKHR, Vec<vk::ImageView>), anyhow::Error> {
+        old_swapchain: vk::SwapchainKHR,
+    ) -> Result<(vk::SwapchainKHR, Vec<vk::Image>, Vec<vk::ImageView>), anyhow::Error> {
         let surface_present_modes = unsafe {
             surface_instance.get_physical_device_surface_present_modes(*physical_device, *surface)
         }?;
@@ -462,13 +1079,13 @@ impl Vulkan {
             .image_color_space(surface_format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(capture::swapchain_usage(&surface_capabilities))
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .queue_family_indices(&queue_families)
             .pre_transform(surface_capabilities.current_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(vk::PresentModeKHR::FIFO);
-        let swapchain_loader = swapchain::Device::new(instance, logical_device);
+            .present_mode(vk::PresentModeKHR::FIFO)
+            .old_swapchain(old_swapchain);
         let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
         let swapchain_images = unsafe { swapchain_loader.get_swapchain_images(swapchain)? };
         let mut swapchain_image_views = Vec::with_capacity(swapchain_images.len());
@@ -482,78 +1099,128 @@ impl Vulkan {
             let image_view_create_info = vk::ImageViewCreateInfo::default()
                 .image(*image)
                 .view_type(vk::ImageViewType::TYPE_2D)
-                .format(vk::Format::B8G8R8A8_UNORM)
+                .format(surface_format.format)
                 .subresource_range(subresource_range);
             let image_view =
                 unsafe { logical_device.create_image_view(&image_view_create_info, None) }?;
             swapchain_image_views.push(image_view);
         }
-        Ok((swapchain_loader, swapchain, swapchain_image_views))
+        Ok((swapchain, swapchain_images, swapchain_image_views))
     }
 
     fn create_attachments(
-        physical_device: &vk::PhysicalDevice,
-        surface_instance: &ash::khr::surface::Instance,
-        surface: &vk::SurfaceKHR,
-    ) -> Result<Vec<vk::AttachmentDescription>, anyhow::Error> {
-        let surface_format = Self::get_surface_format(surface_instance, surface, physical_device)?;
-        let format = surface_format.format;
+        attachment_formats: &AttachmentFormats,
+    ) -> Vec<vk::AttachmentDescription> {
+        // Layout transitions happen outside the render pass, through `resource_states`, so every
+        // attachment starts and ends in its attachment layout.  With MSAA the swapchain image
+        // becomes a resolve target and the multisampled colour attachment is discarded once
+        // resolved.
+        let color_store_op = if attachment_formats.is_multisampled() {
+            vk::AttachmentStoreOp::DONT_CARE
+        } else {
+            vk::AttachmentStoreOp::STORE
+        };
         let attachment = vk::AttachmentDescription::default()
-            .format(format)
+            .format(attachment_formats.color)
             .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE)
+            .store_op(color_store_op)
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-            .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-            .samples(vk::SampleCountFlags::TYPE_1);
-
-        let attachments = vec![attachment];
-        Ok(attachments)
+            .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .samples(attachment_formats.samples);
+
+        let mut attachments = vec![attachment];
+        if let Some(depth_format) = attachment_formats.depth {
+            let stencil_load_op = if depth::has_stencil_component(depth_format) {
+                vk::AttachmentLoadOp::CLEAR
+            } else {
+                vk::AttachmentLoadOp::DONT_CARE
+            };
+            let depth_attachment = vk::AttachmentDescription::default()
+                .format(depth_format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(stencil_load_op)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .samples(attachment_formats.samples);
+            attachments.push(depth_attachment);
+        }
+        if attachment_formats.is_multisampled() {
+            let resolve_attachment = vk::AttachmentDescription::default()
+                .format(attachment_formats.color)
+                .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1);
+            attachments.push(resolve_attachment);
+        }
+        attachments
     }
 
     fn create_render_pass(
         logical_device: &ash::Device,
-        physical_device: &vk::PhysicalDevice,
-        surface_instance: &ash::khr::surface::Instance,
-        surface: &vk::SurfaceKHR,
+        attachment_formats: &AttachmentFormats,
     ) -> Result<vk::RenderPass, anyhow::Error> {
-        let attachments = Self::create_attachments(physical_device, surface_instance, surface)?;
+        let attachments = Self::create_attachments(attachment_formats);
         let color_attachment_ref = vk::AttachmentReference::default()
             .attachment(0)
             .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        let depth_attachment_ref = vk::AttachmentReference::default()
+            .attachment(1)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        // The resolve attachment always comes last.
+        let resolve_attachment_ref = vk::AttachmentReference::default()
+            .attachment(attachments.len() as u32 - 1)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
         let attachment_refs = vec![color_attachment_ref];
-        let subpass = vk::SubpassDescription::default()
+        let resolve_attachment_refs = vec![resolve_attachment_ref];
+        let mut subpass = vk::SubpassDescription::default()
             .color_attachments(&attachment_refs)
             .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
-        let dependency = vk::SubpassDependency::default()
-            .src_subpass(vk::SUBPASS_EXTERNAL)
-            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .dst_subpass(0)
-            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .dst_access_mask(
-                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-            );
-        let dependencies = vec![dependency];
+        if attachment_formats.depth.is_some() {
+            subpass = subpass.depth_stencil_attachment(&depth_attachment_ref);
+        }
+        if attachment_formats.is_multisampled() {
+            subpass = subpass.resolve_attachments(&resolve_attachment_refs);
+        }
         let subpasses = vec![subpass];
         let render_pass_create_info = vk::RenderPassCreateInfo::default()
             .attachments(&attachments)
-            .subpasses(&subpasses)
-            .dependencies(&dependencies);
+            .subpasses(&subpasses);
         let render_pass =
             unsafe { logical_device.create_render_pass(&render_pass_create_info, None)? };
         Ok(render_pass)
     }
-
     fn create_framebuffers(
         render_pass: &vk::RenderPass,
         logical_device: &ash::Device,
         image_views: &Vec<vk::ImageView>,
+        attachment_images: &AttachmentImages,
         extent: vk::Extent2D,
     ) -> Result<Vec<vk::Framebuffer>, vk::Result> {
         let mut framebuffers = Vec::new();
+        if *render_pass == vk::RenderPass::null() {
+            return Ok(framebuffers);
+        }
         for image_view in image_views {
-            let image_view_array = [*image_view];
+            // Same order as the attachments in `create_attachments`.
+            let mut image_view_array = Vec::new();
+            match &attachment_images.color {
+                Some(color_image) => image_view_array.push(color_image.view),
+                None => image_view_array.push(*image_view),
+            }
+            if let Some(depth_image) = &attachment_images.depth {
+                image_view_array.push(depth_image.view);
+            }
+            if attachment_images.color.is_some() {
+                image_view_array.push(*image_view);
+            }
             let framebuffer_info = vk::FramebufferCreateInfo::default()
                 .render_pass(*render_pass)
                 .attachments(&image_view_array)
@@ -567,10 +1234,12 @@ impl Vulkan {
         Ok(framebuffers)
     }
 
+    // A null `render_pass` creates the pipeline for dynamic rendering against `attachment_formats`.
     fn create_shaders_and_pipeline(
         logical_device: &ash::Device,
         render_pass: &vk::RenderPass,
-        extent: vk::Extent2D,
+        attachment_formats: &AttachmentFormats,
+        pipeline_options: &PipelineOptions,
     ) -> Result<
         (
             vk::ShaderModule,
@@ -580,15 +1249,54 @@ impl Vulkan {
         ),
         anyhow::Error,
     > {
-        let vertex_shader_createinfo = vk::ShaderModuleCreateInfo::default()
-            .code(vk_shader_macros::include_glsl!("shaders/shader.vert", kind: vert));
+        let vertex_shader_code = shader::builtin("shader.vert")?;
+        let vertex_shader_createinfo =
+            vk::ShaderModuleCreateInfo::default().code(&vertex_shader_code);
         let vertex_shader_module =
             unsafe { logical_device.create_shader_module(&vertex_shader_createinfo, None)? };
-        let fragment_shader_createinfo = vk::ShaderModuleCreateInfo::default()
-            .code(vk_shader_macros::include_glsl!("shaders/shader.frag", kind: frag));
+        let fragment_shader_code = shader::builtin("shader.frag")?;
+        let fragment_shader_createinfo =
+            vk::ShaderModuleCreateInfo::default().code(&fragment_shader_code);
         let fragment_shader_module =
             unsafe { logical_device.create_shader_module(&fragment_shader_createinfo, None)? };
 
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
+        let pipeline_layout =
+            unsafe { logical_device.create_pipeline_layout(&pipeline_layout_info, None) }?;
+
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default();
+        let graphics_pipeline = Self::create_graphics_pipeline(
+            logical_device,
+            render_pass,
+            attachment_formats,
+            pipeline_options,
+            (vertex_shader_module, fragment_shader_module),
+            &vertex_input_info,
+            vk::PrimitiveTopology::POINT_LIST,
+            pipeline_layout,
+        )?;
+
+        Ok((
+            vertex_shader_module,
+            fragment_shader_module,
+            pipeline_layout,
+            graphics_pipeline,
+        ))
+    }
+
+    // The fixed-function state shared by every pipeline drawn in the scene pass.  The pipeline is
+    // compatible with the render pass, or with dynamic rendering when `render_pass` is null.
+    #[allow(clippy::too_many_arguments)]
+    fn create_graphics_pipeline(
+        logical_device: &ash::Device,
+        render_pass: &vk::RenderPass,
+        attachment_formats: &AttachmentFormats,
+        pipeline_options: &PipelineOptions,
+        (vertex_shader_module, fragment_shader_module): (vk::ShaderModule, vk::ShaderModule),
+        vertex_input_info: &vk::PipelineVertexInputStateCreateInfo,
+        topology: vk::PrimitiveTopology,
+        pipeline_layout: vk::PipelineLayout,
+    ) -> Result<vk::Pipeline, anyhow::Error> {
         let main_function_name = std::ffi::CString::new("main").unwrap();
         let vertex_shader_stage = vk::PipelineShaderStageCreateInfo::default()
             .stage(vk::ShaderStageFlags::VERTEX)
@@ -600,26 +1308,16 @@ impl Vulkan {
             .name(&main_function_name);
         let shader_stages = vec![vertex_shader_stage, fragment_shader_stage];
 
-        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default();
-        let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::default()
-            .topology(vk::PrimitiveTopology::POINT_LIST);
-
-        let viewports = [vk::Viewport {
-            x: 0.,
-            y: 0.,
-            width: extent.width as f32,
-            height: extent.height as f32,
-            min_depth: 0.,
-            max_depth: 1.,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: extent,
-        }];
+        let input_assembly_info =
+            vk::PipelineInputAssemblyStateCreateInfo::default().topology(topology);
 
+        // The viewport and scissor are set while recording so the pipeline survives resizes.
         let viewport_info = vk::PipelineViewportStateCreateInfo::default()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state_info =
+            vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
 
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::default()
             .line_width(1.0)
@@ -627,8 +1325,18 @@ impl Vulkan {
             .cull_mode(vk::CullModeFlags::NONE)
             .polygon_mode(vk::PolygonMode::FILL);
 
+        let min_sample_shading = pipeline_options
+            .min_sample_shading
+            .filter(|_| attachment_formats.is_multisampled());
         let multisampler_info = vk::PipelineMultisampleStateCreateInfo::default()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+            .rasterization_samples(attachment_formats.samples)
+            .sample_shading_enable(min_sample_shading.is_some())
+            .min_sample_shading(min_sample_shading.unwrap_or(0.0));
+
+        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(pipeline_options.depth_test)
+            .depth_write_enable(pipeline_options.depth_write)
+            .depth_compare_op(pipeline_options.depth_compare_op);
 
         let colourblend_attachments = [vk::PipelineColorBlendAttachmentState::default()
             .blend_enable(true)
@@ -647,33 +1355,34 @@ impl Vulkan {
         let colourblend_info =
             vk::PipelineColorBlendStateCreateInfo::default().attachments(&colourblend_attachments);
 
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
-        let pipeline_layout =
-            unsafe { logical_device.create_pipeline_layout(&pipeline_layout_info, None) }?;
+        let color_attachment_formats = [attachment_formats.color];
+        let mut pipeline_rendering_info = dynamic_rendering::pipeline_rendering_create_info(
+            attachment_formats,
+            &color_attachment_formats,
+        );
 
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+        let mut pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_info)
+            .vertex_input_state(vertex_input_info)
             .input_assembly_state(&input_assembly_info)
             .viewport_state(&viewport_info)
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisampler_info)
+            .depth_stencil_state(&depth_stencil_info)
             .color_blend_state(&colourblend_info)
+            .dynamic_state(&dynamic_state_info)
             .layout(pipeline_layout)
             .render_pass(*render_pass)
             .subpass(0);
+        if *render_pass == vk::RenderPass::null() {
+            pipeline_info = pipeline_info.push_next(&mut pipeline_rendering_info);
+        }
         let graphics_pipeline = unsafe {
             logical_device
                 .create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-                .expect("A problem with the pipeline creation")
+                .map_err(|(_, err)| anyhow!("A problem with the pipeline creation: {}", err))?
         }[0];
-
-        Ok((
-            vertex_shader_module,
-            fragment_shader_module,
-            pipeline_layout,
-            graphics_pipeline,
-        ))
+        Ok(graphics_pipeline)
     }
 
     fn create_command_pools(
@@ -708,128 +1417,647 @@ impl Vulkan {
         unsafe { logical_device.allocate_command_buffers(&commandbuf_allocate_info) }
     }
 
-    fn fill_commandbuffers(
-        commandbuffers: &[vk::CommandBuffer],
-        logical_device: &ash::Device,
-        renderpass: &vk::RenderPass,
-        framebuffers: &Vec<vk::Framebuffer>,
-        extent: vk::Extent2D,
-        pipeline: &vk::Pipeline,
+    #[allow(clippy::too_many_arguments)]
+    fn record_frame(
+        &self,
+        render_graph: &mut RenderGraph,
+        resource_states: &mut ResourceStateTracker,
+        profiler: &RefCell<GpuProfiler>,
+        queries: &RefCell<GpuQueries>,
+        commandbuffer: vk::CommandBuffer,
+        image_index: usize,
+        acquires: &Acquires,
     ) -> Result<(), vk::Result> {
-        for (i, &commandbuffer) in commandbuffers.iter().enumerate() {
-            let commandbuffer_begininfo = vk::CommandBufferBeginInfo::default();
-            unsafe {
-                logical_device.begin_command_buffer(commandbuffer, &commandbuffer_begininfo)?;
+        let commandbuffer_begininfo = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            self.logical_device
+                .reset_command_buffer(commandbuffer, vk::CommandBufferResetFlags::empty())?;
+            self.logical_device
+                .begin_command_buffer(commandbuffer, &commandbuffer_begininfo)?;
+            if !acquires.buffers.is_empty() || !acquires.images.is_empty() {
+                let dependency_info = vk::DependencyInfo::default()
+                    .buffer_memory_barriers(&acquires.buffers)
+                    .image_memory_barriers(&acquires.images);
+                self.synchronization2
+                    .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
             }
-            let clearvalues = [vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.08, 1.0],
-                },
-            }];
-            let renderpass_begininfo = vk::RenderPassBeginInfo::default()
-                .render_pass(*renderpass)
-                .framebuffer(framebuffers[i])
-                .render_area(vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: extent,
-                })
-                .clear_values(&clearvalues);
-            unsafe {
-                logical_device.cmd_begin_render_pass(
-                    commandbuffer,
-                    &renderpass_begininfo,
-                    vk::SubpassContents::INLINE,
-                );
-                logical_device.cmd_bind_pipeline(
+        }
+        self.record_equirect_conversions(commandbuffer, &acquires.equirect_conversions);
+        self.record_mip_chains(commandbuffer, &acquires.mip_chains);
+        self.record_buffer_relocations(commandbuffer, &acquires.buffer_relocations);
+        profiler.borrow().reset(&self.logical_device, commandbuffer);
+        queries.borrow().reset(&self.logical_device, commandbuffer);
+        profiler.borrow_mut().begin_scope(
+            &self.synchronization2,
+            commandbuffer,
+            profiler::FRAME_SCOPE,
+        );
+        render_graph.bind_image(
+            "swapchain",
+            self.swapchain_images[image_index],
+            self.swapchain_image_views[image_index],
+        );
+        render_graph.execute(
+            self,
+            resource_states,
+            profiler,
+            queries,
+            commandbuffer,
+            image_index,
+        );
+        self.record_capture(resource_states, commandbuffer, image_index);
+        profiler
+            .borrow_mut()
+            .end_scope(&self.synchronization2, commandbuffer);
+        unsafe { self.logical_device.end_command_buffer(commandbuffer) }
+    }
+
+    // The frame as a render graph.  Only the scene pass exists so far; further passes declare
+    // what they read and write and are ordered and synchronized around it.
+    #[allow(clippy::too_many_arguments)]
+    fn create_render_graph(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &ash::Device,
+        attachment_images: &AttachmentImages,
+        target_usage: ImageUsage,
+        extent: vk::Extent2D,
+        pass_setup: Option<&PassSetup>,
+        resource_states: &mut ResourceStateTracker,
+    ) -> Result<RenderGraph, anyhow::Error> {
+        let mut render_graph = RenderGraph::default();
+        render_graph.import_image("swapchain", Some(target_usage));
+        if let Some(color_image) = &attachment_images.color {
+            render_graph.import_image("msaa_color", None);
+            render_graph.bind_image("msaa_color", color_image.image, color_image.view);
+        }
+        if let Some(depth_image) = &attachment_images.depth {
+            render_graph.import_image("depth", None);
+            render_graph.bind_image("depth", depth_image.image, depth_image.view);
+        }
+
+        // Every attachment is cleared, and with MSAA the swapchain image is a resolve target, so
+        // previous contents are never needed.
+        let mut scene = render_graph
+            .add_pass("scene")
+            .clear_image("swapchain", ImageUsage::ColorAttachment);
+        if attachment_images.color.is_some() {
+            scene = scene.clear_image("msaa_color", ImageUsage::ColorAttachment);
+        }
+        if attachment_images.depth.is_some() {
+            scene = scene.clear_image("depth", ImageUsage::DepthStencilAttachment);
+        }
+        scene.execute(
+            |vulkan, resources, commandbuffer| match &vulkan.dynamic_rendering {
+                Some(dynamic_rendering) => vulkan.record_dynamic_rendering(
+                    dynamic_rendering,
                     commandbuffer,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    *pipeline,
-                );
-                logical_device.cmd_draw(commandbuffer, 1, 1, 0, 0);
-                logical_device.cmd_end_render_pass(commandbuffer);
-                logical_device.end_command_buffer(commandbuffer)?;
-            }
+                    resources.image_index(),
+                ),
+                None => vulkan.record_render_pass(commandbuffer, resources.image_index()),
+            },
+        );
+        if let Some(pass_setup) = pass_setup {
+            pass_setup(&mut render_graph, extent);
+        }
+
+        render_graph.compile(instance, physical_device, logical_device, resource_states)?;
+        Ok(render_graph)
+    }
+
+    // How frames leave the images they are rendered into: ready to present, or when headless,
+    // ready to be copied from.
+    fn target_usage(presenting: bool) -> ImageUsage {
+        if presenting {
+            ImageUsage::Present
+        } else {
+            ImageUsage::TransferSrc
+        }
+    }
+
+    fn track_swapchain_and_attachments(
+        resource_states: &mut ResourceStateTracker,
+        swapchain_images: &[vk::Image],
+        target_usage: ImageUsage,
+        attachment_formats: &AttachmentFormats,
+        attachment_images: &AttachmentImages,
+    ) {
+        let color_range = barriers::full_subresource_range(vk::ImageAspectFlags::COLOR);
+        for image in swapchain_images {
+            resource_states.register(
+                *image,
+                color_range,
+                ImageState::discarded_after(target_usage),
+            );
+        }
+        // Attachment images are shared between frames in flight, so they are treated as having
+        // been written by the previous frame.
+        if let Some(color_image) = &attachment_images.color {
+            resource_states.register(
+                color_image.image,
+                color_range,
+                ImageState::discarded_after(ImageUsage::ColorAttachment),
+            );
+        }
+        if let (Some(depth_image), Some(depth_format)) =
+            (&attachment_images.depth, attachment_formats.depth)
+        {
+            resource_states.register(
+                depth_image.image,
+                barriers::full_subresource_range(depth::aspect_mask(depth_format)),
+                ImageState::discarded_after(ImageUsage::DepthStencilAttachment),
+            );
+        }
+    }
+
+    fn clear_values(&self) -> Vec<vk::ClearValue> {
+        let mut clearvalues = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.08, 1.0],
+            },
+        }];
+        if self.attachment_formats.depth.is_some() {
+            clearvalues.push(vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            });
+        }
+        clearvalues
+    }
+
+    fn record_render_pass(&self, commandbuffer: vk::CommandBuffer, image_index: usize) {
+        let clearvalues = self.clear_values();
+        let renderpass_begininfo = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.framebuffers[image_index])
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .clear_values(&clearvalues);
+        unsafe {
+            self.logical_device.cmd_begin_render_pass(
+                commandbuffer,
+                &renderpass_begininfo,
+                vk::SubpassContents::INLINE,
+            );
+            self.record_draws(commandbuffer);
+            self.logical_device.cmd_end_render_pass(commandbuffer);
+        }
+    }
+
+    // Everything drawn inside the render pass, independent of how the pass was begun.  The skybox
+    // comes first so that everything else is drawn over it.
+    fn record_draws(&self, commandbuffer: vk::CommandBuffer) {
+        self.set_viewport_and_scissor(commandbuffer);
+        self.record_skybox(commandbuffer);
+        unsafe {
+            self.logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline,
+            );
+        }
+        self.draw(
+            commandbuffer,
+            &Draw {
+                vertex_count: 1,
+                ..Default::default()
+            },
+        );
+        if let Some(scene_draws) = &self.scene_draws {
+            scene_draws(self, commandbuffer);
+        }
+    }
+
+    // Pipelines leave the viewport and scissor dynamic; both cover the whole target.
+    fn set_viewport_and_scissor(&self, commandbuffer: vk::CommandBuffer) {
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: self.extent.width as f32,
+            height: self.extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.extent,
+        }];
+        unsafe {
+            self.logical_device
+                .cmd_set_viewport(commandbuffer, 0, &viewports);
+            self.logical_device
+                .cmd_set_scissor(commandbuffer, 0, &scissors);
         }
-        Ok(())
     }
 
     fn create_semaphores(
         logical_device: &ash::Device,
-        amount: usize,
+        image_count: usize,
     ) -> Result<Semaphores, anyhow::Error> {
-        let mut image_available = Vec::new();
-        let mut rendering_finished = Vec::new();
         let semaphore_create_info = vk::SemaphoreCreateInfo::default();
-        let mut may_begin_rendering = Vec::new();
-        let fence_create_info =
-            vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
-        for _ in 0..amount {
+        let mut image_available = Vec::new();
+        for _ in 0..FRAMES_IN_FLIGHT {
             let avialable_semaphore =
                 unsafe { logical_device.create_semaphore(&semaphore_create_info, None)? };
+            image_available.push(avialable_semaphore);
+        }
+        let mut rendering_finished = Vec::new();
+        for _ in 0..image_count {
             let rendering_finished_semaphore =
                 unsafe { logical_device.create_semaphore(&semaphore_create_info, None)? };
-            image_available.push(avialable_semaphore);
             rendering_finished.push(rendering_finished_semaphore);
-            let fence = unsafe { logical_device.create_fence(&fence_create_info, None) }?;
-            may_begin_rendering.push(fence);
         }
         Ok(Semaphores {
             image_available,
             rendering_finished,
-            may_begin_rendering,
         })
     }
 
-    pub fn render(&mut self) -> Result<(), vk::Result> {
-        let (image_index, _) = unsafe {
-            self.swapchain_loader.acquire_next_image(
-                self.swapchain,
-                std::u64::MAX,
-                self.semaphores.image_available[self.current_image],
-                vk::Fence::null(),
-            )?
+    // Recreates everything that depends on the surface extent.  Called when the window is resized
+    // and when the swapchain reports that it no longer matches the surface.  Does nothing when
+    // rendering headlessly, as there is no surface to follow.
+    #[tracing::instrument(skip_all)]
+    pub fn resize(&mut self) -> Result<(), anyhow::Error> {
+        let Some(presentation) = &self.presentation else {
+            return Ok(());
         };
+        let extent = Self::get_surface_extent(
+            &self.physical_device,
+            &presentation.surface_instance,
+            &presentation.surface,
+            presentation.window_extent,
+        )?;
+        if extent.width == 0 || extent.height == 0 {
+            // A minimised window can't have a swapchain; keep the old one until it is restored.
+            return Ok(());
+        }
+        unsafe { self.logical_device.device_wait_idle()? };
+        self.destroy_swapchain_resources();
+
+        let presentation = self.presentation.as_mut().expect("checked above");
+        let old_swapchain = presentation.swapchain;
+        let (swapchain, swapchain_images, swapchain_image_views) =
+            Self::create_swapchain_and_image_views(
+                &self.physical_device,
+                &self.logical_device,
+                &presentation.surface_instance,
+                &presentation.surface,
+                &self.queue_family_indices,
+                &presentation.swapchain_loader,
+                extent,
+                old_swapchain,
+            )?;
         unsafe {
-            self.logical_device
-                .wait_for_fences(
-                    &[self.semaphores.may_begin_rendering[self.current_image]],
-                    true,
-                    std::u64::MAX,
-                )
-                .expect("fence-waiting");
-            self.logical_device
-                .reset_fences(&[self.semaphores.may_begin_rendering[self.current_image]])
-                .expect("resetting fences");
-        }
-        let semaphores_available = [self.semaphores.image_available[self.current_image]];
-        let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let semaphores_finished = [self.semaphores.rendering_finished[self.current_image]];
-        let commandbuffers = [self.commandbuffers[image_index as usize]];
-        let submit_info = [vk::SubmitInfo::default()
-            .wait_semaphores(&semaphores_available)
-            .wait_dst_stage_mask(&waiting_stages)
-            .command_buffers(&commandbuffers)
-            .signal_semaphores(&semaphores_finished)];
+            presentation
+                .swapchain_loader
+                .destroy_swapchain(old_swapchain, None)
+        };
+        presentation.swapchain = swapchain;
+        self.swapchain_images = swapchain_images;
+        self.swapchain_image_views = swapchain_image_views;
+        self.extent = extent;
+
+        self.attachment_images = AttachmentImages::new(
+            &self.allocator,
+            &self.logical_device,
+            &self.attachment_formats,
+            extent,
+        )?;
+        Self::track_swapchain_and_attachments(
+            &mut self.resource_states,
+            &self.swapchain_images,
+            ImageUsage::Present,
+            &self.attachment_formats,
+            &self.attachment_images,
+        );
+        self.render_graph = Self::create_render_graph(
+            &self.instance,
+            &self.physical_device,
+            &self.logical_device,
+            &self.attachment_images,
+            ImageUsage::Present,
+            extent,
+            self.pass_setup.as_ref(),
+            &mut self.resource_states,
+        )?;
+        self.framebuffers = Self::create_framebuffers(
+            &self.render_pass,
+            &self.logical_device,
+            &self.swapchain_image_views,
+            &self.attachment_images,
+            extent,
+        )?;
+
+        if self.swapchain_image_views.len() != self.image_count {
+            self.image_count = self.swapchain_image_views.len();
+            self.semaphores.destroy(&self.logical_device);
+            self.semaphores = Self::create_semaphores(&self.logical_device, self.image_count)?;
+        }
+        Ok(())
+    }
+
+    // For hosts that dispatch their own window events: call with the window's new size in pixels
+    // when it changes.  Otherwise the same as `resize`.
+    pub fn resize_to(&mut self, window_extent: vk::Extent2D) -> Result<(), anyhow::Error> {
+        if let Some(presentation) = &mut self.presentation {
+            presentation.window_extent = Some(window_extent);
+        }
+        self.resize()
+    }
+
+    fn destroy_swapchain_resources(&mut self) {
+        self.render_graph
+            .destroy(&self.logical_device, &mut self.resource_states);
+        for image in self.swapchain_images.drain(..) {
+            self.resource_states.forget(image);
+        }
+        for image in [&self.attachment_images.color, &self.attachment_images.depth]
+            .into_iter()
+            .flatten()
+        {
+            self.resource_states.forget(image.image);
+        }
         unsafe {
-            self.logical_device
-                .queue_submit(
-                    self.queues.graphics_queue,
-                    &submit_info,
-                    self.semaphores.may_begin_rendering[self.current_image],
-                )
-                .expect("queue submission");
+            for framebuffer in self.framebuffers.drain(..) {
+                self.logical_device.destroy_framebuffer(framebuffer, None);
+            }
+            self.attachment_images.destroy(&self.logical_device);
+            if self.headless_images.is_empty() {
+                for image_view in self.swapchain_image_views.drain(..) {
+                    self.logical_device.destroy_image_view(image_view, None);
+                }
+            } else {
+                self.swapchain_image_views.clear();
+                for image in self.headless_images.iter_mut() {
+                    image.destroy(&self.logical_device);
+                }
+                self.headless_images.clear();
+            }
+        }
+    }
+
+    pub fn render(&mut self) -> Result<(), anyhow::Error> {
+        let frame = self.frame_timeline.last_value() + 1;
+        let _span = tracing::info_span!("render", frame).entered();
+        let frame_slot = frame as usize % FRAMES_IN_FLIGHT;
+        // The command buffer and semaphore for this slot were last used FRAMES_IN_FLIGHT frames
+        // ago; that frame must be done with them.
+        tracing::info_span!("wait_for_frame").in_scope(|| {
+            self.frame_timeline.wait(
+                &self.logical_device,
+                frame.saturating_sub(FRAMES_IN_FLIGHT as u64),
+                u64::MAX,
+            )
+        })?;
+        self.collect_retired()?;
+
+        // Headless targets have an image per frame in flight, so the slot's image is free.
+        let image_index = match &self.presentation {
+            Some(presentation) => {
+                let acquire_result = tracing::info_span!("acquire").in_scope(|| unsafe {
+                    presentation.swapchain_loader.acquire_next_image(
+                        presentation.swapchain,
+                        u64::MAX,
+                        self.semaphores.image_available[frame_slot],
+                        vk::Fence::null(),
+                    )
+                });
+                match acquire_result {
+                    Ok((image_index, _)) => image_index as usize,
+                    Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => return self.resize(),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            None => frame_slot,
+        };
+        // Only once an image was acquired, so that a frame skipped for a resize doesn't read back
+        // the slot's results twice.
+        self.profiler
+            .begin_frame(&self.logical_device, frame_slot)?;
+        self.queries.begin_frame(&self.logical_device, frame_slot)?;
+        self.clock.tick(frame);
+
+        let commandbuffer = self.commandbuffers[frame_slot];
+        let mut resource_states = std::mem::take(&mut self.resource_states);
+        let mut render_graph = std::mem::take(&mut self.render_graph);
+        let profiler = RefCell::new(std::mem::take(&mut self.profiler));
+        let queries = RefCell::new(std::mem::take(&mut self.queries));
+        let record_span = tracing::info_span!("record").entered();
+        let recorded = self.record_frame(
+            &mut render_graph,
+            &mut resource_states,
+            &profiler,
+            &queries,
+            commandbuffer,
+            image_index,
+            self.transfers.pending_acquires(),
+        );
+        record_span.exit();
+        self.resource_states = resource_states;
+        self.render_graph = render_graph;
+        self.profiler = profiler.into_inner();
+        self.queries = queries.into_inner();
+        recorded?;
+
+        // Waiting on the transfer timeline covers every upload submitted so far, including the
+        // ones whose ownership was just acquired.
+        let mut wait_infos = vec![vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.transfers.timeline.semaphore)
+            .value(self.transfers.timeline.last_value())
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)];
+        let commandbuffer_infos =
+            [vk::CommandBufferSubmitInfo::default().command_buffer(commandbuffer)];
+        let mut signal_infos = vec![vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.frame_timeline.semaphore)
+            .value(self.frame_timeline.next_value())
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)];
+        if self.presentation.is_some() {
+            wait_infos.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(self.semaphores.image_available[frame_slot])
+                    .stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT),
+            );
+            signal_infos.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(self.semaphores.rendering_finished[image_index])
+                    .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+            );
+        }
+        let submit_info = vk::SubmitInfo2::default()
+            .wait_semaphore_infos(&wait_infos)
+            .command_buffer_infos(&commandbuffer_infos)
+            .signal_semaphore_infos(&signal_infos);
+        let submitted = Instant::now();
+        tracing::info_span!("submit").in_scope(|| unsafe {
+            self.synchronization2.queue_submit2(
+                self.queues.graphics_queue,
+                &[submit_info],
+                vk::Fence::null(),
+            )
+        })?;
+        // Kept until now so that a frame that fails to record or submit leaves them for the next.
+        self.transfers.clear_acquires();
+        self.profiler.end_frame(frame, submitted);
+        if let Some(capture) = &mut self.capture {
+            capture.frame = Some(frame);
+        }
+
+        let Some(presentation) = &self.presentation else {
+            return Ok(());
         };
-        let swapchains = [self.swapchain];
-        let indices = [image_index];
+        let semaphores_finished = [self.semaphores.rendering_finished[image_index]];
+        let swapchains = [presentation.swapchain];
+        let indices = [image_index as u32];
         let present_info = vk::PresentInfoKHR::default()
             .wait_semaphores(&semaphores_finished)
             .swapchains(&swapchains)
             .image_indices(&indices);
-        unsafe {
-            self.swapchain_loader
-                .queue_present(self.queues.graphics_queue, &present_info)?;
-        };
-        self.current_image = (self.current_image + 1) % self.image_count;
+        let present_result = tracing::info_span!("present").in_scope(|| unsafe {
+            presentation
+                .swapchain_loader
+                .queue_present(self.queues.graphics_queue, &present_info)
+        });
+        match present_result {
+            Ok(false) => Ok(()),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.resize(),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Replaces the application's passes, rebuilding the render graph straight away.
+    pub fn set_passes(
+        &mut self,
+        pass_setup: impl Fn(&mut RenderGraph, vk::Extent2D) + 'static,
+    ) -> Result<(), anyhow::Error> {
+        unsafe { self.logical_device.device_wait_idle()? };
+        self.render_graph
+            .destroy(&self.logical_device, &mut self.resource_states);
+        self.pass_setup = Some(Box::new(pass_setup));
+        self.render_graph = Self::create_render_graph(
+            &self.instance,
+            &self.physical_device,
+            &self.logical_device,
+            &self.attachment_images,
+            Self::target_usage(self.presentation.is_some()),
+            self.extent,
+            self.pass_setup.as_ref(),
+            &mut self.resource_states,
+        )?;
+        Ok(())
+    }
+
+    // Replaces the application's scene draws.  Takes effect from the next frame recorded.
+    pub fn set_scene_draws(&mut self, scene_draws: impl Fn(&Vulkan, vk::CommandBuffer) + 'static) {
+        self.scene_draws = Some(Box::new(scene_draws));
+    }
+
+    // Creates a buffer with memory of its own, tracked so that render graph passes can declare
+    // accesses to it.  Destroy it with `destroy_buffer`.
+    pub fn create_buffer(
+        &mut self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<Buffer, anyhow::Error> {
+        let buffer = Buffer::new(
+            &self.allocator,
+            &self.logical_device,
+            size,
+            usage,
+            properties,
+        )?;
+        self.resource_states.register_buffer(buffer.buffer);
+        Ok(buffer)
+    }
+
+    // The buffer is destroyed once the frames that might use it have finished.
+    pub fn destroy_buffer(&mut self, mut buffer: Buffer) {
+        self.resource_states.forget_buffer(buffer.buffer);
+        self.retire(move |logical_device| buffer.destroy(logical_device));
+    }
+
+    pub fn instance(&self) -> &Instance {
+        &self.instance
+    }
+
+    pub fn physical_device(&self) -> vk::PhysicalDevice {
+        self.physical_device
+    }
+
+    pub fn logical_device(&self) -> &ash::Device {
+        &self.logical_device
+    }
+
+    // The size of the images frames are rendered into.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    // The simulated time of the frame being rendered, for passes to animate with.
+    pub fn frame_time(&self) -> FrameTime {
+        self.clock.frame_time()
+    }
+
+    // Takes effect from the next frame.  Switching clocks restarts time from zero.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = FrameClock::new(clock);
+    }
+
+    // GPU timings of the render graph's passes and of the scopes recorded inside them.
+    pub fn profiler(&self) -> &GpuProfiler {
+        &self.profiler
+    }
+
+    // GPU timelines of the frames read back since the last call, for merging into CPU traces.
+    pub fn take_gpu_timelines(&mut self) -> Vec<GpuFrameTimeline> {
+        self.profiler.take_timelines()
+    }
+
+    // Results of the occlusion and pipeline statistics queries recorded by passes.
+    pub fn queries(&self) -> &GpuQueries {
+        &self.queries
+    }
+
+    // The most recently submitted frame.  Frames are numbered from 1; 0 means none yet.
+    pub fn frame_number(&self) -> u64 {
+        self.frame_timeline.last_value()
+    }
+
+    // Whether the GPU has finished `frame`, without blocking.
+    pub fn is_frame_finished(&self, frame: u64) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .frame_timeline
+            .is_complete(&self.logical_device, frame)?)
+    }
+
+    // Blocks until the GPU has finished `frame`.
+    pub fn wait_for_frame(&self, frame: u64) -> Result<(), anyhow::Error> {
+        self.frame_timeline
+            .wait(&self.logical_device, frame, u64::MAX)?;
+        Ok(())
+    }
+
+    // Defers `destroy` until the GPU has finished every frame submitted so far, i.e. every frame
+    // that might still use the resource, and the next one too, which waits for every upload
+    // submitted so far and acquires the resources they wrote.
+    pub fn retire(&mut self, destroy: impl FnOnce(&ash::Device) + 'static) {
+        self.retired
+            .push(self.frame_timeline.last_value() + 1, destroy);
+    }
+
+    fn collect_retired(&mut self) -> Result<(), anyhow::Error> {
+        self.destroy_released_resources();
+        let completed_frame = self.frame_timeline.completed_value(&self.logical_device)?;
+        self.retired.collect(&self.logical_device, completed_frame);
+        self.transfers.collect(&self.logical_device)?;
+        if let Some(bindless) = self.bindless.as_mut() {
+            bindless.collect(completed_frame);
+        }
         Ok(())
     }
 }
@@ -837,18 +2065,38 @@ impl Vulkan {
 impl Drop for Vulkan {
     fn drop(&mut self) {
         unsafe {
+            self.logical_device
+                .device_wait_idle()
+                .expect("Failed to wait for the device to become idle.");
+            self.destroy_registered_resources();
+            self.retired.destroy_all(&self.logical_device);
+            self.transfers.destroy(&self.logical_device);
+            self.texture_set_pools.destroy(&self.logical_device);
+            self.compute_set_pools.destroy(&self.logical_device);
+            if let Some(skybox) = self.skybox.as_mut() {
+                skybox.destroy(&self.logical_device);
+            }
+            if let Some(bindless) = self.bindless.as_mut() {
+                bindless.destroy(&self.logical_device);
+            }
+            if let Some(transient_ring) = self.transient_ring.as_mut() {
+                transient_ring.destroy(&self.logical_device);
+            }
+            self.samplers.destroy(&self.logical_device);
+            self.frame_timeline.destroy(&self.logical_device);
+            self.profiler.destroy(&self.logical_device);
+            self.queries.destroy(&self.logical_device);
             self.semaphores.destroy(&self.logical_device);
             self.command_pools.destroy(&self.logical_device);
-            self.framebuffers.iter().for_each(|framebuffer| {
-                self.logical_device.destroy_framebuffer(*framebuffer, None);
-            });
-            let image_views = std::mem::take(&mut self.swapchain_image_views);
-            for image_view in image_views {
-                self.logical_device.destroy_image_view(image_view, None);
+            self.destroy_swapchain_resources();
+            if let Some(presentation) = &self.presentation {
+                presentation
+                    .swapchain_loader
+                    .destroy_swapchain(presentation.swapchain, None);
+                presentation
+                    .surface_instance
+                    .destroy_surface(presentation.surface, None);
             }
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
-            self.surface_instance.destroy_surface(self.surface, None);
             self.logical_device
                 .destroy_render_pass(self.render_pass, None);
             self.logical_device
@@ -858,10 +2106,15 @@ impl Drop for Vulkan {
             self.logical_device
                 .destroy_pipeline_layout(self.pipeline_layout, None);
             self.logical_device.destroy_pipeline(self.pipeline, None);
-            self.logical_device.destroy_device(None);
-            self.debug_utils
-                .destroy_debug_utils_messenger(self.debug_utils_messenger, None);
-            self.instance.destroy_instance(None);
+            // Everything cinder created has been destroyed, so what is left was never destroyed.
+            self.allocator.report_leaks();
+            self.allocator.destroy(&self.logical_device);
+            if !self.external_device {
+                self.logical_device.destroy_device(None);
+                self.debug_utils
+                    .destroy_debug_utils_messenger(self.debug_utils_messenger, None);
+                self.instance.destroy_instance(None);
+            }
         }
     }
 }
@@ -0,0 +1,886 @@
+// Device memory.  Buffers and images are placed in large blocks of memory, many to a block, rather
+// than each getting a `vkAllocateMemory` of its own: `maxMemoryAllocationCount` can be as low as
+// 4096.  Blocks belong to one memory type, and buffers and images never share a block, so
+// `bufferImageGranularity` needn't be considered.  Requests of more than half a block get a
+// dedicated allocation.  Host-visible memory is mapped once when it is allocated and stays mapped.
+//
+// An `Allocation` keeps the allocator alive and is freed with just the device, so destruction
+// deferred with `Vulkan::retire` needs nothing more than before.  Whatever is still allocated when
+// `Vulkan` is dropped is reported as leaked.
+//
+// Registered buffers, whose owner is the registry, can be moved out of sparsely used blocks with
+// `Vulkan::defragment`.
+
+use anyhow::anyhow;
+use ash::vk;
+use ash::Instance;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::buffer::Buffer;
+use super::registry::ResourceId;
+use super::Vulkan;
+
+// The most a block holds.  Heaps too small for a few of them get smaller blocks.
+const BLOCK_SIZE: vk::DeviceSize = 64 << 20;
+const MIN_BLOCKS_PER_HEAP: vk::DeviceSize = 8;
+
+// What an allocation is bound to.  Buffers are linear resources, optimally tiled images are not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Buffer,
+    Image,
+}
+
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    // The properties asked for; the memory type may have more.
+    pub properties: vk::MemoryPropertyFlags,
+    // Null unless the memory is host visible.
+    mapped: *mut u8,
+    id: u64,
+    allocator: Allocator,
+}
+
+impl Allocation {
+    // The start of the allocation in host memory, if it is host visible.
+    pub fn mapped(&self) -> Option<*mut u8> {
+        (!self.mapped.is_null()).then_some(self.mapped)
+    }
+
+    // The device must no longer be using the memory.
+    pub fn free(&mut self, logical_device: &ash::Device) {
+        self.allocator
+            .state
+            .borrow_mut()
+            .free(logical_device, self.id);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub size: vk::DeviceSize,
+    pub flags: vk::MemoryHeapFlags,
+    pub block_count: usize,
+    pub block_bytes: vk::DeviceSize,
+    // Every allocation, dedicated ones included.
+    pub allocation_count: usize,
+    pub allocated_bytes: vk::DeviceSize,
+    pub dedicated_count: usize,
+    pub dedicated_bytes: vk::DeviceSize,
+    // From VK_EXT_memory_budget, when the device has it: how much of the heap the whole process
+    // uses, and how much it can use before allocations may fail or perform worse.
+    pub usage: Option<vk::DeviceSize>,
+    pub budget: Option<vk::DeviceSize>,
+}
+
+struct Block {
+    memory: vk::DeviceMemory,
+    memory_type_index: u32,
+    kind: ResourceKind,
+    size: vk::DeviceSize,
+    mapped: *mut u8,
+    // Unused ranges as (offset, size), sorted by offset and never adjacent.
+    free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    allocations: usize,
+    // Skipped by new allocations while `Vulkan::defragment` moves buffers out.
+    evacuating: bool,
+}
+
+impl Block {
+    fn used(&self) -> vk::DeviceSize {
+        self.size
+            - self
+                .free
+                .iter()
+                .map(|(_, size)| size)
+                .sum::<vk::DeviceSize>()
+    }
+
+    // First fit.
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let (index, offset) = self
+            .free
+            .iter()
+            .enumerate()
+            .find_map(|(index, &(start, len))| {
+                let offset = start.next_multiple_of(alignment);
+                (offset + size <= start + len).then_some((index, offset))
+            })?;
+        let (start, len) = self.free.remove(index);
+        let mut position = index;
+        if offset > start {
+            self.free.insert(position, (start, offset - start));
+            position += 1;
+        }
+        if offset + size < start + len {
+            self.free
+                .insert(position, (offset + size, start + len - offset - size));
+        }
+        self.allocations += 1;
+        Some(offset)
+    }
+
+    fn release(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free.partition_point(|&(start, _)| start < offset);
+        self.free.insert(index, (offset, size));
+        if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+            self.free[index - 1].1 += self.free.remove(index).1;
+        }
+        self.allocations -= 1;
+    }
+}
+
+enum Placement {
+    // An index into `AllocatorState::blocks`.
+    Block(usize),
+    Dedicated(vk::DeviceMemory),
+}
+
+struct LiveAllocation {
+    kind: ResourceKind,
+    memory_type_index: u32,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    placement: Placement,
+}
+
+struct AllocatorState {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    // None where a freed block was.
+    blocks: Vec<Option<Block>>,
+    live: HashMap<u64, LiveAllocation>,
+    next_id: u64,
+}
+
+// Cheap to clone; every clone is the same allocator.
+#[derive(Clone)]
+pub struct Allocator {
+    state: Rc<RefCell<AllocatorState>>,
+}
+
+fn map_whole(
+    logical_device: &ash::Device,
+    memory: vk::DeviceMemory,
+) -> Result<*mut u8, vk::Result> {
+    unsafe {
+        logical_device
+            .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+            .map(|pointer| pointer.cast::<u8>())
+    }
+}
+
+impl AllocatorState {
+    fn memory_type_index(
+        &self,
+        memory_type_bits: u32,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<u32, anyhow::Error> {
+        self.memory_properties
+            .memory_types_as_slice()
+            .iter()
+            .enumerate()
+            .find(|(index, memory_type)| {
+                memory_type_bits & (1 << index) != 0
+                    && memory_type.property_flags.contains(properties)
+            })
+            .map(|(index, _)| index as u32)
+            .ok_or_else(|| anyhow!("No memory type with properties {:?} found.", properties))
+    }
+
+    fn block_size(&self, memory_type_index: u32) -> vk::DeviceSize {
+        let heap_index =
+            self.memory_properties.memory_types[memory_type_index as usize].heap_index as usize;
+        BLOCK_SIZE.min(self.memory_properties.memory_heaps[heap_index].size / MIN_BLOCKS_PER_HEAP)
+    }
+
+    fn is_dedicated(&self, memory_type_index: u32, size: vk::DeviceSize) -> bool {
+        size > self.block_size(memory_type_index) / 2
+    }
+
+    fn is_host_visible(&self, memory_type_index: u32) -> bool {
+        self.memory_properties.memory_types[memory_type_index as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+    }
+
+    fn allocate_memory(
+        &self,
+        logical_device: &ash::Device,
+        memory_type_index: u32,
+        size: vk::DeviceSize,
+    ) -> Result<(vk::DeviceMemory, *mut u8), anyhow::Error> {
+        let memory_allocate_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+        let memory = unsafe { logical_device.allocate_memory(&memory_allocate_info, None)? };
+        if !self.is_host_visible(memory_type_index) {
+            return Ok((memory, std::ptr::null_mut()));
+        }
+        match map_whole(logical_device, memory) {
+            Ok(mapped) => Ok((memory, mapped)),
+            Err(err) => {
+                unsafe { logical_device.free_memory(memory, None) };
+                Err(err.into())
+            }
+        }
+    }
+
+    // Returns the id, memory, offset and mapping of the new allocation.
+    fn allocate(
+        &mut self,
+        logical_device: &ash::Device,
+        requirements: vk::MemoryRequirements,
+        properties: vk::MemoryPropertyFlags,
+        kind: ResourceKind,
+    ) -> Result<(u64, vk::DeviceMemory, vk::DeviceSize, *mut u8), anyhow::Error> {
+        let memory_type_index =
+            self.memory_type_index(requirements.memory_type_bits, properties)?;
+        let block_size = self.block_size(memory_type_index);
+        let id = self.next_id;
+        self.next_id += 1;
+
+        if self.is_dedicated(memory_type_index, requirements.size) {
+            let (memory, mapped) =
+                self.allocate_memory(logical_device, memory_type_index, requirements.size)?;
+            self.live.insert(
+                id,
+                LiveAllocation {
+                    kind,
+                    memory_type_index,
+                    offset: 0,
+                    size: requirements.size,
+                    placement: Placement::Dedicated(memory),
+                },
+            );
+            return Ok((id, memory, 0, mapped));
+        }
+
+        let placed = self.place(memory_type_index, kind, requirements);
+        let (index, offset) = match placed {
+            Some(placed) => placed,
+            None => {
+                let (memory, mapped) =
+                    self.allocate_memory(logical_device, memory_type_index, block_size)?;
+                let mut block = Block {
+                    memory,
+                    memory_type_index,
+                    kind,
+                    size: block_size,
+                    mapped,
+                    free: vec![(0, block_size)],
+                    allocations: 0,
+                    evacuating: false,
+                };
+                let offset = block
+                    .allocate(requirements.size, requirements.alignment)
+                    .expect("An empty block holds any allocation of up to half its size.");
+                let index = match self.blocks.iter().position(Option::is_none) {
+                    Some(index) => {
+                        self.blocks[index] = Some(block);
+                        index
+                    }
+                    None => {
+                        self.blocks.push(Some(block));
+                        self.blocks.len() - 1
+                    }
+                };
+                (index, offset)
+            }
+        };
+        self.live.insert(
+            id,
+            LiveAllocation {
+                kind,
+                memory_type_index,
+                offset,
+                size: requirements.size,
+                placement: Placement::Block(index),
+            },
+        );
+        let block = self.blocks[index].as_ref().expect("placed above");
+        let mapped = if block.mapped.is_null() {
+            block.mapped
+        } else {
+            unsafe { block.mapped.add(offset as usize) }
+        };
+        Ok((id, block.memory, offset, mapped))
+    }
+
+    // Suballocates from the first existing block that has room, returning its index and the offset.
+    fn place(
+        &mut self,
+        memory_type_index: u32,
+        kind: ResourceKind,
+        requirements: vk::MemoryRequirements,
+    ) -> Option<(usize, vk::DeviceSize)> {
+        self.blocks
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, block)| Some((index, block.as_mut()?)))
+            .filter(|(_, block)| {
+                block.memory_type_index == memory_type_index
+                    && block.kind == kind
+                    && !block.evacuating
+            })
+            .find_map(|(index, block)| {
+                let offset = block.allocate(requirements.size, requirements.alignment)?;
+                Some((index, offset))
+            })
+    }
+
+    fn free(&mut self, logical_device: &ash::Device, id: u64) {
+        let Some(allocation) = self.live.remove(&id) else {
+            return;
+        };
+        let index = match allocation.placement {
+            Placement::Dedicated(memory) => {
+                unsafe { logical_device.free_memory(memory, None) };
+                return;
+            }
+            Placement::Block(index) => index,
+        };
+        let block = self.blocks[index]
+            .as_mut()
+            .expect("Allocation in a freed block.");
+        block.release(allocation.offset, allocation.size);
+        if block.allocations > 0 {
+            return;
+        }
+        // One empty block per memory type and kind is kept for the next allocation.
+        let (memory_type_index, kind) = (block.memory_type_index, block.kind);
+        let has_other = self.blocks.iter().enumerate().any(|(other, block)| {
+            other != index
+                && block.as_ref().is_some_and(|block| {
+                    block.memory_type_index == memory_type_index && block.kind == kind
+                })
+        });
+        if has_other {
+            let block = self.blocks[index].take().expect("checked above");
+            unsafe { logical_device.free_memory(block.memory, None) };
+        }
+    }
+}
+
+impl Allocator {
+    pub fn new(instance: &Instance, physical_device: &vk::PhysicalDevice) -> Self {
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(*physical_device) };
+        Self {
+            state: Rc::new(RefCell::new(AllocatorState {
+                memory_properties,
+                blocks: Vec::new(),
+                live: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    pub fn allocate(
+        &self,
+        logical_device: &ash::Device,
+        requirements: vk::MemoryRequirements,
+        properties: vk::MemoryPropertyFlags,
+        kind: ResourceKind,
+    ) -> Result<Allocation, anyhow::Error> {
+        let (id, memory, offset, mapped) =
+            self.state
+                .borrow_mut()
+                .allocate(logical_device, requirements, properties, kind)?;
+        Ok(Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            properties,
+            mapped,
+            id,
+            allocator: self.clone(),
+        })
+    }
+
+    pub fn heap_stats(&self) -> Vec<HeapStats> {
+        let state = self.state.borrow();
+        let memory_properties = &state.memory_properties;
+        let heap_index = |memory_type_index: u32| {
+            memory_properties.memory_types[memory_type_index as usize].heap_index as usize
+        };
+        let mut heaps: Vec<_> = memory_properties
+            .memory_heaps_as_slice()
+            .iter()
+            .map(|heap| HeapStats {
+                size: heap.size,
+                flags: heap.flags,
+                ..Default::default()
+            })
+            .collect();
+        for block in state.blocks.iter().flatten() {
+            let heap = &mut heaps[heap_index(block.memory_type_index)];
+            heap.block_count += 1;
+            heap.block_bytes += block.size;
+        }
+        for allocation in state.live.values() {
+            let heap = &mut heaps[heap_index(allocation.memory_type_index)];
+            heap.allocation_count += 1;
+            heap.allocated_bytes += allocation.size;
+            if matches!(allocation.placement, Placement::Dedicated(_)) {
+                heap.dedicated_count += 1;
+                heap.dedicated_bytes += allocation.size;
+            }
+        }
+        heaps
+    }
+
+    // Marks blocks of buffers whose contents would fit in the other blocks of their memory type,
+    // least used first, so that new allocations avoid them.  Returns whether any were marked.
+    fn begin_evacuation(&self) -> bool {
+        let mut state = self.state.borrow_mut();
+        let mut groups: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in state.blocks.iter().enumerate() {
+            if let Some(block) = block
+                .as_ref()
+                .filter(|block| block.kind == ResourceKind::Buffer)
+            {
+                groups
+                    .entry(block.memory_type_index)
+                    .or_default()
+                    .push(index);
+            }
+        }
+        let mut marked = false;
+        for mut indices in groups.into_values() {
+            let block = |index: usize| state.blocks[index].as_ref().expect("listed above");
+            indices.sort_by_key(|&index| block(index).used());
+            let mut spare: vk::DeviceSize = indices
+                .iter()
+                .map(|&index| block(index).size - block(index).used())
+                .sum();
+            let mut evacuated = Vec::new();
+            // The fullest block always stays.
+            for &index in &indices[..indices.len() - 1] {
+                let used = block(index).used();
+                // Its own free space can't take what is moved out of it.
+                spare -= block(index).size - used;
+                if used > spare {
+                    break;
+                }
+                spare -= used;
+                evacuated.push(index);
+            }
+            for index in evacuated {
+                state.blocks[index]
+                    .as_mut()
+                    .expect("listed above")
+                    .evacuating = true;
+                marked = true;
+            }
+        }
+        marked
+    }
+
+    fn is_evacuating(&self, allocation: &Allocation) -> bool {
+        let state = self.state.borrow();
+        state
+            .live
+            .get(&allocation.id)
+            .is_some_and(|live| match live.placement {
+                Placement::Block(index) => state.blocks[index]
+                    .as_ref()
+                    .is_some_and(|block| block.evacuating),
+                Placement::Dedicated(_) => false,
+            })
+    }
+
+    // Whether `buffer` is one `Vulkan::defragment` moves: it can be copied from, and its block is
+    // being evacuated.
+    fn is_movable(&self, buffer: &Buffer) -> bool {
+        buffer.usage.contains(vk::BufferUsageFlags::TRANSFER_SRC)
+            && self.is_evacuating(&buffer.allocation)
+    }
+
+    fn end_evacuation(&self) {
+        for block in self.state.borrow_mut().blocks.iter_mut().flatten() {
+            block.evacuating = false;
+        }
+    }
+
+    // Warns about what is still allocated.  Only meaningful once everything cinder owns is destroyed.
+    pub fn report_leaks(&self) {
+        let state = self.state.borrow();
+        if state.live.is_empty() {
+            return;
+        }
+        let bytes: vk::DeviceSize = state.live.values().map(|allocation| allocation.size).sum();
+        tracing::warn!(
+            "Leaked {} device memory allocations, {} bytes:",
+            state.live.len(),
+            bytes
+        );
+        let mut ids: Vec<_> = state.live.keys().collect();
+        ids.sort();
+        for id in ids {
+            let allocation = &state.live[id];
+            tracing::warn!(
+                "Leaked allocation #{}: {:?} of {} bytes in memory type {}",
+                id,
+                allocation.kind,
+                allocation.size,
+                allocation.memory_type_index
+            );
+        }
+    }
+
+    // Frees every block and dedicated allocation, leaked ones included.  Only safe once the device
+    // is idle.
+    pub fn destroy(&self, logical_device: &ash::Device) {
+        let mut state = self.state.borrow_mut();
+        for (_, allocation) in state.live.drain() {
+            if let Placement::Dedicated(memory) = allocation.placement {
+                unsafe { logical_device.free_memory(memory, None) };
+            }
+        }
+        for block in state.blocks.drain(..).flatten() {
+            unsafe { logical_device.free_memory(block.memory, None) };
+        }
+    }
+}
+
+// A registered buffer's contents, copied to its new place before the next frame uses it.
+pub struct BufferRelocation {
+    pub src: vk::Buffer,
+    pub dst: vk::Buffer,
+    pub size: vk::DeviceSize,
+}
+
+impl Vulkan {
+    // Per memory heap, in the order of `vk::PhysicalDeviceMemoryProperties::memory_heaps`.
+    pub fn memory_stats(&self) -> Vec<HeapStats> {
+        let mut heaps = self.allocator.heap_stats();
+        if self.device_features.memory_budget {
+            let mut budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+            let mut memory_properties =
+                vk::PhysicalDeviceMemoryProperties2::default().push_next(&mut budget_properties);
+            unsafe {
+                self.instance.get_physical_device_memory_properties2(
+                    self.physical_device,
+                    &mut memory_properties,
+                )
+            };
+            for (index, heap) in heaps.iter_mut().enumerate() {
+                heap.usage = Some(budget_properties.heap_usage[index]);
+                heap.budget = Some(budget_properties.heap_budget[index]);
+            }
+        }
+        heaps
+    }
+
+    // Moves registered buffers out of sparsely used blocks so those blocks can be freed, and
+    // returns the ids of the buffers moved.  Their contents are copied at the start of the next
+    // frame, and their old memory freed once it is done.  A moved buffer has a new `vk::Buffer`,
+    // so descriptors and bindless slots referring to it need to be written again.  Only buffers
+    // with TRANSFER_SRC usage can be moved; buffers outside the registry never are.
+    pub fn defragment(&mut self) -> Result<Vec<ResourceId<Buffer>>, anyhow::Error> {
+        if !self.allocator.begin_evacuation() {
+            return Ok(Vec::new());
+        }
+        let movable: Vec<_> = self
+            .registry
+            .buffers()
+            .filter(|(_, buffer)| self.allocator.is_movable(buffer))
+            .map(|(id, buffer)| (id, buffer.size, buffer.usage, buffer.allocation.properties))
+            .collect();
+        let mut moved = Vec::new();
+        let mut result = Ok(());
+        for (id, size, usage, properties) in movable {
+            if let Err(err) = self.relocate_buffer(id, size, usage, properties) {
+                result = Err(err);
+                break;
+            }
+            moved.push(id);
+        }
+        self.allocator.end_evacuation();
+        result.map(|()| moved)
+    }
+
+    // Gives the registered buffer `id` new memory, copying its contents over in the next frame.
+    fn relocate_buffer(
+        &mut self,
+        id: ResourceId<Buffer>,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<(), anyhow::Error> {
+        let src = self.registry.buffer(id)?.buffer;
+        let buffer =
+            self.create_buffer(size, usage | vk::BufferUsageFlags::TRANSFER_DST, properties)?;
+        let dst = buffer.buffer;
+        match self.registry.replace_buffer(id, buffer) {
+            Ok(old) => {
+                self.transfers
+                    .push_buffer_relocation(BufferRelocation { src, dst, size });
+                self.destroy_buffer(old);
+                Ok(())
+            }
+            Err(buffer) => {
+                self.destroy_buffer(buffer);
+                Err(anyhow!("Stale buffer handle {:?}.", id))
+            }
+        }
+    }
+
+    pub(super) fn record_buffer_relocations(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        relocations: &[BufferRelocation],
+    ) {
+        if relocations.is_empty() {
+            return;
+        }
+        // The buffers may have been written by any earlier frame, and may be used in any way
+        // after.
+        let before = [vk::MemoryBarrier2::default()
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+            .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+            .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)];
+        let after = [vk::MemoryBarrier2::default()
+            .src_stage_mask(vk::PipelineStageFlags2::COPY)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+            .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)];
+        unsafe {
+            self.synchronization2.cmd_pipeline_barrier2(
+                commandbuffer,
+                &vk::DependencyInfo::default().memory_barriers(&before),
+            );
+            for relocation in relocations {
+                self.logical_device.cmd_copy_buffer(
+                    commandbuffer,
+                    relocation.src,
+                    relocation.dst,
+                    &[vk::BufferCopy::default().size(relocation.size)],
+                );
+            }
+            self.synchronization2.cmd_pipeline_barrier2(
+                commandbuffer,
+                &vk::DependencyInfo::default().memory_barriers(&after),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: vk::DeviceSize = 1 << 20;
+
+    fn block(kind: ResourceKind, size: vk::DeviceSize) -> Block {
+        Block {
+            memory: vk::DeviceMemory::null(),
+            memory_type_index: 0,
+            kind,
+            size,
+            mapped: std::ptr::null_mut(),
+            free: vec![(0, size)],
+            allocations: 0,
+            evacuating: false,
+        }
+    }
+
+    // One device-local memory type on a heap of `heap_size` bytes.
+    fn allocator(heap_size: vk::DeviceSize, blocks: Vec<Block>) -> Allocator {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 1,
+            memory_heap_count: 1,
+            ..Default::default()
+        };
+        memory_properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        memory_properties.memory_heaps[0].size = heap_size;
+        Allocator {
+            state: Rc::new(RefCell::new(AllocatorState {
+                memory_properties,
+                blocks: blocks.into_iter().map(Some).collect(),
+                live: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    fn requirements(size: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment,
+            memory_type_bits: 1,
+        }
+    }
+
+    #[test]
+    fn allocations_are_padded_to_their_alignment() {
+        let mut block = block(ResourceKind::Buffer, 1024);
+        assert_eq!(block.allocate(10, 1), Some(0));
+        assert_eq!(block.allocate(16, 256), Some(256));
+        // The padding stays free for smaller alignments.
+        assert_eq!(block.free, vec![(10, 246), (272, 752)]);
+        assert_eq!(block.allocate(8, 8), Some(16));
+        assert_eq!(block.allocate(800, 256), None);
+        assert_eq!(block.allocations, 3);
+        assert_eq!(block.used(), 10 + 16 + 8);
+    }
+
+    #[test]
+    fn released_ranges_merge_with_their_neighbours() {
+        let mut block = block(ResourceKind::Buffer, 1024);
+        let a = block.allocate(100, 1).unwrap();
+        let b = block.allocate(100, 1).unwrap();
+        let c = block.allocate(100, 1).unwrap();
+        block.release(a, 100);
+        block.release(c, 100);
+        assert_eq!(block.free, vec![(0, 100), (200, 824)]);
+        block.release(b, 100);
+        assert_eq!(block.free, vec![(0, 1024)]);
+        assert_eq!(block.allocations, 0);
+    }
+
+    #[test]
+    fn released_ranges_merge_with_one_neighbour() {
+        let mut block = block(ResourceKind::Buffer, 1024);
+        let a = block.allocate(100, 1).unwrap();
+        let b = block.allocate(100, 1).unwrap();
+        let c = block.allocate(100, 1).unwrap();
+        let d = block.allocate(100, 1).unwrap();
+        block.release(b, 100);
+        block.release(a, 100);
+        assert_eq!(block.free, vec![(0, 200), (400, 624)]);
+        block.release(c, 100);
+        assert_eq!(block.free, vec![(0, 300), (400, 624)]);
+        block.release(d, 100);
+        assert_eq!(block.free, vec![(0, 1024)]);
+    }
+
+    #[test]
+    fn requests_of_more_than_half_a_block_are_dedicated() {
+        let state = allocator(1 << 30, Vec::new()).state;
+        let state = state.borrow();
+        assert_eq!(state.block_size(0), BLOCK_SIZE);
+        assert!(!state.is_dedicated(0, BLOCK_SIZE / 2));
+        assert!(state.is_dedicated(0, BLOCK_SIZE / 2 + 1));
+
+        // Small heaps get smaller blocks, and so a lower threshold.
+        let state = allocator(64 * MIB, Vec::new()).state;
+        let state = state.borrow();
+        assert_eq!(state.block_size(0), 8 * MIB);
+        assert!(!state.is_dedicated(0, 4 * MIB));
+        assert!(state.is_dedicated(0, 4 * MIB + 1));
+    }
+
+    #[test]
+    fn sparse_buffer_blocks_are_evacuated_into_fuller_ones() {
+        let mut blocks: Vec<_> = (0..4).map(|_| block(ResourceKind::Buffer, 1024)).collect();
+        blocks[0].allocate(900, 1).unwrap();
+        blocks[1].allocate(60, 1).unwrap();
+        blocks[2].allocate(50, 1).unwrap();
+        blocks[3].kind = ResourceKind::Image;
+        blocks[3].allocate(10, 1).unwrap();
+        let allocator = allocator(1 << 30, blocks);
+        assert!(allocator.begin_evacuation());
+        let evacuating: Vec<_> = allocator
+            .state
+            .borrow()
+            .blocks
+            .iter()
+            .flatten()
+            .map(|block| block.evacuating)
+            .collect();
+        // The fullest block stays, and image blocks are never evacuated.
+        assert_eq!(evacuating, vec![false, true, true, false]);
+
+        // Moved buffers land in the block that stays, at its lowest free offset.
+        let mut state = allocator.state.borrow_mut();
+        assert_eq!(
+            state.place(0, ResourceKind::Buffer, requirements(50, 16)),
+            Some((0, 912))
+        );
+        assert_eq!(
+            state.place(0, ResourceKind::Buffer, requirements(100, 16)),
+            None
+        );
+        drop(state);
+        allocator.end_evacuation();
+        assert_eq!(
+            allocator
+                .state
+                .borrow_mut()
+                .place(0, ResourceKind::Buffer, requirements(100, 16)),
+            Some((1, 64))
+        );
+    }
+
+    #[test]
+    fn blocks_whose_contents_fit_nowhere_else_stay() {
+        let mut blocks: Vec<_> = (0..2).map(|_| block(ResourceKind::Buffer, 1024)).collect();
+        blocks[0].allocate(900, 1).unwrap();
+        blocks[1].allocate(200, 1).unwrap();
+        let allocator = allocator(1 << 30, blocks);
+        assert!(!allocator.begin_evacuation());
+    }
+
+    #[test]
+    fn only_buffers_that_can_be_copied_from_are_moved() {
+        let mut blocks: Vec<_> = (0..2).map(|_| block(ResourceKind::Buffer, 1024)).collect();
+        blocks[0].allocate(900, 1).unwrap();
+        let allocator = allocator(1 << 30, blocks);
+        let buffer = |usage, index: usize| {
+            let mut state = allocator.state.borrow_mut();
+            let offset = state.blocks[index]
+                .as_mut()
+                .unwrap()
+                .allocate(16, 16)
+                .unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.live.insert(
+                id,
+                LiveAllocation {
+                    kind: ResourceKind::Buffer,
+                    memory_type_index: 0,
+                    offset,
+                    size: 16,
+                    placement: Placement::Block(index),
+                },
+            );
+            Buffer {
+                buffer: vk::Buffer::null(),
+                allocation: Allocation {
+                    memory: vk::DeviceMemory::null(),
+                    offset,
+                    size: 16,
+                    properties: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    mapped: std::ptr::null_mut(),
+                    id,
+                    allocator: allocator.clone(),
+                },
+                size: 16,
+                usage,
+            }
+        };
+        let copyable = buffer(vk::BufferUsageFlags::TRANSFER_SRC, 1);
+        let pinned = buffer(vk::BufferUsageFlags::UNIFORM_BUFFER, 1);
+        let kept = buffer(vk::BufferUsageFlags::TRANSFER_SRC, 0);
+        assert!(allocator.begin_evacuation());
+        assert!(allocator.is_movable(&copyable));
+        assert!(!allocator.is_movable(&pinned));
+        assert!(!allocator.is_movable(&kept));
+        allocator.end_evacuation();
+        assert!(!allocator.is_movable(&copyable));
+    }
+}
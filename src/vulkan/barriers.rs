@@ -0,0 +1,296 @@
+// Resource state tracking.  Each image's current layout, and the access/stage that last touched
+// each image or buffer, are recorded here, so moving a resource to a new usage only requires
+// naming that usage; the VK_KHR_synchronization2 barrier is derived from the two states.
+
+use ash::vk;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageUsage {
+    ColorAttachment,
+    DepthStencilAttachment,
+    // Sampled from fragment or compute shaders.
+    ShaderRead,
+    // Read and written as a storage image by fragment or compute shaders.
+    Storage,
+    TransferSrc,
+    TransferDst,
+    Present,
+}
+
+impl ImageUsage {
+    pub(crate) fn state(self) -> ImageState {
+        let (layout, access, stage) = match self {
+            ImageUsage::ColorAttachment => (
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+                vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            ImageUsage::DepthStencilAttachment => (
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS,
+            ),
+            ImageUsage::ShaderRead => (
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags2::SHADER_SAMPLED_READ,
+                vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER,
+            ),
+            ImageUsage::Storage => (
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER,
+            ),
+            ImageUsage::TransferSrc => (
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags2::TRANSFER_READ,
+                vk::PipelineStageFlags2::ALL_TRANSFER,
+            ),
+            ImageUsage::TransferDst => (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags2::TRANSFER_WRITE,
+                vk::PipelineStageFlags2::ALL_TRANSFER,
+            ),
+            // Presentation has no access of its own; the stage matches the one the
+            // image-available semaphore is waited on, so the next frame's barrier chains with it.
+            ImageUsage::Present => (
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                vk::AccessFlags2::NONE,
+                vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            ),
+        };
+        ImageState {
+            layout,
+            access,
+            stage,
+        }
+    }
+
+    fn is_read_only(self) -> bool {
+        matches!(
+            self,
+            ImageUsage::ShaderRead | ImageUsage::TransferSrc | ImageUsage::Present
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferUsage {
+    VertexInput,
+    IndexInput,
+    Uniform,
+    StorageRead,
+    StorageWrite,
+    Indirect,
+    TransferSrc,
+    TransferDst,
+}
+
+impl BufferUsage {
+    fn access_and_stage(self) -> (vk::AccessFlags2, vk::PipelineStageFlags2) {
+        let shader_stages = vk::PipelineStageFlags2::VERTEX_SHADER
+            | vk::PipelineStageFlags2::FRAGMENT_SHADER
+            | vk::PipelineStageFlags2::COMPUTE_SHADER;
+        match self {
+            BufferUsage::VertexInput => (
+                vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+                vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT,
+            ),
+            BufferUsage::IndexInput => (
+                vk::AccessFlags2::INDEX_READ,
+                vk::PipelineStageFlags2::INDEX_INPUT,
+            ),
+            BufferUsage::Uniform => (vk::AccessFlags2::UNIFORM_READ, shader_stages),
+            BufferUsage::StorageRead => (vk::AccessFlags2::SHADER_STORAGE_READ, shader_stages),
+            BufferUsage::StorageWrite => (
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                shader_stages,
+            ),
+            BufferUsage::Indirect => (
+                vk::AccessFlags2::INDIRECT_COMMAND_READ,
+                vk::PipelineStageFlags2::DRAW_INDIRECT,
+            ),
+            BufferUsage::TransferSrc => (
+                vk::AccessFlags2::TRANSFER_READ,
+                vk::PipelineStageFlags2::ALL_TRANSFER,
+            ),
+            BufferUsage::TransferDst => (
+                vk::AccessFlags2::TRANSFER_WRITE,
+                vk::PipelineStageFlags2::ALL_TRANSFER,
+            ),
+        }
+    }
+
+    fn is_write(self) -> bool {
+        matches!(self, BufferUsage::StorageWrite | BufferUsage::TransferDst)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageState {
+    pub layout: vk::ImageLayout,
+    pub access: vk::AccessFlags2,
+    pub stage: vk::PipelineStageFlags2,
+}
+
+impl ImageState {
+    // The state of an image whose contents don't matter, but which may still be in use by work
+    // submitted earlier in the given way.  Used for attachments shared between frames in flight.
+    pub fn discarded_after(usage: ImageUsage) -> Self {
+        Self {
+            layout: vk::ImageLayout::UNDEFINED,
+            ..usage.state()
+        }
+    }
+}
+
+struct TrackedImage {
+    state: ImageState,
+    usage: Option<ImageUsage>,
+    subresource_range: vk::ImageSubresourceRange,
+}
+
+// Buffers have no layout, so only the last usage is kept.
+struct TrackedBuffer {
+    usage: Option<BufferUsage>,
+}
+
+#[derive(Default)]
+pub struct ResourceStateTracker {
+    images: HashMap<vk::Image, TrackedImage>,
+    buffers: HashMap<vk::Buffer, TrackedBuffer>,
+    pending_barriers: Vec<vk::ImageMemoryBarrier2<'static>>,
+    pending_buffer_barriers: Vec<vk::BufferMemoryBarrier2<'static>>,
+}
+
+impl ResourceStateTracker {
+    pub fn register(
+        &mut self,
+        image: vk::Image,
+        subresource_range: vk::ImageSubresourceRange,
+        state: ImageState,
+    ) {
+        self.images.insert(
+            image,
+            TrackedImage {
+                state,
+                usage: None,
+                subresource_range,
+            },
+        );
+    }
+
+    pub fn forget(&mut self, image: vk::Image) {
+        self.images.remove(&image);
+    }
+
+    pub(crate) fn state(&self, image: vk::Image) -> Option<ImageState> {
+        self.images.get(&image).map(|tracked| tracked.state)
+    }
+
+    // Queues a barrier moving `image` to `usage`, preserving its contents.  Nothing is queued when
+    // the image is already being read the same way.
+    pub fn transition(&mut self, image: vk::Image, usage: ImageUsage) {
+        self.transition_impl(image, usage, false);
+    }
+
+    // As `transition`, but the current contents may be thrown away, e.g. before a clear.
+    pub fn transition_discarding(&mut self, image: vk::Image, usage: ImageUsage) {
+        self.transition_impl(image, usage, true);
+    }
+
+    fn transition_impl(&mut self, image: vk::Image, usage: ImageUsage, discard: bool) {
+        let tracked = self
+            .images
+            .get_mut(&image)
+            .unwrap_or_else(|| panic!("Image {:?} is not tracked.", image));
+        let new_state = usage.state();
+        if tracked.usage == Some(usage) && usage.is_read_only() && !discard {
+            return;
+        }
+        let old_layout = if discard {
+            vk::ImageLayout::UNDEFINED
+        } else {
+            tracked.state.layout
+        };
+        self.pending_barriers.push(
+            vk::ImageMemoryBarrier2::default()
+                .image(image)
+                .subresource_range(tracked.subresource_range)
+                .old_layout(old_layout)
+                .new_layout(new_state.layout)
+                .src_stage_mask(tracked.state.stage)
+                .src_access_mask(tracked.state.access)
+                .dst_stage_mask(new_state.stage)
+                .dst_access_mask(new_state.access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED),
+        );
+        tracked.state = new_state;
+        tracked.usage = Some(usage);
+    }
+
+    pub fn register_buffer(&mut self, buffer: vk::Buffer) {
+        self.buffers.insert(buffer, TrackedBuffer { usage: None });
+    }
+
+    pub fn forget_buffer(&mut self, buffer: vk::Buffer) {
+        self.buffers.remove(&buffer);
+    }
+
+    // Queues a barrier before `buffer` is used as `usage`.  Only hazards involving a write need
+    // one; consecutive reads are left unsynchronized.
+    pub fn buffer_access(&mut self, buffer: vk::Buffer, usage: BufferUsage) {
+        let tracked = self
+            .buffers
+            .get_mut(&buffer)
+            .unwrap_or_else(|| panic!("Buffer {:?} is not tracked.", buffer));
+        if let Some(previous) = tracked.usage {
+            if previous.is_write() || usage.is_write() {
+                let (src_access, src_stage) = previous.access_and_stage();
+                let (dst_access, dst_stage) = usage.access_and_stage();
+                self.pending_buffer_barriers.push(
+                    vk::BufferMemoryBarrier2::default()
+                        .buffer(buffer)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                        .src_stage_mask(src_stage)
+                        .src_access_mask(src_access)
+                        .dst_stage_mask(dst_stage)
+                        .dst_access_mask(dst_access)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED),
+                );
+            }
+        }
+        tracked.usage = Some(usage);
+    }
+
+    // Records every queued barrier as a single `vkCmdPipelineBarrier2`.
+    pub fn flush(
+        &mut self,
+        synchronization2: &ash::khr::synchronization2::Device,
+        commandbuffer: vk::CommandBuffer,
+    ) {
+        if self.pending_barriers.is_empty() && self.pending_buffer_barriers.is_empty() {
+            return;
+        }
+        let dependency_info = vk::DependencyInfo::default()
+            .image_memory_barriers(&self.pending_barriers)
+            .buffer_memory_barriers(&self.pending_buffer_barriers);
+        unsafe { synchronization2.cmd_pipeline_barrier2(commandbuffer, &dependency_info) };
+        self.pending_barriers.clear();
+        self.pending_buffer_barriers.clear();
+    }
+}
+
+pub fn full_subresource_range(aspect_mask: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange::default()
+        .aspect_mask(aspect_mask)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(0)
+        .layer_count(vk::REMAINING_ARRAY_LAYERS)
+}
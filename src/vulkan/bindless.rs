@@ -0,0 +1,350 @@
+// Bindless resources.  With `Options::bindless`, one large descriptor set holds every texture and
+// storage buffer added to it, and shaders pick them by integer handle instead of being handed a
+// set per draw.  Set 0 of a bindless mesh pipeline is the table:
+//
+//     #extension GL_EXT_nonuniform_qualifier : require
+//     layout (set = 0, binding = 0) uniform sampler2D textures[];
+//     layout (set = 0, binding = 1) readonly buffer Buffers { ... } buffers[];
+//
+// Cubemaps can be sampled by declaring `samplerCube` at the same binding.  Indices that vary within
+// a draw need `nonuniformEXT`.  The arrays are partially bound and updated after binding, so
+// adding a resource never disturbs frames in flight, and a released handle's slot is only reused
+// once the frames that might have read it have finished.
+
+use anyhow::anyhow;
+use ash::vk;
+use std::collections::VecDeque;
+
+use super::buffer::Buffer;
+use super::mesh::MeshPipeline;
+use super::texture::Texture;
+use super::Vulkan;
+
+// Upper bounds on the table's size; devices with lower limits get smaller tables.
+const MAX_TEXTURES: u32 = 16384;
+const MAX_BUFFERS: u32 = 4096;
+
+const TEXTURE_BINDING: u32 = 0;
+const BUFFER_BINDING: u32 = 1;
+
+// An index into `textures[]`.  Stays the same until released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BindlessTexture(pub u32);
+
+// An index into `buffers[]`.  Stays the same until released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BindlessBuffer(pub u32);
+
+// The slots of one array.  Released slots wait for the frame they were released in to finish.
+struct Slots {
+    capacity: u32,
+    // Slots from here on have never been handed out.
+    next: u32,
+    free: Vec<u32>,
+    released: VecDeque<(u64, u32)>,
+}
+
+impl Slots {
+    fn new(capacity: u32) -> Self {
+        Self {
+            capacity,
+            next: 0,
+            free: Vec::new(),
+            released: VecDeque::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u32> {
+        self.free.pop().or_else(|| {
+            (self.next < self.capacity).then(|| {
+                self.next += 1;
+                self.next - 1
+            })
+        })
+    }
+
+    fn release(&mut self, frame: u64, slot: u32) {
+        self.released.push_back((frame, slot));
+    }
+
+    fn collect(&mut self, completed_frame: u64) {
+        while let Some((frame, slot)) = self.released.front() {
+            if *frame > completed_frame {
+                break;
+            }
+            self.free.push(*slot);
+            self.released.pop_front();
+        }
+    }
+}
+
+pub struct BindlessTable {
+    pub set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    textures: Slots,
+    buffers: Slots,
+}
+
+impl BindlessTable {
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &ash::Device,
+    ) -> Result<Self, anyhow::Error> {
+        let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut properties =
+            vk::PhysicalDeviceProperties2::default().push_next(&mut indexing_properties);
+        unsafe { instance.get_physical_device_properties2(*physical_device, &mut properties) };
+        // Combined image samplers count as both samplers and sampled images.
+        let texture_count = MAX_TEXTURES
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_samplers)
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_sampled_images)
+            .min(indexing_properties.max_descriptor_set_update_after_bind_samplers)
+            .min(indexing_properties.max_descriptor_set_update_after_bind_sampled_images);
+        let buffer_count = MAX_BUFFERS
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_storage_buffers)
+            .min(indexing_properties.max_descriptor_set_update_after_bind_storage_buffers)
+            .min(
+                indexing_properties
+                    .max_per_stage_update_after_bind_resources
+                    .saturating_sub(texture_count),
+            );
+        if texture_count == 0 || buffer_count == 0 {
+            return Err(anyhow!(
+                "The device's descriptor limits are too low for bindless."
+            ));
+        }
+
+        let stages = vk::ShaderStageFlags::VERTEX
+            | vk::ShaderStageFlags::FRAGMENT
+            | vk::ShaderStageFlags::COMPUTE;
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(TEXTURE_BINDING)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(texture_count)
+                .stage_flags(stages),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(BUFFER_BINDING)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(buffer_count)
+                .stage_flags(stages),
+        ];
+        let binding_flags = [vk::DescriptorBindingFlags::PARTIALLY_BOUND
+            | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+            | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING;
+            2];
+        let mut binding_flags_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::default().binding_flags(&binding_flags);
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_info);
+        let set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None)? };
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(texture_count),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(buffer_count),
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let pool = match unsafe { logical_device.create_descriptor_pool(&pool_info, None) } {
+            Ok(pool) => pool,
+            Err(err) => {
+                unsafe { logical_device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(err.into());
+            }
+        };
+        let set_layouts = [set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&set_layouts);
+        let set = match unsafe { logical_device.allocate_descriptor_sets(&allocate_info) } {
+            Ok(sets) => sets[0],
+            Err(err) => {
+                unsafe {
+                    logical_device.destroy_descriptor_pool(pool, None);
+                    logical_device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                return Err(err.into());
+            }
+        };
+        tracing::info!(
+            "Bindless table: {} textures, {} buffers.",
+            texture_count,
+            buffer_count
+        );
+
+        Ok(Self {
+            set_layout,
+            pool,
+            set,
+            textures: Slots::new(texture_count),
+            buffers: Slots::new(buffer_count),
+        })
+    }
+
+    pub fn collect(&mut self, completed_frame: u64) {
+        self.textures.collect(completed_frame);
+        self.buffers.collect(completed_frame);
+    }
+
+    // Only safe once the device is idle.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe {
+            logical_device.destroy_descriptor_pool(self.pool, None);
+            logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+impl Vulkan {
+    // Whether the bindless table exists: `Options::bindless` was set and the device supports it.
+    pub fn is_bindless(&self) -> bool {
+        self.bindless.is_some()
+    }
+
+    fn bindless_table(&mut self) -> Result<&mut BindlessTable, anyhow::Error> {
+        self.bindless
+            .as_mut()
+            .ok_or_else(|| anyhow!("Bindless resources are not enabled."))
+    }
+
+    // Makes `texture`, sampled with `sampler`, readable by shaders as `textures[handle]` from the
+    // next frame on.  The texture must outlive the handle.
+    pub fn add_bindless_texture(
+        &mut self,
+        texture: &Texture,
+        sampler: vk::Sampler,
+    ) -> Result<BindlessTexture, anyhow::Error> {
+        let table = self.bindless_table()?;
+        let slot = table
+            .textures
+            .allocate()
+            .ok_or_else(|| anyhow!("The bindless table has no free texture slots."))?;
+        let set = table.set;
+        let image_info = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)
+            .image_view(texture.view())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(set)
+            .dst_binding(TEXTURE_BINDING)
+            .dst_array_element(slot)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info);
+        unsafe { self.logical_device.update_descriptor_sets(&[write], &[]) };
+        Ok(BindlessTexture(slot))
+    }
+
+    // Makes all of `buffer`, which needs STORAGE_BUFFER usage, readable and writable by shaders as
+    // `buffers[handle]` from the next frame on.  The buffer must outlive the handle.
+    pub fn add_bindless_buffer(
+        &mut self,
+        buffer: &Buffer,
+    ) -> Result<BindlessBuffer, anyhow::Error> {
+        let table = self.bindless_table()?;
+        let slot = table
+            .buffers
+            .allocate()
+            .ok_or_else(|| anyhow!("The bindless table has no free buffer slots."))?;
+        let set = table.set;
+        let buffer_info = [vk::DescriptorBufferInfo::default()
+            .buffer(buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(set)
+            .dst_binding(BUFFER_BINDING)
+            .dst_array_element(slot)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&buffer_info);
+        unsafe { self.logical_device.update_descriptor_sets(&[write], &[]) };
+        Ok(BindlessBuffer(slot))
+    }
+
+    // The handle may be handed out again once the frames that might use it have finished.  Shaders
+    // must not read it after this frame.
+    pub fn release_bindless_texture(&mut self, handle: BindlessTexture) {
+        let frame = self.frame_timeline.last_value() + 1;
+        if let Some(table) = &mut self.bindless {
+            table.textures.release(frame, handle.0);
+        }
+    }
+
+    pub fn release_bindless_buffer(&mut self, handle: BindlessBuffer) {
+        let frame = self.frame_timeline.last_value() + 1;
+        if let Some(table) = &mut self.bindless {
+            table.buffers.release(frame, handle.0);
+        }
+    }
+
+    // Binds the table as set 0 of a pipeline created with `MeshPipelineDesc::bindless`.
+    pub fn bind_bindless_table(&self, commandbuffer: vk::CommandBuffer, pipeline: &MeshPipeline) {
+        let Some(table) = &self.bindless else {
+            return;
+        };
+        unsafe {
+            self.logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[table.set],
+                &[],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_slots_are_reused_once_their_frame_completes() {
+        let mut slots = Slots::new(8);
+        let first = slots.allocate().unwrap();
+        slots.allocate().unwrap();
+        slots.release(5, first);
+        slots.collect(4);
+        assert_eq!(slots.allocate(), Some(2));
+        slots.collect(5);
+        assert_eq!(slots.allocate(), Some(first));
+    }
+
+    #[test]
+    fn slots_released_in_later_frames_keep_waiting() {
+        let mut slots = Slots::new(8);
+        let first = slots.allocate().unwrap();
+        let second = slots.allocate().unwrap();
+        slots.release(3, first);
+        slots.release(4, second);
+        slots.collect(3);
+        assert_eq!(slots.allocate(), Some(first));
+        assert_eq!(slots.allocate(), Some(2));
+        slots.collect(4);
+        assert_eq!(slots.allocate(), Some(second));
+    }
+
+    #[test]
+    fn a_full_table_only_frees_up_after_collect() {
+        let mut slots = Slots::new(2);
+        assert_eq!(slots.allocate(), Some(0));
+        assert_eq!(slots.allocate(), Some(1));
+        assert_eq!(slots.allocate(), None);
+        slots.release(7, 1);
+        assert_eq!(slots.allocate(), None);
+        slots.collect(7);
+        assert_eq!(slots.allocate(), Some(1));
+        assert_eq!(slots.allocate(), None);
+    }
+}
@@ -0,0 +1,105 @@
+use ash::vk;
+
+use super::allocator::{Allocation, Allocator, ResourceKind};
+
+// A buffer placed in memory from the allocator.
+pub struct Buffer {
+    pub buffer: vk::Buffer,
+    pub allocation: Allocation,
+    pub size: vk::DeviceSize,
+    pub usage: vk::BufferUsageFlags,
+}
+
+impl Buffer {
+    pub fn new(
+        allocator: &Allocator,
+        logical_device: &ash::Device,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<Self, anyhow::Error> {
+        let buffer_create_info = vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = unsafe { logical_device.create_buffer(&buffer_create_info, None)? };
+
+        let memory_requirements = unsafe { logical_device.get_buffer_memory_requirements(buffer) };
+        let mut allocation = match allocator.allocate(
+            logical_device,
+            memory_requirements,
+            properties,
+            ResourceKind::Buffer,
+        ) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { logical_device.destroy_buffer(buffer, None) };
+                return Err(err);
+            }
+        };
+        if let Err(err) = unsafe {
+            logical_device.bind_buffer_memory(buffer, allocation.memory, allocation.offset)
+        } {
+            unsafe { logical_device.destroy_buffer(buffer, None) };
+            allocation.free(logical_device);
+            return Err(err.into());
+        }
+
+        Ok(Self {
+            buffer,
+            allocation,
+            size,
+            usage,
+        })
+    }
+
+    fn mapped(&self) -> *mut u8 {
+        self.allocation
+            .mapped()
+            .expect("The buffer's memory is not host visible.")
+    }
+
+    // The buffer must have been created HOST_VISIBLE | HOST_COHERENT.
+    pub fn write(&self, offset: vk::DeviceSize, data: &[u8]) {
+        assert!(
+            offset + data.len() as vk::DeviceSize <= self.size,
+            "Write of {} bytes at {} overruns a buffer of {} bytes.",
+            data.len(),
+            offset,
+            self.size
+        );
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.mapped().add(offset as usize),
+                data.len(),
+            );
+        }
+    }
+
+    // The buffer must have been created HOST_VISIBLE | HOST_COHERENT, and any device writes made
+    // visible to the host.
+    pub fn read(&self, offset: vk::DeviceSize, len: usize) -> Vec<u8> {
+        assert!(
+            offset + len as vk::DeviceSize <= self.size,
+            "Read of {} bytes at {} overruns a buffer of {} bytes.",
+            len,
+            offset,
+            self.size
+        );
+        let mut data = vec![0u8; len];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.mapped().add(offset as usize),
+                data.as_mut_ptr(),
+                len,
+            );
+        }
+        data
+    }
+
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe { logical_device.destroy_buffer(self.buffer, None) };
+        self.allocation.free(logical_device);
+    }
+}
@@ -0,0 +1,203 @@
+// Read-back of rendered frames.  A capture records a copy of the swapchain image into a
+// host-visible buffer at the end of a frame, after the render graph has finished with it, then
+// waits for that frame and converts the pixels to RGBA.
+
+use anyhow::anyhow;
+use ash::vk;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use super::barriers::{ImageUsage, ResourceStateTracker};
+use super::buffer::Buffer;
+use super::Vulkan;
+
+// A frame can be skipped when the swapchain turns out to be out of date; give up after this many.
+const MAX_CAPTURE_ATTEMPTS: usize = 3;
+
+pub struct PendingCapture {
+    pub buffer: Buffer,
+    // Set once a frame with the copy recorded has been submitted.
+    pub frame: Option<u64>,
+}
+
+// A captured frame as 8-bit RGBA, rows top to bottom.
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+    // Whether the pixels are sRGB encoded, as they are whenever the surface presents in the sRGB
+    // color space, whatever the swapchain format.
+    pub srgb: bool,
+}
+
+impl Screenshot {
+    pub fn save_png(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        if self.srgb {
+            encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+        }
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+// Whether each pixel is stored blue first, for the swapchain formats a screenshot can be taken of.
+fn is_bgra(format: vk::Format) -> Option<bool> {
+    match format {
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Some(true),
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::A8B8G8R8_UNORM_PACK32
+        | vk::Format::A8B8G8R8_SRGB_PACK32 => Some(false),
+        _ => None,
+    }
+}
+
+// The swapchain can only be read back when the surface allows TRANSFER_SRC usage.
+pub fn swapchain_usage(surface_capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::ImageUsageFlags {
+    let optional_usage =
+        surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC;
+    vk::ImageUsageFlags::COLOR_ATTACHMENT | optional_usage
+}
+
+impl Vulkan {
+    // Renders a frame and returns what was presented.  Blocks until the GPU has finished it.
+    pub fn capture_frame(&mut self) -> Result<Screenshot, anyhow::Error> {
+        // Headless targets are read back as they would be shown in an sRGB window.
+        let mut color_space = vk::ColorSpaceKHR::SRGB_NONLINEAR;
+        if let Some(presentation) = &self.presentation {
+            let surface_capabilities = unsafe {
+                presentation
+                    .surface_instance
+                    .get_physical_device_surface_capabilities(
+                        self.physical_device,
+                        presentation.surface,
+                    )
+            }?;
+            if !swapchain_usage(&surface_capabilities).contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+                return Err(anyhow!(
+                    "The surface doesn't allow swapchain images to be read back."
+                ));
+            }
+            color_space = Self::get_surface_format(
+                &presentation.surface_instance,
+                &presentation.surface,
+                &self.physical_device,
+            )?
+            .color_space;
+        }
+        let format = self.attachment_formats.color;
+        let is_bgra = is_bgra(format)
+            .ok_or_else(|| anyhow!("Can't capture frames in swapchain format {:?}.", format))?;
+
+        for _ in 0..MAX_CAPTURE_ATTEMPTS {
+            // Resizes only happen before recording or after submitting, so the extent a frame is
+            // recorded at is the one when it begins.
+            let extent = self.extent;
+            let size = extent.width as vk::DeviceSize * extent.height as vk::DeviceSize * 4;
+            let buffer = Buffer::new(
+                &self.allocator,
+                &self.logical_device,
+                size,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            self.capture = Some(PendingCapture {
+                buffer,
+                frame: None,
+            });
+            let rendered = self.render();
+            let mut capture = self.capture.take().expect("Capture taken during render.");
+            let result = rendered.and_then(|()| match capture.frame {
+                Some(frame) => {
+                    self.frame_timeline
+                        .wait(&self.logical_device, frame, u64::MAX)?;
+                    Ok(Some(capture.buffer.read(0, size as usize)))
+                }
+                None => Ok(None),
+            });
+            if capture.frame.is_some() {
+                let mut buffer = capture.buffer;
+                self.retire(move |logical_device| buffer.destroy(logical_device));
+            } else {
+                capture.buffer.destroy(&self.logical_device);
+            }
+            let Some(mut pixels) = result? else {
+                continue;
+            };
+            for pixel in pixels.chunks_exact_mut(4) {
+                if is_bgra {
+                    pixel.swap(0, 2);
+                }
+                // The surface is composited opaquely, so whatever alpha was rendered is ignored.
+                pixel[3] = u8::MAX;
+            }
+            return Ok(Screenshot {
+                width: extent.width,
+                height: extent.height,
+                pixels,
+                srgb: color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            });
+        }
+        Err(anyhow!(
+            "No frame could be captured in {} attempts.",
+            MAX_CAPTURE_ATTEMPTS
+        ))
+    }
+
+    // Records the copy for a pending capture, leaving the swapchain image as the frame would have.
+    pub(super) fn record_capture(
+        &self,
+        resource_states: &mut ResourceStateTracker,
+        commandbuffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        let Some(capture) = &self.capture else {
+            return;
+        };
+        let image = self.swapchain_images[image_index];
+        resource_states.transition(image, ImageUsage::TransferSrc);
+        resource_states.flush(&self.synchronization2, commandbuffer);
+        let region = vk::BufferImageCopy::default()
+            .image_subresource(
+                vk::ImageSubresourceLayers::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .layer_count(1),
+            )
+            .image_extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            });
+        let host_read = [vk::BufferMemoryBarrier2::default()
+            .buffer(capture.buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::HOST)
+            .dst_access_mask(vk::AccessFlags2::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)];
+        let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(&host_read);
+        unsafe {
+            self.logical_device.cmd_copy_image_to_buffer(
+                commandbuffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                capture.buffer.buffer,
+                &[region],
+            );
+            self.synchronization2
+                .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+        }
+        resource_states.transition(image, Self::target_usage(self.presentation.is_some()));
+        resource_states.flush(&self.synchronization2, commandbuffer);
+    }
+}
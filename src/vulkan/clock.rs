@@ -0,0 +1,59 @@
+// The time frames are rendered at.  In real time it follows the wall clock; with a fixed
+// timestep every frame advances it by the same amount however long rendering takes, so offline
+// renders come out the same every run.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    RealTime,
+    Fixed(Duration),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameTime {
+    // The frame number, as returned by `Vulkan::frame_number` once it has been submitted.
+    pub frame: u64,
+    // Time since the first frame rendered with this clock.
+    pub time: Duration,
+    // Time since the previous frame; zero for the first.
+    pub delta: Duration,
+}
+
+pub struct FrameClock {
+    clock: Clock,
+    // When the first frame was rendered, for real time.
+    started: Option<Instant>,
+    frame_time: Option<FrameTime>,
+}
+
+impl FrameClock {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            started: None,
+            frame_time: None,
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    // Advances to `frame`, which is about to be recorded.
+    pub fn tick(&mut self, frame: u64) {
+        let time = match (self.clock, self.frame_time) {
+            (Clock::RealTime, _) => self.started.get_or_insert_with(Instant::now).elapsed(),
+            (Clock::Fixed(_), None) => Duration::ZERO,
+            (Clock::Fixed(timestep), Some(previous)) => previous.time + timestep,
+        };
+        let delta = self.frame_time.map_or(Duration::ZERO, |previous| {
+            time.saturating_sub(previous.time)
+        });
+        self.frame_time = Some(FrameTime { frame, time, delta });
+    }
+
+    pub fn frame_time(&self) -> FrameTime {
+        self.frame_time.unwrap_or_default()
+    }
+}
@@ -0,0 +1,401 @@
+// Compute: pipelines built from a compute shader, sets of storage buffers and storage images for
+// them, and dispatches.  Dispatches are recorded from render graph passes added with
+// `Vulkan::set_passes`, outside the scene pass.  A pass declares the buffers and images it writes
+// (`BufferUsage::StorageWrite`, `ImageUsage::Storage`), and the scene declares what it reads with
+// `RenderGraph::extend_pass("scene")`, so the graph orders the two and places the barriers
+// between them; simulation results can then be drawn in the same frame.
+
+use anyhow::anyhow;
+use ash::vk;
+
+use super::buffer::Buffer;
+use super::Vulkan;
+use crate::shader::{ShaderSource, ShaderStage};
+
+const SETS_PER_POOL: u32 = 64;
+const DESCRIPTORS_PER_POOL: u32 = SETS_PER_POOL * 4;
+
+pub struct ComputePipelineDesc<'a> {
+    pub shader: ShaderSource<'a>,
+    // Storage buffers at bindings 0 to N-1 of set 0, followed by storage images, in the order
+    // `create_compute_set` is given them.
+    pub storage_buffers: u32,
+    pub storage_images: u32,
+    // Bytes of push constants, set with `Vulkan::push_compute_constants`.
+    pub push_constants: u32,
+}
+
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    // Sets for the pipeline are created with `Vulkan::create_compute_set`.  Null when the pipeline
+    // has neither storage buffers nor images.
+    pub set_layout: vk::DescriptorSetLayout,
+    pub storage_buffers: u32,
+    pub storage_images: u32,
+    pub push_constants: u32,
+}
+
+pub struct ComputeSet {
+    pub set: vk::DescriptorSet,
+    // The pool the set was allocated from, which it is returned to.
+    pool: vk::DescriptorPool,
+}
+
+// Pools of storage buffer and storage image descriptor sets, added as earlier ones fill up.
+#[derive(Default)]
+pub struct ComputeSetPools {
+    pools: Vec<vk::DescriptorPool>,
+}
+
+impl ComputeSetPools {
+    fn create_pool(logical_device: &ash::Device) -> Result<vk::DescriptorPool, vk::Result> {
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(DESCRIPTORS_PER_POOL),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(DESCRIPTORS_PER_POOL),
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .max_sets(SETS_PER_POOL)
+            .pool_sizes(&pool_sizes);
+        unsafe { logical_device.create_descriptor_pool(&pool_create_info, None) }
+    }
+
+    fn allocate(
+        &mut self,
+        logical_device: &ash::Device,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<ComputeSet, anyhow::Error> {
+        let layouts = [layout];
+        if let Some(pool) = self.pools.last() {
+            let allocate_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(*pool)
+                .set_layouts(&layouts);
+            match unsafe { logical_device.allocate_descriptor_sets(&allocate_info) } {
+                Ok(sets) => {
+                    return Ok(ComputeSet {
+                        set: sets[0],
+                        pool: *pool,
+                    })
+                }
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let pool = Self::create_pool(logical_device)?;
+        self.pools.push(pool);
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info)? };
+        Ok(ComputeSet { set: sets[0], pool })
+    }
+
+    // Only safe once the device is idle.  Destroying the pools frees every set.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        for pool in self.pools.drain(..) {
+            unsafe { logical_device.destroy_descriptor_pool(pool, None) };
+        }
+    }
+}
+
+impl Vulkan {
+    pub fn create_compute_pipeline(
+        &self,
+        desc: &ComputePipelineDesc,
+    ) -> Result<ComputePipeline, anyhow::Error> {
+        let queue_family_properties = unsafe {
+            self.instance
+                .get_physical_device_queue_family_properties(self.physical_device)
+        };
+        if !queue_family_properties[self.queue_family_indices.graphics as usize]
+            .queue_flags
+            .contains(vk::QueueFlags::COMPUTE)
+        {
+            return Err(anyhow!(
+                "Compute pipelines need compute on the graphics queue."
+            ));
+        }
+        let max_push_constants_size = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+        }
+        .limits
+        .max_push_constants_size;
+        if desc.push_constants > max_push_constants_size {
+            return Err(anyhow!(
+                "Compute pipelines can have at most {} bytes of push constants.",
+                max_push_constants_size
+            ));
+        }
+
+        let shader_code = desc.shader.spirv(ShaderStage::Compute)?;
+        let shader_module = unsafe {
+            self.logical_device.create_shader_module(
+                &vk::ShaderModuleCreateInfo::default().code(&shader_code),
+                None,
+            )?
+        };
+        let result = self
+            .create_compute_pipeline_layout(desc)
+            .and_then(|(set_layout, layout)| {
+                let stage = vk::PipelineShaderStageCreateInfo::default()
+                    .stage(vk::ShaderStageFlags::COMPUTE)
+                    .module(shader_module)
+                    .name(c"main");
+                let pipeline_info = vk::ComputePipelineCreateInfo::default()
+                    .stage(stage)
+                    .layout(layout);
+                match unsafe {
+                    self.logical_device.create_compute_pipelines(
+                        vk::PipelineCache::null(),
+                        &[pipeline_info],
+                        None,
+                    )
+                } {
+                    Ok(pipelines) => Ok(ComputePipeline {
+                        pipeline: pipelines[0],
+                        layout,
+                        set_layout,
+                        storage_buffers: desc.storage_buffers,
+                        storage_images: desc.storage_images,
+                        push_constants: desc.push_constants,
+                    }),
+                    Err((_, err)) => {
+                        unsafe {
+                            self.logical_device.destroy_pipeline_layout(layout, None);
+                            self.logical_device
+                                .destroy_descriptor_set_layout(set_layout, None);
+                        }
+                        Err(anyhow!("A problem with the pipeline creation: {}", err))
+                    }
+                }
+            });
+        // The module is only needed while the pipeline is created.
+        unsafe {
+            self.logical_device
+                .destroy_shader_module(shader_module, None)
+        };
+        result
+    }
+
+    fn create_compute_pipeline_layout(
+        &self,
+        desc: &ComputePipelineDesc,
+    ) -> Result<(vk::DescriptorSetLayout, vk::PipelineLayout), anyhow::Error> {
+        let push_constant_ranges: Vec<_> = (desc.push_constants > 0)
+            .then(|| {
+                vk::PushConstantRange::default()
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .size(desc.push_constants)
+            })
+            .into_iter()
+            .collect();
+        let bindings: Vec<_> = (0..desc.storage_buffers + desc.storage_images)
+            .map(|binding| {
+                let descriptor_type = if binding < desc.storage_buffers {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::STORAGE_IMAGE
+                };
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(binding)
+                    .descriptor_type(descriptor_type)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            })
+            .collect();
+        let set_layout = if bindings.is_empty() {
+            vk::DescriptorSetLayout::null()
+        } else {
+            let set_layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
+            unsafe {
+                self.logical_device
+                    .create_descriptor_set_layout(&set_layout_info, None)?
+            }
+        };
+        let set_layouts: Vec<_> = (set_layout != vk::DescriptorSetLayout::null())
+            .then_some(set_layout)
+            .into_iter()
+            .collect();
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        match unsafe {
+            self.logical_device
+                .create_pipeline_layout(&layout_info, None)
+        } {
+            Ok(layout) => Ok((set_layout, layout)),
+            Err(err) => {
+                unsafe {
+                    self.logical_device
+                        .destroy_descriptor_set_layout(set_layout, None)
+                };
+                Err(err.into())
+            }
+        }
+    }
+
+    // The pipeline is destroyed once the frames that might use it have finished.
+    pub fn destroy_compute_pipeline(&mut self, pipeline: ComputePipeline) {
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_pipeline(pipeline.pipeline, None);
+            logical_device.destroy_pipeline_layout(pipeline.layout, None);
+            logical_device.destroy_descriptor_set_layout(pipeline.set_layout, None);
+        });
+    }
+
+    // A set for `pipeline` with each buffer, which needs STORAGE_BUFFER usage, and then each image
+    // view, whose image needs STORAGE usage, in binding order.  The images are accessed in the
+    // GENERAL layout, which `ImageUsage::Storage` moves them to.
+    pub fn create_compute_set(
+        &mut self,
+        pipeline: &ComputePipeline,
+        buffers: &[&Buffer],
+        images: &[vk::ImageView],
+    ) -> Result<ComputeSet, anyhow::Error> {
+        if buffers.len() != pipeline.storage_buffers as usize
+            || images.len() != pipeline.storage_images as usize
+        {
+            return Err(anyhow!(
+                "The pipeline uses {} storage buffers and {} storage images, not {} and {}.",
+                pipeline.storage_buffers,
+                pipeline.storage_images,
+                buffers.len(),
+                images.len()
+            ));
+        }
+        if pipeline.set_layout == vk::DescriptorSetLayout::null() {
+            return Err(anyhow!("The pipeline has no storage buffers or images."));
+        }
+        let compute_set = self
+            .compute_set_pools
+            .allocate(&self.logical_device, pipeline.set_layout)?;
+        let buffer_infos: Vec<_> = buffers
+            .iter()
+            .map(|buffer| {
+                [vk::DescriptorBufferInfo::default()
+                    .buffer(buffer.buffer)
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)]
+            })
+            .collect();
+        let image_infos: Vec<_> = images
+            .iter()
+            .map(|view| {
+                [vk::DescriptorImageInfo::default()
+                    .image_view(*view)
+                    .image_layout(vk::ImageLayout::GENERAL)]
+            })
+            .collect();
+        let buffer_writes = buffer_infos
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer_info)| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(compute_set.set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(buffer_info)
+            });
+        let image_writes = image_infos.iter().enumerate().map(|(binding, image_info)| {
+            vk::WriteDescriptorSet::default()
+                .dst_set(compute_set.set)
+                .dst_binding(pipeline.storage_buffers + binding as u32)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(image_info)
+        });
+        let writes: Vec<_> = buffer_writes.chain(image_writes).collect();
+        unsafe { self.logical_device.update_descriptor_sets(&writes, &[]) };
+        Ok(compute_set)
+    }
+
+    // The set is freed once the frames that might use it have finished.
+    pub fn destroy_compute_set(&mut self, compute_set: ComputeSet) {
+        self.retire(move |logical_device| unsafe {
+            logical_device
+                .free_descriptor_sets(compute_set.pool, &[compute_set.set])
+                .expect("Failed to free a compute set.");
+        });
+    }
+
+    pub fn bind_compute_pipeline(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+    ) {
+        unsafe {
+            self.logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.pipeline,
+            );
+        }
+    }
+
+    pub fn bind_compute_set(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        compute_set: &ComputeSet,
+    ) {
+        unsafe {
+            self.logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.layout,
+                0,
+                &[compute_set.set],
+                &[],
+            );
+        }
+    }
+
+    // `data` must fit the pipeline's `push_constants`.
+    pub fn push_compute_constants(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        data: &[u8],
+    ) {
+        assert!(
+            data.len() <= pipeline.push_constants as usize,
+            "Push constants larger than the pipeline's range."
+        );
+        unsafe {
+            self.logical_device.cmd_push_constants(
+                commandbuffer,
+                pipeline.layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                data,
+            );
+        }
+    }
+
+    // Runs the bound pipeline over `group_counts` workgroups in x, y and z.
+    pub fn dispatch(&self, commandbuffer: vk::CommandBuffer, group_counts: [u32; 3]) {
+        let [x, y, z] = group_counts;
+        unsafe { self.logical_device.cmd_dispatch(commandbuffer, x, y, z) };
+    }
+
+    // As `dispatch`, with the workgroup counts read from a `vk::DispatchIndirectCommand` at
+    // `offset` in `buffer`, which needs INDIRECT_BUFFER usage.  A pass filling it on the GPU
+    // declares it as `BufferUsage::StorageWrite` and the dispatching pass as
+    // `BufferUsage::Indirect`.
+    pub fn dispatch_indirect(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        unsafe {
+            self.logical_device
+                .cmd_dispatch_indirect(commandbuffer, buffer.buffer, offset)
+        };
+    }
+}
@@ -0,0 +1,423 @@
+// Cubemaps: textures of six square faces in the order +X, -X, +Y, -Y, +Z, -Z, sampled by
+// direction.  They are uploaded from six images like any other texture, or converted from an
+// equirectangular (latitude-longitude) image by a compute shader at the start of the next frame,
+// on the graphics queue, before any mip chain is generated from the result.
+
+use anyhow::anyhow;
+use ash::vk;
+use std::path::Path;
+
+use super::barriers::{self, ImageUsage};
+use super::image::Image;
+use super::mipmap::{self, MipChain};
+use super::sampler::SamplerDesc;
+use super::texture::{Mipmaps, Texture};
+use super::Vulkan;
+use crate::image_file::{self, ColorSpace, DecodedImage};
+use crate::shader;
+
+// Converted cubemaps are written by the compute shader, so they need a format it can store.
+const CONVERTED_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Matches the local size in equirect_to_cube.comp.
+const WORKGROUP_SIZE: u32 = 8;
+
+// A conversion for the next frame to record.  The pipeline and descriptor set are retired as soon
+// as they are created, so they outlive that frame and no more.
+pub struct EquirectConversion {
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    set: vk::DescriptorSet,
+    image: vk::Image,
+    face_size: u32,
+    // Leaves the cubemap in TRANSFER_DST_OPTIMAL for its mip chain rather than ready to sample.
+    generate_mipmaps: bool,
+}
+
+impl Vulkan {
+    // A cubemap from six images of the same square size and format, in face order.
+    pub fn create_cubemap(
+        &mut self,
+        faces: &[DecodedImage],
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        let [first, ..] = faces else {
+            return Err(anyhow!("A cubemap needs 6 faces, not 0."));
+        };
+        if faces.len() != 6 {
+            return Err(anyhow!("A cubemap needs 6 faces, not {}.", faces.len()));
+        }
+        if faces
+            .iter()
+            .any(|face| face.extent != first.extent || face.format != first.format)
+        {
+            return Err(anyhow!(
+                "Every cubemap face must have the same size and format."
+            ));
+        }
+        let pixels: Vec<u8> = faces
+            .iter()
+            .flat_map(|face| face.pixels.iter().copied())
+            .collect();
+        self.create_layered_texture(first.extent, first.format, 6, &[&pixels], mipmaps)
+    }
+
+    // Decodes six PNG, JPEG or HDR files, in face order, into a cubemap.
+    pub fn load_cubemap(
+        &mut self,
+        paths: [&Path; 6],
+        color_space: ColorSpace,
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        let faces = paths
+            .iter()
+            .map(|path| image_file::load_image(path, color_space))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.create_cubemap(&faces, mipmaps)
+    }
+
+    // Decodes an equirectangular image, usually HDR, and converts it to a cubemap with faces of
+    // `face_size` texels.  A quarter of the image's width keeps roughly its resolution.
+    pub fn load_equirect_cubemap(
+        &mut self,
+        path: &Path,
+        face_size: u32,
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        let image = image_file::load_image(path, ColorSpace::Linear)?;
+        let equirect = self.create_texture_from_image(&image, Mipmaps::None)?;
+        let cubemap = self.create_cubemap_from_equirect(&equirect, face_size, mipmaps);
+        // Destruction waits for the frame that converts it.
+        self.destroy_texture(equirect);
+        cubemap
+    }
+
+    // A cubemap in R16G16B16A16_SFLOAT converted from `equirect` on the GPU.  Like any texture it
+    // can be sampled from the next frame rendered, and `equirect` can be destroyed straight away.
+    pub fn create_cubemap_from_equirect(
+        &mut self,
+        equirect: &Texture,
+        face_size: u32,
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        if equirect.layers != 1 {
+            return Err(anyhow!("Equirectangular images have a single layer."));
+        }
+        if face_size == 0 {
+            return Err(anyhow!("Cubemap faces need at least one texel."));
+        }
+        let queue_family_properties = unsafe {
+            self.instance
+                .get_physical_device_queue_family_properties(self.physical_device)
+        };
+        if !queue_family_properties[self.queue_family_indices.graphics as usize]
+            .queue_flags
+            .contains(vk::QueueFlags::COMPUTE)
+        {
+            return Err(anyhow!(
+                "Converting cubemaps needs compute on the graphics queue."
+            ));
+        }
+        let format_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, CONVERTED_FORMAT)
+        };
+        if !format_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::STORAGE_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE)
+        {
+            return Err(anyhow!(
+                "Cubemaps in format {:?} can't be converted on this device.",
+                CONVERTED_FORMAT
+            ));
+        }
+
+        let extent = vk::Extent2D {
+            width: face_size,
+            height: face_size,
+        };
+        let generate_mipmaps = mipmaps == Mipmaps::Generate
+            && face_size > 1
+            && self.can_generate_mipmaps(CONVERTED_FORMAT);
+        if mipmaps == Mipmaps::Generate && face_size > 1 && !generate_mipmaps {
+            tracing::warn!(
+                "Mipmaps can't be generated for {:?} cubemaps; using only the base level.",
+                CONVERTED_FORMAT
+            );
+        }
+        let mip_levels = if generate_mipmaps {
+            mipmap::full_mip_levels(extent)
+        } else {
+            1
+        };
+        let mut usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+        if generate_mipmaps {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST;
+        }
+        let mut image =
+            self.create_texture_image(extent, CONVERTED_FORMAT, 6, mip_levels, usage)?;
+
+        let conversion =
+            match self.create_equirect_conversion(&image, equirect, face_size, generate_mipmaps) {
+                Ok(conversion) => conversion,
+                Err(err) => {
+                    image.destroy(&self.logical_device);
+                    return Err(err);
+                }
+            };
+        self.transfers.push_equirect_conversion(conversion);
+        if generate_mipmaps {
+            self.transfers.push_mip_chain(MipChain {
+                image: image.image,
+                extent,
+                levels: mip_levels,
+                layers: 6,
+            });
+        }
+        self.resource_states.register(
+            image.image,
+            barriers::full_subresource_range(vk::ImageAspectFlags::COLOR),
+            ImageUsage::ShaderRead.state(),
+        );
+        Ok(Texture {
+            image,
+            format: CONVERTED_FORMAT,
+            extent,
+            mip_levels,
+            layers: 6,
+        })
+    }
+
+    // Everything created here is only needed by the frame that records the conversion, so it is
+    // retired as soon as it exists, which also cleans up after failures.
+    fn create_equirect_conversion(
+        &mut self,
+        cubemap: &Image,
+        equirect: &Texture,
+        face_size: u32,
+        generate_mipmaps: bool,
+    ) -> Result<EquirectConversion, anyhow::Error> {
+        let shader_code = shader::builtin("equirect_to_cube.comp")?;
+        let shader_module = unsafe {
+            self.logical_device.create_shader_module(
+                &vk::ShaderModuleCreateInfo::default().code(&shader_code),
+                None,
+            )?
+        };
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_shader_module(shader_module, None)
+        });
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE),
+        ];
+        let set_layout = unsafe {
+            self.logical_device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings),
+                None,
+            )?
+        };
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_descriptor_set_layout(set_layout, None)
+        });
+        let set_layouts = [set_layout];
+        let layout = unsafe {
+            self.logical_device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::default().set_layouts(&set_layouts),
+                None,
+            )?
+        };
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_pipeline_layout(layout, None)
+        });
+
+        let stage = vk::PipelineShaderStageCreateInfo::default()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader_module)
+            .name(c"main");
+        let pipeline_info = vk::ComputePipelineCreateInfo::default()
+            .stage(stage)
+            .layout(layout);
+        let pipeline = unsafe {
+            self.logical_device
+                .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+                .map_err(|(_, err)| anyhow!("A problem with the pipeline creation: {}", err))?
+        }[0];
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_pipeline(pipeline, None)
+        });
+
+        // Destroying the pool frees the set.
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1),
+        ];
+        let pool = unsafe {
+            self.logical_device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .max_sets(1)
+                    .pool_sizes(&pool_sizes),
+                None,
+            )?
+        };
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_descriptor_pool(pool, None)
+        });
+        let set = unsafe {
+            self.logical_device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(pool)
+                    .set_layouts(&set_layouts),
+            )?
+        }[0];
+
+        // The shader writes the base level of every face through an array view.
+        let storage_view = unsafe {
+            self.logical_device.create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .image(cubemap.image)
+                    .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+                    .format(CONVERTED_FORMAT)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .level_count(1)
+                            .layer_count(6),
+                    ),
+                None,
+            )?
+        };
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_image_view(storage_view, None)
+        });
+
+        // Longitude wraps around; latitude stops at the poles.  Not every format can be filtered.
+        let equirect_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, equirect.format)
+        };
+        let filter = if equirect_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+        {
+            vk::Filter::LINEAR
+        } else {
+            vk::Filter::NEAREST
+        };
+        let sampler = self.sampler(&SamplerDesc {
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            max_anisotropy: None,
+        })?;
+        let equirect_info = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)
+            .image_view(equirect.view())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let storage_info = [vk::DescriptorImageInfo::default()
+            .image_view(storage_view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+        let writes = [
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&equirect_info),
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&storage_info),
+        ];
+        unsafe { self.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(EquirectConversion {
+            pipeline,
+            layout,
+            set,
+            image: cubemap.image,
+            face_size,
+            generate_mipmaps,
+        })
+    }
+
+    pub(super) fn record_equirect_conversions(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        conversions: &[EquirectConversion],
+    ) {
+        let shader_read = ImageUsage::ShaderRead.state();
+        let transfer_dst = ImageUsage::TransferDst.state();
+        for conversion in conversions {
+            let barrier = vk::ImageMemoryBarrier2::default()
+                .image(conversion.image)
+                .subresource_range(barriers::full_subresource_range(
+                    vk::ImageAspectFlags::COLOR,
+                ))
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+            let to_general = [barrier
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)];
+            // The mip chain blits from the base level as well as writing the rest.
+            let (layout, stage, access) = if conversion.generate_mipmaps {
+                (
+                    transfer_dst.layout,
+                    transfer_dst.stage,
+                    vk::AccessFlags2::TRANSFER_READ | vk::AccessFlags2::TRANSFER_WRITE,
+                )
+            } else {
+                (shader_read.layout, shader_read.stage, shader_read.access)
+            };
+            let written = [barrier
+                .old_layout(vk::ImageLayout::GENERAL)
+                .new_layout(layout)
+                .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                .dst_stage_mask(stage)
+                .dst_access_mask(access)];
+            let groups = conversion.face_size.div_ceil(WORKGROUP_SIZE);
+            unsafe {
+                let dependency_info =
+                    vk::DependencyInfo::default().image_memory_barriers(&to_general);
+                self.synchronization2
+                    .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+                self.logical_device.cmd_bind_pipeline(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    conversion.pipeline,
+                );
+                self.logical_device.cmd_bind_descriptor_sets(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    conversion.layout,
+                    0,
+                    &[conversion.set],
+                    &[],
+                );
+                self.logical_device
+                    .cmd_dispatch(commandbuffer, groups, groups, 6);
+                let dependency_info = vk::DependencyInfo::default().image_memory_barriers(&written);
+                self.synchronization2
+                    .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+            }
+        }
+    }
+}
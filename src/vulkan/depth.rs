@@ -0,0 +1,95 @@
+use anyhow::anyhow;
+use ash::vk;
+use ash::Instance;
+
+use super::allocator::Allocator;
+use super::image::{self, Image};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthBuffer {
+    None,
+    Depth,
+    DepthStencil,
+}
+
+impl DepthBuffer {
+    // Candidates in order of preference.  Plain depth falls back to combined formats since some
+    // devices only support those as attachments.
+    fn candidate_formats(self) -> &'static [vk::Format] {
+        match self {
+            DepthBuffer::None => &[],
+            DepthBuffer::Depth => &[
+                vk::Format::D32_SFLOAT,
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::Format::D16_UNORM,
+            ],
+            DepthBuffer::DepthStencil => &[
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::Format::D16_UNORM_S8_UINT,
+            ],
+        }
+    }
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D32_SFLOAT_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+    )
+}
+
+pub fn aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    if has_stencil_component(format) {
+        vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::DEPTH
+    }
+}
+
+pub fn select_depth_format(
+    instance: &Instance,
+    physical_device: &vk::PhysicalDevice,
+    depth_buffer: DepthBuffer,
+) -> Result<Option<vk::Format>, anyhow::Error> {
+    if depth_buffer == DepthBuffer::None {
+        return Ok(None);
+    }
+    depth_buffer
+        .candidate_formats()
+        .iter()
+        .find(|format| {
+            let format_properties = unsafe {
+                instance.get_physical_device_format_properties(*physical_device, **format)
+            };
+            format_properties
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        })
+        .map(|format| Some(*format))
+        .ok_or_else(|| anyhow!("No supported format found for {:?}.", depth_buffer))
+}
+
+pub fn create_depth_image(
+    allocator: &Allocator,
+    logical_device: &ash::Device,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    samples: vk::SampleCountFlags,
+) -> Result<Image, anyhow::Error> {
+    let image_create_info = image::attachment_create_info(
+        format,
+        extent,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        samples,
+    );
+    Image::new(
+        allocator,
+        logical_device,
+        &image_create_info,
+        aspect_mask(format),
+    )
+}
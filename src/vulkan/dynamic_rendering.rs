@@ -0,0 +1,82 @@
+// Rendering without `vk::RenderPass` or `vk::Framebuffer` objects, via VK_KHR_dynamic_rendering
+// (core in Vulkan 1.3).
+
+use ash::vk;
+
+use super::{depth, AttachmentFormats, Vulkan};
+
+pub fn pipeline_rendering_create_info<'a>(
+    attachment_formats: &AttachmentFormats,
+    color_attachment_formats: &'a [vk::Format],
+) -> vk::PipelineRenderingCreateInfo<'a> {
+    let depth_format = attachment_formats.depth.unwrap_or(vk::Format::UNDEFINED);
+    let stencil_format = if depth::has_stencil_component(depth_format) {
+        depth_format
+    } else {
+        vk::Format::UNDEFINED
+    };
+    vk::PipelineRenderingCreateInfo::default()
+        .color_attachment_formats(color_attachment_formats)
+        .depth_attachment_format(depth_format)
+        .stencil_attachment_format(stencil_format)
+}
+
+impl Vulkan {
+    // Attachments must already be in their attachment layouts; the render graph sees to that.
+    pub(super) fn record_dynamic_rendering(
+        &self,
+        dynamic_rendering: &ash::khr::dynamic_rendering::Device,
+        commandbuffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        let swapchain_image_view = self.swapchain_image_views[image_index];
+
+        let clear_values = self.clear_values();
+        let mut color_attachment = vk::RenderingAttachmentInfo::default()
+            .image_view(swapchain_image_view)
+            .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(clear_values[0]);
+        if let Some(color_image) = &self.attachment_images.color {
+            color_attachment = color_attachment
+                .image_view(color_image.view)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                .resolve_image_view(swapchain_image_view)
+                .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        }
+        let color_attachments = [color_attachment];
+        let depth_attachment = self.attachment_images.depth.as_ref().map(|depth_image| {
+            vk::RenderingAttachmentInfo::default()
+                .image_view(depth_image.view)
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .clear_value(clear_values[1])
+        });
+        let mut rendering_info = vk::RenderingInfo::default()
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .layer_count(1)
+            .color_attachments(&color_attachments);
+        if let Some(depth_attachment) = &depth_attachment {
+            rendering_info = rendering_info.depth_attachment(depth_attachment);
+            if self
+                .attachment_formats
+                .depth
+                .is_some_and(depth::has_stencil_component)
+            {
+                rendering_info = rendering_info.stencil_attachment(depth_attachment);
+            }
+        }
+
+        unsafe {
+            dynamic_rendering.cmd_begin_rendering(commandbuffer, &rendering_info);
+            self.record_draws(commandbuffer);
+            dynamic_rendering.cmd_end_rendering(commandbuffer);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+// Vulkan objects owned by a host application, for rendering with cinder on a device the host
+// already uses.  cinder records and submits its own command buffers on the given queues but never
+// destroys the instance or device; the host must keep them alive until `Vulkan` is dropped.
+// Queues are externally synchronized, so the host mustn't submit to the same queues from another
+// thread while cinder renders or uploads.
+
+use ash::vk;
+use ash::{Entry, Instance};
+
+use super::features::DeviceFeatures;
+
+#[derive(Clone)]
+pub struct ExternalDevice {
+    pub entry: Entry,
+    // Needs the surface extensions for the window when cinder presents to one.
+    pub instance: Instance,
+    pub physical_device: vk::PhysicalDevice,
+    pub device: ash::Device,
+    // Must support graphics, and presenting to the window if there is one.
+    pub graphics_queue_family: u32,
+    pub graphics_queue: vk::Queue,
+    // May be the graphics family and queue.
+    pub transfer_queue_family: u32,
+    pub transfer_queue: vk::Queue,
+    // What the host enabled when creating `device`.  Synchronization2 and timeline semaphores are
+    // required, as is VK_KHR_swapchain for presenting.  `DeviceFeatures::query` and
+    // `DeviceFeatures::extension_names` say what the physical device offers.
+    pub features: DeviceFeatures,
+}
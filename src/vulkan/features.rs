@@ -0,0 +1,134 @@
+use ash::vk;
+use ash::Instance;
+use std::ffi::{c_char, CStr};
+
+// Optional device capabilities.  `query` reports what the physical device supports; `Vulkan::new`
+// then switches off anything that wasn't asked for before creating the logical device.  For an
+// `ExternalDevice` they are what the host enabled instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceFeatures {
+    // Only needed when presenting to a window.
+    pub swapchain: bool,
+    // Must be enabled whenever it is available, e.g. on MoltenVK.
+    pub portability_subset: bool,
+    pub sample_rate_shading: bool,
+    // Enabled whenever it is available; samplers without it ignore their anisotropy.
+    pub sampler_anisotropy: bool,
+    // Block-compressed texture formats, enabled whenever available.  Each format still has to be
+    // checked with `get_physical_device_format_properties` before use.
+    pub texture_compression_bc: bool,
+    pub texture_compression_etc2: bool,
+    pub texture_compression_astc_ldr: bool,
+    pub dynamic_rendering: bool,
+    // Required; every barrier cinder records is a `vkCmdPipelineBarrier2`.
+    pub synchronization2: bool,
+    // Required; frame pacing and uploads are tracked with timeline semaphores.  Only the core
+    // Vulkan 1.2 entry points are used, so devices older than 1.2 don't qualify.
+    pub timeline_semaphore: bool,
+    pub pipeline_statistics_query: bool,
+    pub occlusion_query_precise: bool,
+    pub conditional_rendering: bool,
+    // The Vulkan 1.2 descriptor indexing features bindless resources need: non-uniformly indexed,
+    // partially bound arrays of sampled images and storage buffers, updated after being bound.
+    pub descriptor_indexing: bool,
+    // Enabled whenever it is available, for the budgets in `Vulkan::memory_stats`.
+    pub memory_budget: bool,
+}
+
+impl DeviceFeatures {
+    pub fn query(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+    ) -> Result<Self, anyhow::Error> {
+        let extensions =
+            unsafe { instance.enumerate_device_extension_properties(*physical_device)? };
+        let has_extension = |name: &CStr| {
+            extensions
+                .iter()
+                .any(|extension| extension.extension_name_as_c_str() == Ok(name))
+        };
+
+        let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+        let mut synchronization2_features = vk::PhysicalDeviceSynchronization2Features::default();
+        let mut timeline_semaphore_features =
+            vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut conditional_rendering_features =
+            vk::PhysicalDeviceConditionalRenderingFeaturesEXT::default();
+        let mut descriptor_indexing_features =
+            vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut dynamic_rendering_features)
+            .push_next(&mut synchronization2_features)
+            .push_next(&mut timeline_semaphore_features)
+            .push_next(&mut conditional_rendering_features)
+            .push_next(&mut descriptor_indexing_features);
+        unsafe { instance.get_physical_device_features2(*physical_device, &mut features) };
+        let sample_rate_shading = features.features.sample_rate_shading == vk::TRUE;
+        let sampler_anisotropy = features.features.sampler_anisotropy == vk::TRUE;
+        let texture_compression_bc = features.features.texture_compression_bc == vk::TRUE;
+        let texture_compression_etc2 = features.features.texture_compression_etc2 == vk::TRUE;
+        let texture_compression_astc_ldr =
+            features.features.texture_compression_astc_ldr == vk::TRUE;
+        let pipeline_statistics_query = features.features.pipeline_statistics_query == vk::TRUE;
+        let occlusion_query_precise = features.features.occlusion_query_precise == vk::TRUE;
+        let properties = unsafe { instance.get_physical_device_properties(*physical_device) };
+        let is_vulkan_1_2 = properties.api_version >= vk::API_VERSION_1_2;
+
+        Ok(Self {
+            swapchain: has_extension(ash::khr::swapchain::NAME),
+            portability_subset: has_extension(ash::khr::portability_subset::NAME),
+            sample_rate_shading,
+            sampler_anisotropy,
+            texture_compression_bc,
+            texture_compression_etc2,
+            texture_compression_astc_ldr,
+            dynamic_rendering: has_extension(ash::khr::dynamic_rendering::NAME)
+                && dynamic_rendering_features.dynamic_rendering == vk::TRUE,
+            synchronization2: has_extension(ash::khr::synchronization2::NAME)
+                && synchronization2_features.synchronization2 == vk::TRUE,
+            timeline_semaphore: is_vulkan_1_2
+                && timeline_semaphore_features.timeline_semaphore == vk::TRUE,
+            pipeline_statistics_query,
+            occlusion_query_precise,
+            conditional_rendering: has_extension(ash::ext::conditional_rendering::NAME)
+                && conditional_rendering_features.conditional_rendering == vk::TRUE,
+            descriptor_indexing: is_vulkan_1_2
+                && [
+                    descriptor_indexing_features.shader_sampled_image_array_non_uniform_indexing,
+                    descriptor_indexing_features.shader_storage_buffer_array_non_uniform_indexing,
+                    descriptor_indexing_features.descriptor_binding_sampled_image_update_after_bind,
+                    descriptor_indexing_features
+                        .descriptor_binding_storage_buffer_update_after_bind,
+                    descriptor_indexing_features.descriptor_binding_update_unused_while_pending,
+                    descriptor_indexing_features.descriptor_binding_partially_bound,
+                    descriptor_indexing_features.runtime_descriptor_array,
+                ]
+                .iter()
+                .all(|feature| *feature == vk::TRUE),
+            memory_budget: has_extension(ash::ext::memory_budget::NAME),
+        })
+    }
+
+    pub fn extension_names(&self) -> Vec<*const c_char> {
+        let mut extension_names = Vec::new();
+        if self.swapchain {
+            extension_names.push(ash::khr::swapchain::NAME.as_ptr());
+        }
+        if self.portability_subset {
+            extension_names.push(ash::khr::portability_subset::NAME.as_ptr());
+        }
+        if self.dynamic_rendering {
+            extension_names.push(ash::khr::dynamic_rendering::NAME.as_ptr());
+        }
+        if self.synchronization2 {
+            extension_names.push(ash::khr::synchronization2::NAME.as_ptr());
+        }
+        if self.conditional_rendering {
+            extension_names.push(ash::ext::conditional_rendering::NAME.as_ptr());
+        }
+        if self.memory_budget {
+            extension_names.push(ash::ext::memory_budget::NAME.as_ptr());
+        }
+        extension_names
+    }
+}
@@ -0,0 +1,42 @@
+// Rendering without a window.  A headless target stands in for the swapchain with images of its
+// own, one per frame in flight, which frames leave ready to be copied from.
+
+use ash::vk;
+
+use super::allocator::Allocator;
+use super::image::{self, Image};
+use super::FRAMES_IN_FLIGHT;
+
+// Matches the views cinder creates for swapchain images, so headless frames look the same.
+pub const TARGET_FORMAT: vk::Format = vk::Format::B8G8R8A8_UNORM;
+
+pub fn create_target_images(
+    allocator: &Allocator,
+    logical_device: &ash::Device,
+    extent: vk::Extent2D,
+) -> Result<Vec<Image>, anyhow::Error> {
+    let image_create_info = image::attachment_create_info(
+        TARGET_FORMAT,
+        extent,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::SampleCountFlags::TYPE_1,
+    );
+    let mut images = Vec::with_capacity(FRAMES_IN_FLIGHT);
+    for _ in 0..FRAMES_IN_FLIGHT {
+        match Image::new(
+            allocator,
+            logical_device,
+            &image_create_info,
+            vk::ImageAspectFlags::COLOR,
+        ) {
+            Ok(image) => images.push(image),
+            Err(err) => {
+                for image in images.iter_mut() {
+                    image.destroy(logical_device);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(images)
+}
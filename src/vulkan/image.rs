@@ -0,0 +1,135 @@
+use anyhow::anyhow;
+use ash::vk;
+use ash::Instance;
+
+use super::allocator::{Allocation, Allocator, ResourceKind};
+
+// A device-local image placed in memory from the allocator, with a single view covering all of
+// it: a cube view for cube-compatible images, an array view for other layered ones.
+pub struct Image {
+    pub image: vk::Image,
+    pub allocation: Allocation,
+    pub view: vk::ImageView,
+}
+
+impl Image {
+    pub fn new(
+        allocator: &Allocator,
+        logical_device: &ash::Device,
+        image_create_info: &vk::ImageCreateInfo,
+        aspect_mask: vk::ImageAspectFlags,
+    ) -> Result<Self, anyhow::Error> {
+        let image = unsafe { logical_device.create_image(image_create_info, None)? };
+
+        let memory_requirements = unsafe { logical_device.get_image_memory_requirements(image) };
+        let mut allocation = match allocator.allocate(
+            logical_device,
+            memory_requirements,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ResourceKind::Image,
+        ) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { logical_device.destroy_image(image, None) };
+                return Err(err);
+            }
+        };
+        let destroy = |allocation: &mut Allocation| {
+            unsafe { logical_device.destroy_image(image, None) };
+            allocation.free(logical_device);
+        };
+        if let Err(err) =
+            unsafe { logical_device.bind_image_memory(image, allocation.memory, allocation.offset) }
+        {
+            destroy(&mut allocation);
+            return Err(err.into());
+        }
+
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(aspect_mask)
+            .base_mip_level(0)
+            .level_count(image_create_info.mip_levels)
+            .base_array_layer(0)
+            .layer_count(image_create_info.array_layers);
+        let view_type = if image_create_info
+            .flags
+            .contains(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+        {
+            vk::ImageViewType::CUBE
+        } else if image_create_info.array_layers > 1 {
+            vk::ImageViewType::TYPE_2D_ARRAY
+        } else {
+            vk::ImageViewType::TYPE_2D
+        };
+        let image_view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(view_type)
+            .format(image_create_info.format)
+            .subresource_range(subresource_range);
+        let view = match unsafe { logical_device.create_image_view(&image_view_create_info, None) }
+        {
+            Ok(view) => view,
+            Err(err) => {
+                destroy(&mut allocation);
+                return Err(err.into());
+            }
+        };
+
+        Ok(Self {
+            image,
+            allocation,
+            view,
+        })
+    }
+
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe {
+            logical_device.destroy_image_view(self.view, None);
+            logical_device.destroy_image(self.image, None);
+        }
+        self.allocation.free(logical_device);
+    }
+}
+
+// A single-level 2D image suitable for use as a framebuffer attachment.
+pub fn attachment_create_info(
+    format: vk::Format,
+    extent: vk::Extent2D,
+    usage: vk::ImageUsageFlags,
+    samples: vk::SampleCountFlags,
+) -> vk::ImageCreateInfo<'static> {
+    vk::ImageCreateInfo::default()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+}
+
+pub fn find_memory_type(
+    instance: &Instance,
+    physical_device: &vk::PhysicalDevice,
+    memory_type_bits: u32,
+    properties: vk::MemoryPropertyFlags,
+) -> Result<u32, anyhow::Error> {
+    let memory_properties =
+        unsafe { instance.get_physical_device_memory_properties(*physical_device) };
+    memory_properties
+        .memory_types_as_slice()
+        .iter()
+        .enumerate()
+        .find(|(index, memory_type)| {
+            memory_type_bits & (1 << index) != 0 && memory_type.property_flags.contains(properties)
+        })
+        .map(|(index, _)| index as u32)
+        .ok_or_else(|| anyhow!("No memory type with properties {:?} found.", properties))
+}
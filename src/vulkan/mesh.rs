@@ -0,0 +1,478 @@
+// Meshes: vertex and index buffers, pipelines reading per-vertex and per-instance attributes, and
+// the draw calls that use them.  Buffers are uploaded on the transfer queue and can be drawn from
+// the next frame rendered.  Draws are recorded from `Vulkan::set_scene_draws`, inside the scene
+// pass, which is what mesh pipelines are built for.
+
+use anyhow::anyhow;
+use ash::vk;
+
+use super::buffer::Buffer;
+use super::PipelineOptions;
+use super::Vulkan;
+use crate::shader::{ShaderSource, ShaderStage};
+
+pub enum Indices<'a> {
+    U16(&'a [u16]),
+    U32(&'a [u32]),
+}
+
+pub struct IndexBuffer {
+    // Destroy it with `Vulkan::destroy_buffer`.
+    pub buffer: Buffer,
+    pub index_type: vk::IndexType,
+    pub count: u32,
+}
+
+// One vertex buffer binding.  With `vk::VertexInputRate::INSTANCE` its attributes advance once per
+// instance instead of once per vertex.
+#[derive(Clone, Debug)]
+pub struct VertexBinding {
+    pub stride: u32,
+    pub input_rate: vk::VertexInputRate,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VertexAttribute {
+    // The `layout(location = N)` of the vertex shader input.
+    pub location: u32,
+    pub format: vk::Format,
+    // Within the binding's stride.
+    pub offset: u32,
+}
+
+pub struct MeshPipelineDesc<'a> {
+    pub vertex_shader: ShaderSource<'a>,
+    pub fragment_shader: ShaderSource<'a>,
+    // Binding N is the Nth buffer passed to `bind_vertex_buffers`.
+    pub bindings: &'a [VertexBinding],
+    pub topology: vk::PrimitiveTopology,
+    pub options: PipelineOptions,
+    // Combined image samplers at bindings 0 to N-1 of set 0, read by the fragment shader.
+    pub textures: u32,
+    // Set 0 is the bindless table instead, see `Vulkan::bind_bindless_table`.  Needs `textures`
+    // to be 0.
+    pub bindless: bool,
+    // Bytes of push constants visible to both shaders, set with `Vulkan::push_mesh_constants`.
+    pub push_constants: u32,
+}
+
+pub struct MeshPipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    // Texture sets for the pipeline are created with `Vulkan::create_texture_set`.  Null for
+    // bindless pipelines.
+    pub texture_set_layout: vk::DescriptorSetLayout,
+    pub textures: u32,
+    pub push_constants: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Draw {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    // Offsets per-instance attributes; `gl_InstanceIndex` starts here too.
+    pub first_instance: u32,
+}
+
+impl Default for Draw {
+    fn default() -> Self {
+        Self {
+            vertex_count: 0,
+            instance_count: 1,
+            first_vertex: 0,
+            first_instance: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DrawIndexed {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    // Added to every index before the vertex is fetched, so several meshes can share one buffer.
+    pub base_vertex: i32,
+    pub first_instance: u32,
+}
+
+impl DrawIndexed {
+    // Every index in `index_buffer`, drawn once.
+    pub fn all(index_buffer: &IndexBuffer) -> Self {
+        Self {
+            index_count: index_buffer.count,
+            instance_count: 1,
+            first_index: 0,
+            base_vertex: 0,
+            first_instance: 0,
+        }
+    }
+}
+
+impl Vulkan {
+    // A device-local vertex buffer holding `data`, which may be per-vertex or per-instance.
+    // Destroy it with `destroy_buffer`.
+    pub fn create_vertex_buffer(&mut self, data: &[u8]) -> Result<Buffer, anyhow::Error> {
+        self.create_mesh_buffer(data, vk::BufferUsageFlags::VERTEX_BUFFER)
+    }
+
+    pub fn create_index_buffer(&mut self, indices: Indices) -> Result<IndexBuffer, anyhow::Error> {
+        let (data, index_type, count): (Vec<u8>, _, _) = match indices {
+            Indices::U16(indices) => (
+                indices
+                    .iter()
+                    .flat_map(|index| index.to_ne_bytes())
+                    .collect(),
+                vk::IndexType::UINT16,
+                indices.len(),
+            ),
+            Indices::U32(indices) => (
+                indices
+                    .iter()
+                    .flat_map(|index| index.to_ne_bytes())
+                    .collect(),
+                vk::IndexType::UINT32,
+                indices.len(),
+            ),
+        };
+        let buffer = self.create_mesh_buffer(&data, vk::BufferUsageFlags::INDEX_BUFFER)?;
+        Ok(IndexBuffer {
+            buffer,
+            index_type,
+            count: count as u32,
+        })
+    }
+
+    fn create_mesh_buffer(
+        &mut self,
+        data: &[u8],
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Buffer, anyhow::Error> {
+        if data.is_empty() {
+            return Err(anyhow!("Mesh buffers can't be empty."));
+        }
+        // TRANSFER_SRC lets `defragment` move the buffer once it is registered.
+        let mut buffer = self.create_buffer(
+            data.len() as vk::DeviceSize,
+            usage | vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        if let Err(err) = self.upload_to_buffer(buffer.buffer, 0, data) {
+            self.resource_states.forget_buffer(buffer.buffer);
+            buffer.destroy(&self.logical_device);
+            return Err(err);
+        }
+        Ok(buffer)
+    }
+
+    pub fn create_mesh_pipeline(
+        &self,
+        desc: &MeshPipelineDesc,
+    ) -> Result<MeshPipeline, anyhow::Error> {
+        if desc.bindless && desc.textures > 0 {
+            return Err(anyhow!(
+                "Bindless mesh pipelines can't have textures of their own."
+            ));
+        }
+        let max_push_constants_size = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+        }
+        .limits
+        .max_push_constants_size;
+        if desc.push_constants > max_push_constants_size {
+            return Err(anyhow!(
+                "Mesh pipelines can have at most {} bytes of push constants.",
+                max_push_constants_size
+            ));
+        }
+        // Ignored on devices without sample rate shading, which is only reported once, when `Vulkan`
+        // is created with it in `Options::pipeline`.
+        let mut pipeline_options = desc.options;
+        if !self.device_features.sample_rate_shading {
+            pipeline_options.min_sample_shading = None;
+        }
+
+        let vertex_shader_code = desc.vertex_shader.spirv(ShaderStage::Vertex)?;
+        let fragment_shader_code = desc.fragment_shader.spirv(ShaderStage::Fragment)?;
+        let vertex_shader_module = unsafe {
+            self.logical_device.create_shader_module(
+                &vk::ShaderModuleCreateInfo::default().code(&vertex_shader_code),
+                None,
+            )?
+        };
+        // The modules are only needed while the pipeline is created.
+        let destroy_modules = |modules: &[vk::ShaderModule]| {
+            for module in modules {
+                unsafe { self.logical_device.destroy_shader_module(*module, None) };
+            }
+        };
+        let fragment_shader_module = match unsafe {
+            self.logical_device.create_shader_module(
+                &vk::ShaderModuleCreateInfo::default().code(&fragment_shader_code),
+                None,
+            )
+        } {
+            Ok(module) => module,
+            Err(err) => {
+                destroy_modules(&[vertex_shader_module]);
+                return Err(err.into());
+            }
+        };
+
+        let binding_descriptions: Vec<_> = desc
+            .bindings
+            .iter()
+            .enumerate()
+            .map(|(index, binding)| {
+                vk::VertexInputBindingDescription::default()
+                    .binding(index as u32)
+                    .stride(binding.stride)
+                    .input_rate(binding.input_rate)
+            })
+            .collect();
+        let attribute_descriptions: Vec<_> = desc
+            .bindings
+            .iter()
+            .enumerate()
+            .flat_map(|(index, binding)| {
+                binding.attributes.iter().map(move |attribute| {
+                    vk::VertexInputAttributeDescription::default()
+                        .binding(index as u32)
+                        .location(attribute.location)
+                        .format(attribute.format)
+                        .offset(attribute.offset)
+                })
+            })
+            .collect();
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&binding_descriptions)
+            .vertex_attribute_descriptions(&attribute_descriptions);
+
+        let layouts = self.create_mesh_pipeline_layout(desc);
+        let result = layouts.and_then(|(texture_set_layout, layout)| {
+            match Self::create_graphics_pipeline(
+                &self.logical_device,
+                &self.render_pass,
+                &self.attachment_formats,
+                &pipeline_options,
+                (vertex_shader_module, fragment_shader_module),
+                &vertex_input_info,
+                desc.topology,
+                layout,
+            ) {
+                Ok(pipeline) => Ok(MeshPipeline {
+                    pipeline,
+                    layout,
+                    texture_set_layout,
+                    textures: desc.textures,
+                    push_constants: desc.push_constants,
+                }),
+                Err(err) => {
+                    unsafe {
+                        self.logical_device.destroy_pipeline_layout(layout, None);
+                        self.logical_device
+                            .destroy_descriptor_set_layout(texture_set_layout, None);
+                    }
+                    Err(err)
+                }
+            }
+        });
+        destroy_modules(&[vertex_shader_module, fragment_shader_module]);
+        result
+    }
+
+    // Set 0 holds the pipeline's textures, one combined image sampler per binding, or is the
+    // bindless table, whose layout the pipeline doesn't own.
+    fn create_mesh_pipeline_layout(
+        &self,
+        desc: &MeshPipelineDesc,
+    ) -> Result<(vk::DescriptorSetLayout, vk::PipelineLayout), anyhow::Error> {
+        let push_constant_ranges: Vec<_> = (desc.push_constants > 0)
+            .then(|| {
+                vk::PushConstantRange::default()
+                    .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+                    .size(desc.push_constants)
+            })
+            .into_iter()
+            .collect();
+        if desc.bindless {
+            let table = self
+                .bindless
+                .as_ref()
+                .ok_or_else(|| anyhow!("Bindless resources are not enabled."))?;
+            let set_layouts = [table.set_layout];
+            let layout_info = vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(&push_constant_ranges);
+            let layout = unsafe {
+                self.logical_device
+                    .create_pipeline_layout(&layout_info, None)?
+            };
+            return Ok((vk::DescriptorSetLayout::null(), layout));
+        }
+
+        let bindings: Vec<_> = (0..desc.textures)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            })
+            .collect();
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
+        let texture_set_layout = unsafe {
+            self.logical_device
+                .create_descriptor_set_layout(&set_layout_info, None)?
+        };
+        let set_layouts = [texture_set_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        match unsafe {
+            self.logical_device
+                .create_pipeline_layout(&layout_info, None)
+        } {
+            Ok(layout) => Ok((texture_set_layout, layout)),
+            Err(err) => {
+                unsafe {
+                    self.logical_device
+                        .destroy_descriptor_set_layout(texture_set_layout, None)
+                };
+                Err(err.into())
+            }
+        }
+    }
+
+    // The pipeline is destroyed once the frames that might use it have finished.
+    pub fn destroy_mesh_pipeline(&mut self, pipeline: MeshPipeline) {
+        self.retire(move |logical_device| unsafe {
+            logical_device.destroy_pipeline(pipeline.pipeline, None);
+            logical_device.destroy_pipeline_layout(pipeline.layout, None);
+            logical_device.destroy_descriptor_set_layout(pipeline.texture_set_layout, None);
+        });
+    }
+
+    // Also sets the viewport and scissor, which mesh pipelines leave dynamic.
+    pub fn bind_mesh_pipeline(&self, commandbuffer: vk::CommandBuffer, pipeline: &MeshPipeline) {
+        unsafe {
+            self.logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+        }
+        self.set_viewport_and_scissor(commandbuffer);
+    }
+
+    // `data` must fit the pipeline's `push_constants`.
+    pub fn push_mesh_constants(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        pipeline: &MeshPipeline,
+        data: &[u8],
+    ) {
+        assert!(
+            data.len() <= pipeline.push_constants as usize,
+            "Push constants larger than the pipeline's range."
+        );
+        unsafe {
+            self.logical_device.cmd_push_constants(
+                commandbuffer,
+                pipeline.layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                data,
+            );
+        }
+    }
+
+    // Binds `buffers` to consecutive bindings starting at `first_binding`.
+    pub fn bind_vertex_buffers(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        first_binding: u32,
+        buffers: &[&Buffer],
+    ) {
+        let handles: Vec<_> = buffers.iter().map(|buffer| buffer.buffer).collect();
+        let offsets = vec![0; buffers.len()];
+        unsafe {
+            self.logical_device.cmd_bind_vertex_buffers(
+                commandbuffer,
+                first_binding,
+                &handles,
+                &offsets,
+            );
+        }
+    }
+
+    pub fn bind_index_buffer(&self, commandbuffer: vk::CommandBuffer, index_buffer: &IndexBuffer) {
+        unsafe {
+            self.logical_device.cmd_bind_index_buffer(
+                commandbuffer,
+                index_buffer.buffer.buffer,
+                0,
+                index_buffer.index_type,
+            );
+        }
+    }
+
+    pub fn draw(&self, commandbuffer: vk::CommandBuffer, draw: &Draw) {
+        unsafe {
+            self.logical_device.cmd_draw(
+                commandbuffer,
+                draw.vertex_count,
+                draw.instance_count,
+                draw.first_vertex,
+                draw.first_instance,
+            );
+        }
+    }
+
+    pub fn draw_indexed(&self, commandbuffer: vk::CommandBuffer, draw: &DrawIndexed) {
+        unsafe {
+            self.logical_device.cmd_draw_indexed(
+                commandbuffer,
+                draw.index_count,
+                draw.instance_count,
+                draw.first_index,
+                draw.base_vertex,
+                draw.first_instance,
+            );
+        }
+    }
+
+    // As `draw`, with the parameters read from a `vk::DrawIndirectCommand` at `offset` in
+    // `buffer`, which needs INDIRECT_BUFFER usage, e.g. one filled by a compute pass.
+    pub fn draw_indirect(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        unsafe {
+            self.logical_device
+                .cmd_draw_indirect(commandbuffer, buffer.buffer, offset, 1, 0);
+        }
+    }
+
+    // As `draw_indexed`, reading a `vk::DrawIndexedIndirectCommand`.
+    pub fn draw_indexed_indirect(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        unsafe {
+            self.logical_device.cmd_draw_indexed_indirect(
+                commandbuffer,
+                buffer.buffer,
+                offset,
+                1,
+                0,
+            );
+        }
+    }
+}
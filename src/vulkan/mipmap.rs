@@ -0,0 +1,150 @@
+// Mip chains generated on the GPU.  Blits need a graphics queue, so a texture's base level is
+// uploaded on the transfer queue as usual and the rest of its chain is blitted level by level at
+// the start of the next frame, right after the upload is acquired.  Only formats that support
+// linear filtering as blit source and destination can be generated this way.
+
+use ash::vk;
+
+use super::barriers::ImageUsage;
+use super::Vulkan;
+
+// An image whose levels are all TRANSFER_DST_OPTIMAL with only the base level written.  Once
+// recorded every level is SHADER_READ_ONLY_OPTIMAL.
+pub struct MipChain {
+    pub image: vk::Image,
+    pub extent: vk::Extent2D,
+    pub levels: u32,
+    // Every layer, e.g. each face of a cubemap, gets a chain.
+    pub layers: u32,
+}
+
+// Levels in a full chain for `extent`, down to 1x1.
+pub fn full_mip_levels(extent: vk::Extent2D) -> u32 {
+    u32::BITS - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
+// The extent of mip `level`, never smaller than a texel.
+pub fn mip_extent(extent: vk::Extent2D, level: u32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: (extent.width >> level).max(1),
+        height: (extent.height >> level).max(1),
+    }
+}
+
+fn far_corner(extent: vk::Extent2D) -> vk::Offset3D {
+    vk::Offset3D {
+        x: extent.width as i32,
+        y: extent.height as i32,
+        z: 1,
+    }
+}
+
+impl Vulkan {
+    // Whether mip chains can be generated for images in `format`.
+    pub(super) fn can_generate_mipmaps(&self, format: vk::Format) -> bool {
+        let format_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
+        };
+        format_properties.optimal_tiling_features.contains(
+            vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST
+                | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        )
+    }
+
+    pub(super) fn record_mip_chains(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        mip_chains: &[MipChain],
+    ) {
+        let transfer_src = ImageUsage::TransferSrc.state();
+        let transfer_dst = ImageUsage::TransferDst.state();
+        let shader_read = ImageUsage::ShaderRead.state();
+        for mip_chain in mip_chains {
+            let level_barrier = |level: u32, level_count: u32| {
+                vk::ImageMemoryBarrier2::default()
+                    .image(mip_chain.image)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .base_mip_level(level)
+                            .level_count(level_count)
+                            .layer_count(mip_chain.layers),
+                    )
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            };
+            let subresource = |level: u32| {
+                vk::ImageSubresourceLayers::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(level)
+                    .layer_count(mip_chain.layers)
+            };
+
+            for level in 1..mip_chain.levels {
+                // The level above has just been written, by the upload or the previous blit.
+                let to_transfer_src = [level_barrier(level - 1, 1)
+                    .old_layout(transfer_dst.layout)
+                    .new_layout(transfer_src.layout)
+                    .src_stage_mask(transfer_dst.stage)
+                    .src_access_mask(transfer_dst.access)
+                    .dst_stage_mask(transfer_src.stage)
+                    .dst_access_mask(transfer_src.access)];
+                let blit = vk::ImageBlit::default()
+                    .src_subresource(subresource(level - 1))
+                    .src_offsets([
+                        vk::Offset3D::default(),
+                        far_corner(mip_extent(mip_chain.extent, level - 1)),
+                    ])
+                    .dst_subresource(subresource(level))
+                    .dst_offsets([
+                        vk::Offset3D::default(),
+                        far_corner(mip_extent(mip_chain.extent, level)),
+                    ]);
+                unsafe {
+                    let dependency_info =
+                        vk::DependencyInfo::default().image_memory_barriers(&to_transfer_src);
+                    self.synchronization2
+                        .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+                    self.logical_device.cmd_blit_image(
+                        commandbuffer,
+                        mip_chain.image,
+                        transfer_src.layout,
+                        mip_chain.image,
+                        transfer_dst.layout,
+                        &[blit],
+                        vk::Filter::LINEAR,
+                    );
+                }
+            }
+
+            // Every level but the last has been blitted from; the last was only written.
+            let last_level = mip_chain.levels - 1;
+            let mut to_shader_read = vec![level_barrier(last_level, 1)
+                .old_layout(transfer_dst.layout)
+                .new_layout(shader_read.layout)
+                .src_stage_mask(transfer_dst.stage)
+                .src_access_mask(transfer_dst.access)
+                .dst_stage_mask(shader_read.stage)
+                .dst_access_mask(shader_read.access)];
+            if last_level > 0 {
+                to_shader_read.push(
+                    level_barrier(0, last_level)
+                        .old_layout(transfer_src.layout)
+                        .new_layout(shader_read.layout)
+                        .src_stage_mask(transfer_src.stage)
+                        .src_access_mask(vk::AccessFlags2::NONE)
+                        .dst_stage_mask(shader_read.stage)
+                        .dst_access_mask(shader_read.access),
+                );
+            }
+            unsafe {
+                let dependency_info =
+                    vk::DependencyInfo::default().image_memory_barriers(&to_shader_read);
+                self.synchronization2
+                    .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+            }
+        }
+    }
+}
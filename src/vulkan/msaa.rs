@@ -0,0 +1,51 @@
+use ash::vk;
+use ash::Instance;
+
+use super::allocator::Allocator;
+use super::image::{self, Image};
+
+// Picks the largest supported sample count that doesn't exceed `requested`.  Counts above 8 are
+// not considered since few devices support them for both colour and depth.
+pub fn select_sample_count(
+    instance: &Instance,
+    physical_device: &vk::PhysicalDevice,
+    requested: u32,
+    has_depth: bool,
+) -> vk::SampleCountFlags {
+    let limits = unsafe { instance.get_physical_device_properties(*physical_device) }.limits;
+    let mut supported = limits.framebuffer_color_sample_counts;
+    if has_depth {
+        supported &= limits.framebuffer_depth_sample_counts;
+    }
+    [
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .into_iter()
+    .find(|samples| samples.as_raw() <= requested && supported.contains(*samples))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+// The multisampled image rendered into before being resolved to the swapchain image.  It is never
+// read after the resolve, so it is created as transient.
+pub fn create_color_image(
+    allocator: &Allocator,
+    logical_device: &ash::Device,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    samples: vk::SampleCountFlags,
+) -> Result<Image, anyhow::Error> {
+    let image_create_info = image::attachment_create_info(
+        format,
+        extent,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+        samples,
+    );
+    Image::new(
+        allocator,
+        logical_device,
+        &image_create_info,
+        vk::ImageAspectFlags::COLOR,
+    )
+}
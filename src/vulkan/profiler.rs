@@ -0,0 +1,335 @@
+// GPU timing with timestamp queries.  Every frame in flight has its own range of queries; a
+// frame's timestamps are read back when its slot comes round again, by which point the frame
+// timeline guarantees the GPU has finished with it.
+
+use ash::vk;
+use ash::Instance;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+// Timestamps are written at the start and end of every scope.
+const MAX_SCOPES_PER_FRAME: u32 = 64;
+const QUERIES_PER_FRAME: u32 = MAX_SCOPES_PER_FRAME * 2;
+// Number of frames averaged per scope, and kept in the frame-time history.
+const HISTORY_LENGTH: usize = 120;
+
+// The scope wrapping a whole frame; its durations make up the frame-time history.
+pub const FRAME_SCOPE: &str = "frame";
+
+struct RecordedScope {
+    label: String,
+    begin_query: u32,
+    end_query: Option<u32>,
+}
+
+#[derive(Default)]
+struct FrameQueries {
+    scopes: Vec<RecordedScope>,
+    query_count: u32,
+    // Set by `end_frame` once the frame has been submitted.
+    submission: Option<(u64, Instant)>,
+}
+
+// When each scope of a frame ran on the GPU, for exporting alongside CPU traces.
+pub struct GpuFrameTimeline {
+    pub frame: u64,
+    // CPU time at which the frame was submitted; the GPU can't have started it any earlier.
+    pub submitted: Instant,
+    // The GPU clock, in nanoseconds, when the frame's first scope began.  Only differences
+    // between frames are meaningful, and they stop being so if the timestamp counter wraps.
+    pub gpu_begin_ns: f64,
+    pub scopes: Vec<GpuScopeTiming>,
+}
+
+pub struct GpuScopeTiming {
+    pub label: String,
+    // Nanoseconds since `gpu_begin_ns`.
+    pub begin_ns: f64,
+    pub end_ns: f64,
+}
+
+// Recent durations of one scope label, in milliseconds.
+struct ScopeTimings {
+    label: String,
+    durations: VecDeque<f64>,
+}
+
+#[derive(Default)]
+pub struct GpuProfiler {
+    // Null when the graphics queue doesn't support timestamps, in which case nothing is recorded.
+    query_pool: vk::QueryPool,
+    // Nanoseconds per timestamp tick.
+    timestamp_period: f64,
+    timestamp_mask: u64,
+    frames: Vec<FrameQueries>,
+    current_frame: usize,
+    // Indices into the current frame's scopes of those begun but not yet ended.
+    open_scopes: Vec<usize>,
+    scopes: Vec<ScopeTimings>,
+    frame_times: VecDeque<f64>,
+    // Timelines read back but not yet taken, at most `HISTORY_LENGTH` of them.
+    timelines: VecDeque<GpuFrameTimeline>,
+}
+
+impl GpuProfiler {
+    pub(crate) fn new(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &ash::Device,
+        graphics_queue_family: u32,
+        frames_in_flight: usize,
+    ) -> Result<Self, vk::Result> {
+        let properties = unsafe { instance.get_physical_device_properties(*physical_device) };
+        let queue_family_properties =
+            unsafe { instance.get_physical_device_queue_family_properties(*physical_device) };
+        let valid_bits =
+            queue_family_properties[graphics_queue_family as usize].timestamp_valid_bits;
+        let mut profiler = Self {
+            timestamp_period: properties.limits.timestamp_period as f64,
+            timestamp_mask: if valid_bits >= 64 {
+                u64::MAX
+            } else {
+                (1 << valid_bits) - 1
+            },
+            frames: (0..frames_in_flight)
+                .map(|_| FrameQueries::default())
+                .collect(),
+            ..Default::default()
+        };
+        if valid_bits == 0 {
+            tracing::warn!(
+                "The graphics queue doesn't support timestamps; GPU timings are unavailable."
+            );
+            return Ok(profiler);
+        }
+        let query_pool_create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(QUERIES_PER_FRAME * frames_in_flight as u32);
+        profiler.query_pool =
+            unsafe { logical_device.create_query_pool(&query_pool_create_info, None)? };
+        Ok(profiler)
+    }
+
+    // Reads back the timestamps previously recorded in `frame_slot`, which the GPU must have
+    // finished, and makes it the slot that new scopes are recorded into.
+    pub(crate) fn begin_frame(
+        &mut self,
+        logical_device: &ash::Device,
+        frame_slot: usize,
+    ) -> Result<(), vk::Result> {
+        self.current_frame = frame_slot;
+        self.open_scopes.clear();
+        let frame = std::mem::take(&mut self.frames[frame_slot]);
+        if frame.query_count == 0 {
+            return Ok(());
+        }
+        let mut timestamps = vec![0u64; frame.query_count as usize];
+        let first_query = frame_slot as u32 * QUERIES_PER_FRAME;
+        let results = unsafe {
+            logical_device.get_query_pool_results(
+                self.query_pool,
+                first_query,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        match results {
+            Ok(()) => {}
+            // The frame was recorded but never submitted, e.g. because recording failed.
+            Err(vk::Result::NOT_READY) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        // Durations are summed per label, so a label used several times in a frame reports the
+        // total time spent in it.
+        let mut frame_durations: Vec<(String, f64)> = Vec::new();
+        let frame_begin = timestamps[0] & self.timestamp_mask;
+        let mut timeline_scopes = Vec::with_capacity(frame.scopes.len());
+        for scope in frame.scopes {
+            let Some(end_query) = scope.end_query else {
+                continue;
+            };
+            let begin =
+                timestamps[(scope.begin_query - first_query) as usize] & self.timestamp_mask;
+            let end = timestamps[(end_query - first_query) as usize] & self.timestamp_mask;
+            let ticks = end.wrapping_sub(begin) & self.timestamp_mask;
+            let milliseconds = ticks as f64 * self.timestamp_period / 1_000_000.0;
+            let begin_ns = (begin.wrapping_sub(frame_begin) & self.timestamp_mask) as f64
+                * self.timestamp_period;
+            timeline_scopes.push(GpuScopeTiming {
+                label: scope.label.clone(),
+                begin_ns,
+                end_ns: begin_ns + ticks as f64 * self.timestamp_period,
+            });
+            match frame_durations
+                .iter_mut()
+                .find(|(label, _)| *label == scope.label)
+            {
+                Some((_, total)) => *total += milliseconds,
+                None => frame_durations.push((scope.label, milliseconds)),
+            }
+        }
+        for (label, milliseconds) in frame_durations {
+            if label == FRAME_SCOPE {
+                Self::push_sample(&mut self.frame_times, milliseconds);
+            }
+            let index = match self.scopes.iter().position(|scope| scope.label == label) {
+                Some(index) => index,
+                None => {
+                    self.scopes.push(ScopeTimings {
+                        label,
+                        durations: VecDeque::new(),
+                    });
+                    self.scopes.len() - 1
+                }
+            };
+            Self::push_sample(&mut self.scopes[index].durations, milliseconds);
+        }
+        if let Some((frame_number, submitted)) = frame.submission {
+            if self.timelines.len() == HISTORY_LENGTH {
+                self.timelines.pop_front();
+            }
+            self.timelines.push_back(GpuFrameTimeline {
+                frame: frame_number,
+                submitted,
+                gpu_begin_ns: frame_begin as f64 * self.timestamp_period,
+                scopes: timeline_scopes,
+            });
+        }
+        Ok(())
+    }
+
+    // Records when the frame in the current slot was submitted, so that its timeline can be
+    // lined up with CPU time.
+    pub(crate) fn end_frame(&mut self, frame: u64, submitted: Instant) {
+        self.frames[self.current_frame].submission = Some((frame, submitted));
+    }
+
+    fn push_sample(samples: &mut VecDeque<f64>, sample: f64) {
+        if samples.len() == HISTORY_LENGTH {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    // Must be recorded before the first scope of the frame, outside any render pass.
+    pub(crate) fn reset(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+        if self.query_pool == vk::QueryPool::null() {
+            return;
+        }
+        unsafe {
+            logical_device.cmd_reset_query_pool(
+                commandbuffer,
+                self.query_pool,
+                self.current_frame as u32 * QUERIES_PER_FRAME,
+                QUERIES_PER_FRAME,
+            );
+        }
+    }
+
+    // Scopes nest; each `begin_scope` must be matched by an `end_scope` in the same frame.
+    // Scopes beyond `MAX_SCOPES_PER_FRAME` are not timed.
+    pub(crate) fn begin_scope(
+        &mut self,
+        synchronization2: &ash::khr::synchronization2::Device,
+        commandbuffer: vk::CommandBuffer,
+        label: &str,
+    ) {
+        let query = self.allocate_query();
+        let frame = &mut self.frames[self.current_frame];
+        if let Some(query) = query {
+            unsafe {
+                synchronization2.cmd_write_timestamp2(
+                    commandbuffer,
+                    vk::PipelineStageFlags2::TOP_OF_PIPE,
+                    self.query_pool,
+                    query,
+                );
+            }
+            frame.scopes.push(RecordedScope {
+                label: label.to_string(),
+                begin_query: query,
+                end_query: None,
+            });
+            self.open_scopes.push(frame.scopes.len() - 1);
+        } else {
+            // Keeps `end_scope` balanced for scopes that aren't timed.
+            self.open_scopes.push(usize::MAX);
+        }
+    }
+
+    pub(crate) fn end_scope(
+        &mut self,
+        synchronization2: &ash::khr::synchronization2::Device,
+        commandbuffer: vk::CommandBuffer,
+    ) {
+        let scope = self
+            .open_scopes
+            .pop()
+            .expect("end_scope called without a matching begin_scope");
+        if scope == usize::MAX {
+            return;
+        }
+        // The begin query reserved a slot for the end query, so this cannot fail.
+        let query = self.frames[self.current_frame].query_count
+            + self.current_frame as u32 * QUERIES_PER_FRAME;
+        self.frames[self.current_frame].query_count += 1;
+        unsafe {
+            synchronization2.cmd_write_timestamp2(
+                commandbuffer,
+                vk::PipelineStageFlags2::BOTTOM_OF_PIPE,
+                self.query_pool,
+                query,
+            );
+        }
+        self.frames[self.current_frame].scopes[scope].end_query = Some(query);
+    }
+
+    // Reserves a query for the beginning of a scope, and room for its end.
+    fn allocate_query(&mut self) -> Option<u32> {
+        if self.query_pool == vk::QueryPool::null() {
+            return None;
+        }
+        let frame = &mut self.frames[self.current_frame];
+        let reserved = frame.query_count + self.open_scopes.len() as u32;
+        if reserved + 2 > QUERIES_PER_FRAME {
+            return None;
+        }
+        let query = frame.query_count + self.current_frame as u32 * QUERIES_PER_FRAME;
+        frame.query_count += 1;
+        Some(query)
+    }
+
+    // Average duration of every scope label seen, in milliseconds, in order of first appearance.
+    pub fn scope_averages(&self) -> Vec<(&str, f64)> {
+        self.scopes
+            .iter()
+            .map(|scope| (scope.label.as_str(), Self::average(&scope.durations)))
+            .collect()
+    }
+
+    pub fn average_ms(&self, label: &str) -> Option<f64> {
+        self.scopes
+            .iter()
+            .find(|scope| scope.label == label)
+            .map(|scope| Self::average(&scope.durations))
+    }
+
+    // GPU time of recent frames in milliseconds, oldest first.
+    pub fn frame_times(&self) -> impl Iterator<Item = f64> + '_ {
+        self.frame_times.iter().copied()
+    }
+
+    // Timelines of frames read back since the last call, oldest first.
+    pub(crate) fn take_timelines(&mut self) -> Vec<GpuFrameTimeline> {
+        self.timelines.drain(..).collect()
+    }
+
+    fn average(samples: &VecDeque<f64>) -> f64 {
+        samples.iter().sum::<f64>() / samples.len().max(1) as f64
+    }
+
+    pub(crate) fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe { logical_device.destroy_query_pool(self.query_pool, None) };
+    }
+}
@@ -0,0 +1,441 @@
+// Occlusion and pipeline-statistics queries, labelled like profiler scopes.  As with timestamps,
+// every frame in flight has its own range of queries, read back without waiting once the frame
+// timeline shows the frame has finished.
+//
+// With VK_EXT_conditional_rendering, occlusion results are also copied on the GPU into a
+// predicate buffer after each render graph pass, so later passes in the same frame can skip
+// draws whose occlusion query passed no samples.
+
+use ash::vk;
+use ash::Instance;
+use std::collections::HashMap;
+
+use super::allocator::Allocator;
+use super::buffer::Buffer;
+use super::features::DeviceFeatures;
+
+const MAX_QUERIES_PER_FRAME: u32 = 64;
+
+// Each field is only counted when the device supports pipeline statistics queries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub input_vertices: u64,
+    pub input_primitives: u64,
+    pub vertex_shader_invocations: u64,
+    pub clipping_invocations: u64,
+    pub clipping_primitives: u64,
+    pub fragment_shader_invocations: u64,
+    pub compute_shader_invocations: u64,
+}
+
+impl PipelineStatistics {
+    // Results are written in the order of these flag bits.
+    fn flags() -> vk::QueryPipelineStatisticFlags {
+        vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_VERTICES
+            | vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_PRIMITIVES
+            | vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::CLIPPING_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES
+            | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS
+    }
+
+    const COUNTERS: usize = 7;
+
+    fn from_results(results: &[u64]) -> Self {
+        Self {
+            input_vertices: results[0],
+            input_primitives: results[1],
+            vertex_shader_invocations: results[2],
+            clipping_invocations: results[3],
+            clipping_primitives: results[4],
+            fragment_shader_invocations: results[5],
+            compute_shader_invocations: results[6],
+        }
+    }
+}
+
+#[derive(Default)]
+struct FrameQueries {
+    // Labels in query order; query `i` of the frame belongs to label `i`.
+    occlusion_labels: Vec<String>,
+    statistics_labels: Vec<String>,
+    // Occlusion queries before this index have been copied into the predicate buffer.
+    occlusion_copied: u32,
+}
+
+#[derive(Default)]
+pub struct GpuQueries {
+    // Either pool is null when the device doesn't support that kind of query.
+    occlusion_pool: vk::QueryPool,
+    statistics_pool: vk::QueryPool,
+    precise_occlusion: bool,
+    conditional_rendering: Option<ash::ext::conditional_rendering::Device>,
+    // One 32-bit result per occlusion query, per frame in flight.  Only with conditional rendering.
+    predicate_buffer: Option<Buffer>,
+    frames: Vec<FrameQueries>,
+    current_frame: usize,
+    open_occlusion: bool,
+    open_statistics: bool,
+    open_conditional: bool,
+    occlusion_results: HashMap<String, u64>,
+    statistics_results: HashMap<String, PipelineStatistics>,
+}
+
+impl GpuQueries {
+    pub(crate) fn new(
+        instance: &Instance,
+        allocator: &Allocator,
+        logical_device: &ash::Device,
+        device_features: &DeviceFeatures,
+        frames_in_flight: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let query_count = MAX_QUERIES_PER_FRAME * frames_in_flight as u32;
+        let occlusion_pool_create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::OCCLUSION)
+            .query_count(query_count);
+        let occlusion_pool =
+            unsafe { logical_device.create_query_pool(&occlusion_pool_create_info, None)? };
+        let statistics_pool = if device_features.pipeline_statistics_query {
+            let statistics_pool_create_info = vk::QueryPoolCreateInfo::default()
+                .query_type(vk::QueryType::PIPELINE_STATISTICS)
+                .query_count(query_count)
+                .pipeline_statistics(PipelineStatistics::flags());
+            unsafe { logical_device.create_query_pool(&statistics_pool_create_info, None)? }
+        } else {
+            vk::QueryPool::null()
+        };
+
+        let conditional_rendering = device_features
+            .conditional_rendering
+            .then(|| ash::ext::conditional_rendering::Device::new(instance, logical_device));
+        let predicate_buffer = if conditional_rendering.is_some() {
+            Some(Buffer::new(
+                allocator,
+                logical_device,
+                query_count as vk::DeviceSize * 4,
+                vk::BufferUsageFlags::CONDITIONAL_RENDERING_EXT
+                    | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            occlusion_pool,
+            statistics_pool,
+            precise_occlusion: device_features.occlusion_query_precise,
+            conditional_rendering,
+            predicate_buffer,
+            frames: (0..frames_in_flight)
+                .map(|_| FrameQueries::default())
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    // Reads back the results previously recorded in `frame_slot`, which the GPU must have
+    // finished, and makes it the slot that new queries are recorded into.
+    pub(crate) fn begin_frame(
+        &mut self,
+        logical_device: &ash::Device,
+        frame_slot: usize,
+    ) -> Result<(), vk::Result> {
+        self.current_frame = frame_slot;
+        self.open_occlusion = false;
+        self.open_statistics = false;
+        self.open_conditional = false;
+        let frame = std::mem::take(&mut self.frames[frame_slot]);
+        let first_query = Self::first_query(frame_slot);
+
+        if !frame.occlusion_labels.is_empty() {
+            let mut results = vec![0u64; frame.occlusion_labels.len()];
+            if Self::read_results(
+                logical_device,
+                self.occlusion_pool,
+                first_query,
+                &mut results,
+            )? {
+                for (label, samples) in frame.occlusion_labels.into_iter().zip(results) {
+                    self.occlusion_results.insert(label, samples);
+                }
+            }
+        }
+        if !frame.statistics_labels.is_empty() {
+            let mut results =
+                vec![[0u64; PipelineStatistics::COUNTERS]; frame.statistics_labels.len()];
+            if Self::read_results(
+                logical_device,
+                self.statistics_pool,
+                first_query,
+                &mut results,
+            )? {
+                for (label, counters) in frame.statistics_labels.into_iter().zip(results) {
+                    self.statistics_results
+                        .insert(label, PipelineStatistics::from_results(&counters));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // One element of `results` per query.  Returns false if the queries were never submitted,
+    // e.g. because recording the frame failed.
+    fn read_results<T>(
+        logical_device: &ash::Device,
+        query_pool: vk::QueryPool,
+        first_query: u32,
+        results: &mut [T],
+    ) -> Result<bool, vk::Result> {
+        let result = unsafe {
+            logical_device.get_query_pool_results(
+                query_pool,
+                first_query,
+                results,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(vk::Result::NOT_READY) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn first_query(frame_slot: usize) -> u32 {
+        frame_slot as u32 * MAX_QUERIES_PER_FRAME
+    }
+
+    // Must be recorded before any query of the frame, outside any render pass.
+    pub(crate) fn reset(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+        let first_query = Self::first_query(self.current_frame);
+        for query_pool in [self.occlusion_pool, self.statistics_pool] {
+            if query_pool != vk::QueryPool::null() {
+                unsafe {
+                    logical_device.cmd_reset_query_pool(
+                        commandbuffer,
+                        query_pool,
+                        first_query,
+                        MAX_QUERIES_PER_FRAME,
+                    );
+                }
+            }
+        }
+    }
+
+    // Occlusion queries don't nest, and must begin and end in the same subpass.  Queries beyond
+    // `MAX_QUERIES_PER_FRAME` are dropped.  Sample counts are exact when the device supports
+    // `occlusionQueryPrecise`; otherwise only zero and non-zero are meaningful.
+    pub(crate) fn begin_occlusion(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        label: &str,
+    ) {
+        assert!(!self.open_occlusion, "Occlusion queries cannot nest.");
+        let frame = &mut self.frames[self.current_frame];
+        if frame.occlusion_labels.len() as u32 == MAX_QUERIES_PER_FRAME {
+            return;
+        }
+        let query = Self::first_query(self.current_frame) + frame.occlusion_labels.len() as u32;
+        let flags = if self.precise_occlusion {
+            vk::QueryControlFlags::PRECISE
+        } else {
+            vk::QueryControlFlags::empty()
+        };
+        unsafe { logical_device.cmd_begin_query(commandbuffer, self.occlusion_pool, query, flags) };
+        frame.occlusion_labels.push(label.to_string());
+        self.open_occlusion = true;
+    }
+
+    pub(crate) fn end_occlusion(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+    ) {
+        if !self.open_occlusion {
+            return;
+        }
+        let frame = &self.frames[self.current_frame];
+        let query = Self::first_query(self.current_frame) + frame.occlusion_labels.len() as u32 - 1;
+        unsafe { logical_device.cmd_end_query(commandbuffer, self.occlusion_pool, query) };
+        self.open_occlusion = false;
+    }
+
+    // Same rules as occlusion queries.  Nothing is recorded if pipeline statistics aren't
+    // supported.
+    pub(crate) fn begin_statistics(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        label: &str,
+    ) {
+        assert!(
+            !self.open_statistics,
+            "Pipeline statistics queries cannot nest."
+        );
+        let frame = &mut self.frames[self.current_frame];
+        if self.statistics_pool == vk::QueryPool::null()
+            || frame.statistics_labels.len() as u32 == MAX_QUERIES_PER_FRAME
+        {
+            return;
+        }
+        let query = Self::first_query(self.current_frame) + frame.statistics_labels.len() as u32;
+        unsafe {
+            logical_device.cmd_begin_query(
+                commandbuffer,
+                self.statistics_pool,
+                query,
+                vk::QueryControlFlags::empty(),
+            )
+        };
+        frame.statistics_labels.push(label.to_string());
+        self.open_statistics = true;
+    }
+
+    pub(crate) fn end_statistics(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+    ) {
+        if !self.open_statistics {
+            return;
+        }
+        let frame = &self.frames[self.current_frame];
+        let query =
+            Self::first_query(self.current_frame) + frame.statistics_labels.len() as u32 - 1;
+        unsafe { logical_device.cmd_end_query(commandbuffer, self.statistics_pool, query) };
+        self.open_statistics = false;
+    }
+
+    // Copies the occlusion results recorded since the last call into the predicate buffer, ready
+    // for conditional rendering.  Called by the render graph after every pass, outside any
+    // render pass.
+    pub(crate) fn copy_occlusion_results(
+        &mut self,
+        logical_device: &ash::Device,
+        synchronization2: &ash::khr::synchronization2::Device,
+        commandbuffer: vk::CommandBuffer,
+    ) {
+        let Some(predicate_buffer) = &self.predicate_buffer else {
+            return;
+        };
+        let frame = &mut self.frames[self.current_frame];
+        let ended = frame.occlusion_labels.len() as u32 - self.open_occlusion as u32;
+        if ended == frame.occlusion_copied {
+            return;
+        }
+        let first_query = Self::first_query(self.current_frame) + frame.occlusion_copied;
+        let count = ended - frame.occlusion_copied;
+        let offset = first_query as vk::DeviceSize * 4;
+        let barriers = [vk::BufferMemoryBarrier2::default()
+            .buffer(predicate_buffer.buffer)
+            .offset(offset)
+            .size(count as vk::DeviceSize * 4)
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::CONDITIONAL_RENDERING_EXT)
+            .dst_access_mask(vk::AccessFlags2::CONDITIONAL_RENDERING_READ_EXT)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)];
+        let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(&barriers);
+        unsafe {
+            logical_device.cmd_copy_query_pool_results(
+                commandbuffer,
+                self.occlusion_pool,
+                first_query,
+                count,
+                predicate_buffer.buffer,
+                offset,
+                4,
+                vk::QueryResultFlags::WAIT,
+            );
+            synchronization2.cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+        }
+        frame.occlusion_copied = ended;
+    }
+
+    // Draws recorded until `end_conditional` are discarded if the occlusion query `label`, ended
+    // in an earlier pass of this frame, passed no samples (or passed some, when `inverted`).
+    // Returns false, and draws unconditionally, if the query doesn't exist or conditional
+    // rendering isn't supported.
+    pub(crate) fn begin_conditional(
+        &mut self,
+        commandbuffer: vk::CommandBuffer,
+        label: &str,
+        inverted: bool,
+    ) -> bool {
+        assert!(!self.open_conditional, "Conditional rendering cannot nest.");
+        let (Some(conditional_rendering), Some(predicate_buffer)) =
+            (&self.conditional_rendering, &self.predicate_buffer)
+        else {
+            return false;
+        };
+        let frame = &self.frames[self.current_frame];
+        // The most recent query with this label that has been copied.
+        let Some(index) = frame.occlusion_labels[..frame.occlusion_copied as usize]
+            .iter()
+            .rposition(|query_label| query_label == label)
+        else {
+            return false;
+        };
+        let query = Self::first_query(self.current_frame) + index as u32;
+        let flags = if inverted {
+            vk::ConditionalRenderingFlagsEXT::INVERTED
+        } else {
+            vk::ConditionalRenderingFlagsEXT::empty()
+        };
+        let begin_info = vk::ConditionalRenderingBeginInfoEXT::default()
+            .buffer(predicate_buffer.buffer)
+            .offset(query as vk::DeviceSize * 4)
+            .flags(flags);
+        // ash has no wrapper for this extension's commands.
+        unsafe {
+            (conditional_rendering
+                .fp()
+                .cmd_begin_conditional_rendering_ext)(commandbuffer, &begin_info)
+        };
+        self.open_conditional = true;
+        true
+    }
+
+    pub(crate) fn end_conditional(&mut self, commandbuffer: vk::CommandBuffer) {
+        if !self.open_conditional {
+            return;
+        }
+        let conditional_rendering = self
+            .conditional_rendering
+            .as_ref()
+            .expect("conditional rendering was begun");
+        unsafe { (conditional_rendering.fp().cmd_end_conditional_rendering_ext)(commandbuffer) };
+        self.open_conditional = false;
+    }
+
+    // The number of samples that passed the most recently completed occlusion query `label`.
+    pub fn occlusion_result(&self, label: &str) -> Option<u64> {
+        self.occlusion_results.get(label).copied()
+    }
+
+    pub fn statistics_result(&self, label: &str) -> Option<PipelineStatistics> {
+        self.statistics_results.get(label).copied()
+    }
+
+    pub fn supports_pipeline_statistics(&self) -> bool {
+        self.statistics_pool != vk::QueryPool::null()
+    }
+
+    pub fn supports_conditional_rendering(&self) -> bool {
+        self.conditional_rendering.is_some()
+    }
+
+    pub(crate) fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe {
+            logical_device.destroy_query_pool(self.occlusion_pool, None);
+            logical_device.destroy_query_pool(self.statistics_pool, None);
+        }
+        if let Some(predicate_buffer) = self.predicate_buffer.as_mut() {
+            predicate_buffer.destroy(logical_device);
+        }
+    }
+}
@@ -0,0 +1,436 @@
+// The resource registry.  Buffers, textures, mesh pipelines and samplers handed to it are owned
+// by `Vulkan` and reached through typed handles.  Handles can be cloned, and once the last clone of
+// one is dropped its resource is destroyed at the start of the next frame, deferred like
+// `destroy_buffer` until the frames that might still use it have finished.  Anything left is
+// destroyed with `Vulkan`.
+//
+// A `ResourceId` is a copyable key that doesn't keep its resource alive.  Each slot counts how
+// often it has been reused, so an id outliving its resource is reported as stale instead of
+// reaching whatever took the slot next.
+
+use anyhow::anyhow;
+use ash::vk;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use super::buffer::Buffer;
+use super::mesh::MeshPipeline;
+use super::sampler::SamplerDesc;
+use super::texture::Texture;
+use super::Vulkan;
+
+pub type BufferHandle = Handle<Buffer>;
+pub type TextureHandle = Handle<Texture>;
+pub type PipelineHandle = Handle<MeshPipeline>;
+pub type SamplerHandle = Handle<vk::Sampler>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    index: u32,
+    generation: u32,
+}
+
+// Keys whose handles have all been dropped, shared by a pool and its handles.
+type Released = Arc<Mutex<Vec<Key>>>;
+
+struct HandleInner {
+    key: Key,
+    released: Released,
+}
+
+impl Drop for HandleInner {
+    fn drop(&mut self) {
+        self.released
+            .lock()
+            .expect("Released handles poisoned.")
+            .push(self.key);
+    }
+}
+
+// Keeps a registered resource alive.
+pub struct Handle<T> {
+    inner: Arc<HandleInner>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub fn id(&self) -> ResourceId<T> {
+        ResourceId {
+            key: self.inner.key,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.id().fmt(f)
+    }
+}
+
+// Names a registered resource without keeping it alive.
+pub struct ResourceId<T> {
+    key: Key,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> From<&Handle<T>> for ResourceId<T> {
+    fn from(handle: &Handle<T>) -> Self {
+        handle.id()
+    }
+}
+
+impl<T> Clone for ResourceId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ResourceId<T> {}
+
+impl<T> PartialEq for ResourceId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<T> Eq for ResourceId<T> {}
+
+impl<T> Hash for ResourceId<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for ResourceId<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.key.index, self.key.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    // None while the slot is free.
+    value: Option<T>,
+}
+
+struct Pool<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    released: Released,
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            released: Released::default(),
+        }
+    }
+}
+
+impl<T> Pool<T> {
+    fn insert(&mut self, value: T) -> Handle<T> {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize].value = Some(value);
+                index
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+        Handle {
+            inner: Arc::new(HandleInner {
+                key: Key {
+                    index,
+                    generation: self.slots[index as usize].generation,
+                },
+                released: self.released.clone(),
+            }),
+            marker: PhantomData,
+        }
+    }
+
+    fn get(&self, id: ResourceId<T>, kind: &str) -> Result<&T, anyhow::Error> {
+        self.slots
+            .get(id.key.index as usize)
+            .filter(|slot| slot.generation == id.key.generation)
+            .and_then(|slot| slot.value.as_ref())
+            .ok_or_else(|| anyhow!("Stale {} handle {:?}.", kind, id))
+    }
+
+    // Hands `value` back if `id` is stale.
+    fn replace(&mut self, id: ResourceId<T>, value: T) -> Result<T, T> {
+        match self
+            .slots
+            .get_mut(id.key.index as usize)
+            .filter(|slot| slot.generation == id.key.generation)
+            .and_then(|slot| slot.value.as_mut())
+        {
+            Some(current) => Ok(std::mem::replace(current, value)),
+            None => Err(value),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (ResourceId<T>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = ResourceId {
+                key: Key {
+                    index: index as u32,
+                    generation: slot.generation,
+                },
+                marker: PhantomData,
+            };
+            Some((id, slot.value.as_ref()?))
+        })
+    }
+
+    fn remove(&mut self, key: Key) -> Option<T> {
+        let slot = self.slots.get_mut(key.index as usize)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(key.index);
+        Some(value)
+    }
+
+    // The values whose handles have all been dropped since the last call.
+    fn take_released(&mut self) -> Vec<T> {
+        let keys = std::mem::take(&mut *self.released.lock().expect("Released handles poisoned."));
+        keys.into_iter()
+            .filter_map(|key| self.remove(key))
+            .collect()
+    }
+
+    // Every value, whether or not handles to it remain; those handles become stale.
+    fn take_all(&mut self) -> Vec<T> {
+        self.free = (0..self.slots.len() as u32).collect();
+        self.slots
+            .iter_mut()
+            .filter_map(|slot| {
+                slot.generation = slot.generation.wrapping_add(1);
+                slot.value.take()
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    buffers: Pool<Buffer>,
+    textures: Pool<Texture>,
+    pipelines: Pool<MeshPipeline>,
+    samplers: Pool<vk::Sampler>,
+}
+
+impl Registry {
+    pub fn len(&self) -> usize {
+        self.buffers.len() + self.textures.len() + self.pipelines.len() + self.samplers.len()
+    }
+
+    pub fn buffers(&self) -> impl Iterator<Item = (ResourceId<Buffer>, &Buffer)> {
+        self.buffers.iter()
+    }
+
+    pub fn buffer(&self, id: ResourceId<Buffer>) -> Result<&Buffer, anyhow::Error> {
+        self.buffers.get(id, "buffer")
+    }
+
+    // Swaps the buffer `id` names for `buffer`, returning the old one.  Hands `buffer` back if `id`
+    // is stale.
+    pub fn replace_buffer(
+        &mut self,
+        id: ResourceId<Buffer>,
+        buffer: Buffer,
+    ) -> Result<Buffer, Buffer> {
+        self.buffers.replace(id, buffer)
+    }
+}
+
+impl Vulkan {
+    pub fn register_buffer(&mut self, buffer: Buffer) -> BufferHandle {
+        self.registry.buffers.insert(buffer)
+    }
+
+    pub fn register_texture(&mut self, texture: Texture) -> TextureHandle {
+        self.registry.textures.insert(texture)
+    }
+
+    pub fn register_mesh_pipeline(&mut self, pipeline: MeshPipeline) -> PipelineHandle {
+        self.registry.pipelines.insert(pipeline)
+    }
+
+    // A sampler of its own, unlike the shared ones from `sampler`.
+    pub fn create_sampler_handle(
+        &mut self,
+        desc: &SamplerDesc,
+    ) -> Result<SamplerHandle, anyhow::Error> {
+        let sampler = self.create_sampler_object(desc)?;
+        Ok(self.registry.samplers.insert(sampler))
+    }
+
+    pub fn registered_buffer(
+        &self,
+        id: impl Into<ResourceId<Buffer>>,
+    ) -> Result<&Buffer, anyhow::Error> {
+        self.registry.buffers.get(id.into(), "buffer")
+    }
+
+    pub fn registered_texture(
+        &self,
+        id: impl Into<ResourceId<Texture>>,
+    ) -> Result<&Texture, anyhow::Error> {
+        self.registry.textures.get(id.into(), "texture")
+    }
+
+    pub fn registered_mesh_pipeline(
+        &self,
+        id: impl Into<ResourceId<MeshPipeline>>,
+    ) -> Result<&MeshPipeline, anyhow::Error> {
+        self.registry.pipelines.get(id.into(), "pipeline")
+    }
+
+    pub fn registered_sampler(
+        &self,
+        id: impl Into<ResourceId<vk::Sampler>>,
+    ) -> Result<vk::Sampler, anyhow::Error> {
+        self.registry.samplers.get(id.into(), "sampler").copied()
+    }
+
+    // How many registered resources are alive, including those waiting to be destroyed.
+    pub fn registered_resource_count(&self) -> usize {
+        self.registry.len()
+    }
+
+    // Retires the resources whose handles have all been dropped.
+    pub(super) fn destroy_released_resources(&mut self) {
+        let buffers = self.registry.buffers.take_released();
+        let textures = self.registry.textures.take_released();
+        let pipelines = self.registry.pipelines.take_released();
+        let samplers = self.registry.samplers.take_released();
+        self.destroy_resources(buffers, textures, pipelines, samplers);
+    }
+
+    // Retires every registered resource, for when `Vulkan` is dropped.
+    pub(super) fn destroy_registered_resources(&mut self) {
+        let buffers = self.registry.buffers.take_all();
+        let textures = self.registry.textures.take_all();
+        let pipelines = self.registry.pipelines.take_all();
+        let samplers = self.registry.samplers.take_all();
+        self.destroy_resources(buffers, textures, pipelines, samplers);
+    }
+
+    fn destroy_resources(
+        &mut self,
+        buffers: Vec<Buffer>,
+        textures: Vec<Texture>,
+        pipelines: Vec<MeshPipeline>,
+        samplers: Vec<vk::Sampler>,
+    ) {
+        for buffer in buffers {
+            self.destroy_buffer(buffer);
+        }
+        for texture in textures {
+            self.destroy_texture(texture);
+        }
+        for pipeline in pipelines {
+            self.destroy_mesh_pipeline(pipeline);
+        }
+        for sampler in samplers {
+            self.retire(move |logical_device| unsafe {
+                logical_device.destroy_sampler(sampler, None)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_from_before_a_slot_was_reused_are_stale() {
+        let mut pool = Pool::default();
+        let first = pool.insert("first");
+        let first_id = first.id();
+        drop(first);
+        assert_eq!(pool.take_released(), vec!["first"]);
+        let second = pool.insert("second");
+        assert_eq!(second.id().key.index, first_id.key.index);
+        assert_ne!(second.id(), first_id);
+        assert!(pool.get(first_id, "test").is_err());
+        assert_eq!(pool.replace(first_id, "third"), Err("third"));
+        assert_eq!(*pool.get(second.id(), "test").unwrap(), "second");
+    }
+
+    #[test]
+    fn freed_slots_are_reused_before_new_ones() {
+        let mut pool = Pool::default();
+        let a = pool.insert(0);
+        let b = pool.insert(1);
+        let c = pool.insert(2);
+        drop(b);
+        pool.take_released();
+        assert_eq!(pool.len(), 2);
+        let d = pool.insert(3);
+        assert_eq!(d.id().key.index, 1);
+        assert_eq!(pool.len(), 3);
+        let e = pool.insert(4);
+        assert_eq!(e.id().key.index, 3);
+        assert_eq!(
+            pool.iter().map(|(_, value)| *value).collect::<Vec<_>>(),
+            vec![0, 3, 2, 4]
+        );
+        drop((a, c));
+    }
+
+    #[test]
+    fn values_are_released_once_their_last_handle_is_dropped() {
+        let mut pool = Pool::default();
+        let handle = pool.insert("value");
+        let id = handle.id();
+        let clone = handle.clone();
+        drop(handle);
+        assert!(pool.take_released().is_empty());
+        drop(clone);
+        // Dropping the last handle only queues the value; it stays reachable until taken.
+        assert_eq!(*pool.get(id, "test").unwrap(), "value");
+        assert_eq!(pool.take_released(), vec!["value"]);
+        assert!(pool.get(id, "test").is_err());
+        assert!(pool.take_released().is_empty());
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn taking_everything_makes_live_handles_stale() {
+        let mut pool = Pool::default();
+        let handle = pool.insert("value");
+        assert_eq!(pool.take_all(), vec!["value"]);
+        assert!(pool.get(handle.id(), "test").is_err());
+        drop(handle);
+        assert!(pool.take_released().is_empty());
+    }
+}
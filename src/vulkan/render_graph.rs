@@ -0,0 +1,985 @@
+// A frame described as a graph of passes.  Each pass declares the named images and buffers it
+// reads and writes; from that the graph orders the passes, drops the ones whose results are never
+// used, places transient images whose lifetimes don't overlap in the same memory and records the
+// barriers between passes through the `ResourceStateTracker`.
+//
+// Ordering rule: accesses to a resource take effect in the order they were declared.  A pass
+// reading a resource runs after the last pass declared to write it before the read, and a pass
+// writing it runs after the previous writer and every pass that read it in between.  Passes
+// extended with `extend_pass` are ordered by when each access was declared, not by when the pass
+// was added.  Imported resources are visible outside the graph, so passes writing them (or marked
+// with `side_effects`) are never culled.
+
+use anyhow::anyhow;
+use ash::vk;
+use ash::Instance;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use super::barriers::{self, BufferUsage, ImageState, ImageUsage, ResourceStateTracker};
+use super::image;
+use super::profiler::GpuProfiler;
+use super::queries::GpuQueries;
+use super::Vulkan;
+
+pub type PassExecute = Box<dyn Fn(&Vulkan, &PassResources, vk::CommandBuffer)>;
+
+// An image owned by the graph, only valid between its first and last use in a frame.
+#[derive(Clone, Copy, Debug)]
+pub struct TransientImageDesc {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub usage: vk::ImageUsageFlags,
+    pub samples: vk::SampleCountFlags,
+    pub aspect_mask: vk::ImageAspectFlags,
+}
+
+enum ImageSource {
+    // Owned and registered with the state tracker by the caller; bound with `bind_image`.
+    // `final_usage` is applied once all passes have run, e.g. `Present` for swapchain images.
+    Imported { final_usage: Option<ImageUsage> },
+    Transient(TransientImageDesc),
+}
+
+struct GraphImage {
+    name: String,
+    source: ImageSource,
+    image: vk::Image,
+    view: vk::ImageView,
+    // Positions in the schedule of the first and last pass using a transient image.
+    first_use: usize,
+    last_use: usize,
+    // The transient image that used the same memory before this one in a frame.
+    previous_occupant: Option<usize>,
+}
+
+// Buffers are always imported.
+struct GraphBuffer {
+    name: String,
+    buffer: vk::Buffer,
+}
+
+struct ImageAccess {
+    name: String,
+    image: usize,
+    // Position among all of the graph's accesses, in the order they were declared.
+    order: usize,
+    usage: ImageUsage,
+    write: bool,
+    // The previous contents are not needed, e.g. because the pass clears the image.
+    discard: bool,
+}
+
+struct BufferAccess {
+    name: String,
+    buffer: usize,
+    order: usize,
+    usage: BufferUsage,
+    write: bool,
+}
+
+struct Pass {
+    name: String,
+    image_accesses: Vec<ImageAccess>,
+    buffer_accesses: Vec<BufferAccess>,
+    side_effects: bool,
+    execute: Option<PassExecute>,
+}
+
+#[derive(Default)]
+pub struct RenderGraph {
+    images: Vec<GraphImage>,
+    buffers: Vec<GraphBuffer>,
+    passes: Vec<Pass>,
+    // Indices into `passes` in execution order, culled passes excluded.  Filled by `compile`.
+    schedule: Vec<usize>,
+    accesses_declared: usize,
+    transient_memory: Vec<vk::DeviceMemory>,
+}
+
+impl RenderGraph {
+    pub fn import_image(&mut self, name: &str, final_usage: Option<ImageUsage>) {
+        self.add_image(name, ImageSource::Imported { final_usage });
+    }
+
+    pub fn create_image(&mut self, name: &str, desc: TransientImageDesc) {
+        self.add_image(name, ImageSource::Transient(desc));
+    }
+
+    fn add_image(&mut self, name: &str, source: ImageSource) {
+        assert!(
+            self.image_index(name).is_none(),
+            "Image {} is already declared.",
+            name
+        );
+        self.images.push(GraphImage {
+            name: name.to_string(),
+            source,
+            image: vk::Image::null(),
+            view: vk::ImageView::null(),
+            first_use: 0,
+            last_use: 0,
+            previous_occupant: None,
+        });
+    }
+
+    pub fn import_buffer(&mut self, name: &str) {
+        assert!(
+            self.buffer_index(name).is_none(),
+            "Buffer {} is already declared.",
+            name
+        );
+        self.buffers.push(GraphBuffer {
+            name: name.to_string(),
+            buffer: vk::Buffer::null(),
+        });
+    }
+
+    // Imported resources may be rebound between executions, e.g. to the acquired swapchain image.
+    pub fn bind_image(&mut self, name: &str, image: vk::Image, view: vk::ImageView) {
+        let index = self
+            .image_index(name)
+            .unwrap_or_else(|| panic!("Image {} is not declared.", name));
+        let graph_image = &mut self.images[index];
+        assert!(
+            matches!(graph_image.source, ImageSource::Imported { .. }),
+            "Image {} is transient and cannot be bound.",
+            name
+        );
+        graph_image.image = image;
+        graph_image.view = view;
+    }
+
+    pub fn bind_buffer(&mut self, name: &str, buffer: vk::Buffer) {
+        let index = self
+            .buffer_index(name)
+            .unwrap_or_else(|| panic!("Buffer {} is not declared.", name));
+        self.buffers[index].buffer = buffer;
+    }
+
+    pub fn add_pass(&mut self, name: &str) -> PassBuilder<'_> {
+        self.passes.push(Pass {
+            name: name.to_string(),
+            image_accesses: Vec::new(),
+            buffer_accesses: Vec::new(),
+            side_effects: false,
+            execute: None,
+        });
+        PassBuilder {
+            pass: self.passes.last_mut().expect("a pass was just added"),
+            accesses_declared: &mut self.accesses_declared,
+        }
+    }
+
+    // Declares further resources used by a pass added earlier, such as the built-in "scene" pass
+    // reading buffers written by a compute pass.  Calling `execute` on the builder replaces what
+    // the pass records.
+    pub fn extend_pass(&mut self, name: &str) -> PassBuilder<'_> {
+        let pass = self
+            .passes
+            .iter_mut()
+            .find(|pass| pass.name == name)
+            .unwrap_or_else(|| panic!("Pass {} is not declared.", name));
+        PassBuilder {
+            pass,
+            accesses_declared: &mut self.accesses_declared,
+        }
+    }
+
+    fn image_index(&self, name: &str) -> Option<usize> {
+        self.images.iter().position(|image| image.name == name)
+    }
+
+    fn buffer_index(&self, name: &str) -> Option<usize> {
+        self.buffers.iter().position(|buffer| buffer.name == name)
+    }
+
+    // Orders and culls the passes, then creates the transient images.  Must be called once all
+    // resources and passes have been declared, and before `execute`.
+    pub(crate) fn compile(
+        &mut self,
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &ash::Device,
+        resource_states: &mut ResourceStateTracker,
+    ) -> Result<(), anyhow::Error> {
+        self.resolve_accesses()?;
+        let dependencies = self.dependencies();
+        let needed = self.needed_passes(&dependencies);
+        self.schedule = self.sort_passes(&dependencies, &needed)?;
+        self.check_transient_reads()?;
+        self.create_transient_images(instance, physical_device, logical_device, resource_states)
+    }
+
+    fn resolve_accesses(&mut self) -> Result<(), anyhow::Error> {
+        let image_indices: HashMap<String, usize> = self
+            .images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| (image.name.clone(), index))
+            .collect();
+        let buffer_indices: HashMap<String, usize> = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| (buffer.name.clone(), index))
+            .collect();
+        for pass in self.passes.iter_mut() {
+            if pass.execute.is_none() {
+                return Err(anyhow!("Pass {} has nothing to execute.", pass.name));
+            }
+            for access in pass.image_accesses.iter_mut() {
+                access.image = *image_indices.get(&access.name).ok_or_else(|| {
+                    anyhow!("Pass {} uses undeclared image {}.", pass.name, access.name)
+                })?;
+            }
+            for access in pass.buffer_accesses.iter_mut() {
+                access.buffer = *buffer_indices.get(&access.name).ok_or_else(|| {
+                    anyhow!("Pass {} uses undeclared buffer {}.", pass.name, access.name)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // For every pass, the passes that must run before it.
+    fn dependencies(&self) -> Vec<BTreeSet<usize>> {
+        let mut accesses: Vec<(usize, usize, Resource, bool)> = self
+            .passes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, pass)| {
+                pass.resources()
+                    .map(move |(order, resource, write)| (order, index, resource, write))
+            })
+            .collect();
+        accesses.sort_unstable_by_key(|&(order, ..)| order);
+
+        // The last writer of each resource so far, and the passes that read it since.
+        let mut last_writers: HashMap<Resource, usize> = HashMap::new();
+        let mut readers: HashMap<Resource, Vec<usize>> = HashMap::new();
+        let mut dependencies = vec![BTreeSet::new(); self.passes.len()];
+        for (_, index, resource, write) in accesses {
+            let resource_readers = readers.entry(resource).or_default();
+            if write {
+                dependencies[index].extend(last_writers.get(&resource));
+                dependencies[index].extend(resource_readers.drain(..));
+                last_writers.insert(resource, index);
+            } else {
+                dependencies[index].extend(last_writers.get(&resource));
+                resource_readers.push(index);
+            }
+            dependencies[index].remove(&index);
+        }
+        dependencies
+    }
+
+    fn needed_passes(&self, dependencies: &[BTreeSet<usize>]) -> Vec<bool> {
+        let mut needed = vec![false; self.passes.len()];
+        let mut stack: Vec<usize> = self
+            .passes
+            .iter()
+            .enumerate()
+            .filter(|(_, pass)| {
+                pass.side_effects
+                    || pass.buffer_accesses.iter().any(|access| access.write)
+                    || pass.image_accesses.iter().any(|access| {
+                        access.write
+                            && matches!(
+                                self.images[access.image].source,
+                                ImageSource::Imported { .. }
+                            )
+                    })
+            })
+            .map(|(index, _)| index)
+            .collect();
+        while let Some(index) = stack.pop() {
+            if !needed[index] {
+                needed[index] = true;
+                stack.extend(dependencies[index].iter().copied());
+            }
+        }
+        needed
+    }
+
+    // Topological sort, preferring the order in which passes were added.
+    fn sort_passes(
+        &self,
+        dependencies: &[BTreeSet<usize>],
+        needed: &[bool],
+    ) -> Result<Vec<usize>, anyhow::Error> {
+        let mut remaining: Vec<usize> = dependencies.iter().map(BTreeSet::len).collect();
+        let mut ready: BTreeSet<usize> = (0..self.passes.len())
+            .filter(|&index| needed[index] && remaining[index] == 0)
+            .collect();
+        let mut schedule = Vec::new();
+        while let Some(index) = ready.pop_first() {
+            schedule.push(index);
+            for (dependent, dependent_dependencies) in dependencies.iter().enumerate() {
+                if needed[dependent] && dependent_dependencies.contains(&index) {
+                    remaining[dependent] -= 1;
+                    if remaining[dependent] == 0 {
+                        ready.insert(dependent);
+                    }
+                }
+            }
+        }
+        let needed_count = needed.iter().filter(|&&needed| needed).count();
+        if schedule.len() < needed_count {
+            let cycle: Vec<&str> = (0..self.passes.len())
+                .filter(|&index| needed[index] && !schedule.contains(&index))
+                .map(|index| self.passes[index].name.as_str())
+                .collect();
+            return Err(anyhow!(
+                "Render graph has a dependency cycle between passes {}.",
+                cycle.join(", ")
+            ));
+        }
+        Ok(schedule)
+    }
+
+    fn check_transient_reads(&self) -> Result<(), anyhow::Error> {
+        for &pass_index in &self.schedule {
+            let pass = &self.passes[pass_index];
+            for access in pass.image_accesses.iter().filter(|access| !access.write) {
+                let image = &self.images[access.image];
+                let written = self.schedule.iter().any(|&other| {
+                    self.passes[other]
+                        .image_accesses
+                        .iter()
+                        .any(|other_access| {
+                            other_access.write && other_access.image == access.image
+                        })
+                });
+                if matches!(image.source, ImageSource::Transient(_)) && !written {
+                    return Err(anyhow!(
+                        "Pass {} reads transient image {}, which no pass writes.",
+                        pass.name,
+                        image.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_transient_images(
+        &mut self,
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &ash::Device,
+        resource_states: &mut ResourceStateTracker,
+    ) -> Result<(), anyhow::Error> {
+        let mut lifetimes: Vec<Option<(usize, usize)>> = vec![None; self.images.len()];
+        for (position, &pass_index) in self.schedule.iter().enumerate() {
+            for access in &self.passes[pass_index].image_accesses {
+                lifetimes[access.image]
+                    .get_or_insert((position, position))
+                    .1 = position;
+            }
+        }
+
+        let mut used: Vec<usize> = Vec::new();
+        for (index, lifetime) in lifetimes.into_iter().enumerate() {
+            let graph_image = &mut self.images[index];
+            let (ImageSource::Transient(desc), Some((first_use, last_use))) =
+                (&graph_image.source, lifetime)
+            else {
+                continue;
+            };
+            let image_create_info =
+                image::attachment_create_info(desc.format, desc.extent, desc.usage, desc.samples);
+            graph_image.image = unsafe { logical_device.create_image(&image_create_info, None)? };
+            graph_image.first_use = first_use;
+            graph_image.last_use = last_use;
+            used.push(index);
+        }
+        used.sort_by_key(|&index| self.images[index].first_use);
+
+        // Greedy interval assignment: an image reuses the first slot whose previous occupant
+        // is no longer needed and whose memory types are compatible.
+        let mut slots: Vec<MemorySlot> = Vec::new();
+        for &index in &used {
+            let graph_image = &self.images[index];
+            let requirements =
+                unsafe { logical_device.get_image_memory_requirements(graph_image.image) };
+            let slot = slots.iter_mut().find(|slot| {
+                slot.last_use < graph_image.first_use
+                    && slot.memory_type_bits & requirements.memory_type_bits != 0
+            });
+            match slot {
+                Some(slot) => {
+                    slot.size = slot.size.max(requirements.size);
+                    slot.alignment = slot.alignment.max(requirements.alignment);
+                    slot.memory_type_bits &= requirements.memory_type_bits;
+                    slot.last_use = graph_image.last_use;
+                    slot.occupants.push(index);
+                }
+                None => slots.push(MemorySlot {
+                    size: requirements.size,
+                    alignment: requirements.alignment,
+                    memory_type_bits: requirements.memory_type_bits,
+                    last_use: graph_image.last_use,
+                    occupants: vec![index],
+                }),
+            }
+        }
+
+        // One allocation per memory type, with the slots laid out one after another.
+        let mut allocations: HashMap<u32, vk::DeviceSize> = HashMap::new();
+        let mut placements = Vec::new();
+        for slot in &slots {
+            let memory_type_index = image::find_memory_type(
+                instance,
+                physical_device,
+                slot.memory_type_bits,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            let size = allocations.entry(memory_type_index).or_insert(0);
+            let offset = size.next_multiple_of(slot.alignment);
+            *size = offset + slot.size;
+            placements.push((memory_type_index, offset));
+        }
+        let mut memories = HashMap::new();
+        for (&memory_type_index, &size) in &allocations {
+            let memory_allocate_info = vk::MemoryAllocateInfo::default()
+                .allocation_size(size)
+                .memory_type_index(memory_type_index);
+            let memory = unsafe { logical_device.allocate_memory(&memory_allocate_info, None)? };
+            self.transient_memory.push(memory);
+            memories.insert(memory_type_index, memory);
+        }
+
+        for (slot, (memory_type_index, offset)) in slots.iter().zip(placements) {
+            let memory = memories[&memory_type_index];
+            for (position, &index) in slot.occupants.iter().enumerate() {
+                // The frame wraps around, so the first occupant follows the last one.
+                let previous =
+                    slot.occupants[(position + slot.occupants.len() - 1) % slot.occupants.len()];
+                let graph_image = &mut self.images[index];
+                graph_image.previous_occupant = (previous != index).then_some(previous);
+                let ImageSource::Transient(desc) = &graph_image.source else {
+                    unreachable!("only transient images are placed in memory slots");
+                };
+                unsafe { logical_device.bind_image_memory(graph_image.image, memory, offset)? };
+                let subresource_range = barriers::full_subresource_range(desc.aspect_mask);
+                let image_view_create_info = vk::ImageViewCreateInfo::default()
+                    .image(graph_image.image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(desc.format)
+                    .subresource_range(subresource_range);
+                graph_image.view =
+                    unsafe { logical_device.create_image_view(&image_view_create_info, None)? };
+                let first_usage = self.passes[self.schedule[graph_image.first_use]]
+                    .image_accesses
+                    .iter()
+                    .find(|access| access.image == index)
+                    .expect("the first pass using an image accesses it")
+                    .usage;
+                resource_states.register(
+                    graph_image.image,
+                    subresource_range,
+                    ImageState::discarded_after(first_usage),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Records every scheduled pass, preceded by the barriers it needs and timed under the pass's
+    // name.  `image_index` is the swapchain image being rendered, made available to passes through
+    // `PassResources`.
+    pub(crate) fn execute(
+        &self,
+        vulkan: &Vulkan,
+        resource_states: &mut ResourceStateTracker,
+        profiler: &RefCell<GpuProfiler>,
+        queries: &RefCell<GpuQueries>,
+        commandbuffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        let resources = PassResources {
+            graph: self,
+            image_index,
+            profiler,
+            queries,
+            logical_device: &vulkan.logical_device,
+            synchronization2: &vulkan.synchronization2,
+        };
+        for (position, &pass_index) in self.schedule.iter().enumerate() {
+            let pass = &self.passes[pass_index];
+            for access in &pass.image_accesses {
+                let image = &self.images[access.image];
+                let first_use = matches!(image.source, ImageSource::Transient(_))
+                    && image.first_use == position;
+                if first_use {
+                    // The memory was last used by another image; wait for that use to finish.
+                    if let Some(previous) = image.previous_occupant {
+                        let previous_state = resource_states
+                            .state(self.images[previous].image)
+                            .expect("transient images are tracked");
+                        let ImageSource::Transient(desc) = &image.source else {
+                            unreachable!();
+                        };
+                        resource_states.register(
+                            image.image,
+                            barriers::full_subresource_range(desc.aspect_mask),
+                            ImageState {
+                                layout: vk::ImageLayout::UNDEFINED,
+                                ..previous_state
+                            },
+                        );
+                    }
+                }
+                if access.discard || first_use {
+                    resource_states.transition_discarding(image.image, access.usage);
+                } else {
+                    resource_states.transition(image.image, access.usage);
+                }
+            }
+            for access in &pass.buffer_accesses {
+                resource_states.buffer_access(self.buffers[access.buffer].buffer, access.usage);
+            }
+            resource_states.flush(&vulkan.synchronization2, commandbuffer);
+            let execute = pass.execute.as_ref().expect("checked by compile");
+            resources.begin_scope(commandbuffer, &pass.name);
+            execute(vulkan, &resources, commandbuffer);
+            resources.end_scope(commandbuffer);
+            queries.borrow_mut().copy_occlusion_results(
+                &vulkan.logical_device,
+                &vulkan.synchronization2,
+                commandbuffer,
+            );
+        }
+        for image in &self.images {
+            if let ImageSource::Imported {
+                final_usage: Some(usage),
+            } = image.source
+            {
+                resource_states.transition(image.image, usage);
+            }
+        }
+        resource_states.flush(&vulkan.synchronization2, commandbuffer);
+    }
+
+    // Destroys the transient images.  The graph is left empty and must be rebuilt.
+    pub(crate) fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        resource_states: &mut ResourceStateTracker,
+    ) {
+        for image in &self.images {
+            if matches!(image.source, ImageSource::Transient(_)) && image.image != vk::Image::null()
+            {
+                resource_states.forget(image.image);
+                unsafe {
+                    logical_device.destroy_image_view(image.view, None);
+                    logical_device.destroy_image(image.image, None);
+                }
+            }
+        }
+        for memory in self.transient_memory.drain(..) {
+            unsafe { logical_device.free_memory(memory, None) };
+        }
+        *self = Self::default();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Resource {
+    Image(usize),
+    Buffer(usize),
+}
+
+impl Pass {
+    // Each access as `(order, resource, write)`.
+    fn resources(&self) -> impl Iterator<Item = (usize, Resource, bool)> + '_ {
+        let images = self
+            .image_accesses
+            .iter()
+            .map(|access| (access.order, Resource::Image(access.image), access.write));
+        let buffers = self
+            .buffer_accesses
+            .iter()
+            .map(|access| (access.order, Resource::Buffer(access.buffer), access.write));
+        images.chain(buffers)
+    }
+}
+
+struct MemorySlot {
+    size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+    memory_type_bits: u32,
+    last_use: usize,
+    occupants: Vec<usize>,
+}
+
+// Declares what a pass uses.  Each image should be declared once per pass, with the usage it is
+// in while the pass runs.
+pub struct PassBuilder<'g> {
+    pass: &'g mut Pass,
+    accesses_declared: &'g mut usize,
+}
+
+impl PassBuilder<'_> {
+    pub fn read_image(self, name: &str, usage: ImageUsage) -> Self {
+        self.image_access(name, usage, false, false)
+    }
+
+    pub fn write_image(self, name: &str, usage: ImageUsage) -> Self {
+        self.image_access(name, usage, true, false)
+    }
+
+    // A write that doesn't depend on the previous contents, such as a cleared attachment.
+    pub fn clear_image(self, name: &str, usage: ImageUsage) -> Self {
+        self.image_access(name, usage, true, true)
+    }
+
+    fn image_access(self, name: &str, usage: ImageUsage, write: bool, discard: bool) -> Self {
+        self.pass.image_accesses.push(ImageAccess {
+            name: name.to_string(),
+            image: 0,
+            order: *self.accesses_declared,
+            usage,
+            write,
+            discard,
+        });
+        *self.accesses_declared += 1;
+        self
+    }
+
+    pub fn read_buffer(self, name: &str, usage: BufferUsage) -> Self {
+        self.buffer_access(name, usage, false)
+    }
+
+    pub fn write_buffer(self, name: &str, usage: BufferUsage) -> Self {
+        self.buffer_access(name, usage, true)
+    }
+
+    fn buffer_access(self, name: &str, usage: BufferUsage, write: bool) -> Self {
+        self.pass.buffer_accesses.push(BufferAccess {
+            name: name.to_string(),
+            buffer: 0,
+            order: *self.accesses_declared,
+            usage,
+            write,
+        });
+        *self.accesses_declared += 1;
+        self
+    }
+
+    // Keeps the pass even if nothing reads what it writes.
+    pub fn side_effects(self) -> Self {
+        self.pass.side_effects = true;
+        self
+    }
+
+    pub fn execute(self, execute: impl Fn(&Vulkan, &PassResources, vk::CommandBuffer) + 'static) {
+        self.pass.execute = Some(Box::new(execute));
+    }
+}
+
+// The resources of a graph as seen from inside a pass.
+pub struct PassResources<'g> {
+    graph: &'g RenderGraph,
+    image_index: usize,
+    profiler: &'g RefCell<GpuProfiler>,
+    queries: &'g RefCell<GpuQueries>,
+    logical_device: &'g ash::Device,
+    synchronization2: &'g ash::khr::synchronization2::Device,
+}
+
+impl PassResources<'_> {
+    pub fn image_index(&self) -> usize {
+        self.image_index
+    }
+
+    // Times the commands recorded until the matching `end_scope`; see `GpuProfiler`.
+    pub fn begin_scope(&self, commandbuffer: vk::CommandBuffer, label: &str) {
+        self.profiler
+            .borrow_mut()
+            .begin_scope(self.synchronization2, commandbuffer, label);
+    }
+
+    pub fn end_scope(&self, commandbuffer: vk::CommandBuffer) {
+        self.profiler
+            .borrow_mut()
+            .end_scope(self.synchronization2, commandbuffer);
+    }
+
+    // Query recording; see `GpuQueries` for the rules.
+    pub fn begin_occlusion(&self, commandbuffer: vk::CommandBuffer, label: &str) {
+        self.queries
+            .borrow_mut()
+            .begin_occlusion(self.logical_device, commandbuffer, label);
+    }
+
+    pub fn end_occlusion(&self, commandbuffer: vk::CommandBuffer) {
+        self.queries
+            .borrow_mut()
+            .end_occlusion(self.logical_device, commandbuffer);
+    }
+
+    pub fn begin_statistics(&self, commandbuffer: vk::CommandBuffer, label: &str) {
+        self.queries
+            .borrow_mut()
+            .begin_statistics(self.logical_device, commandbuffer, label);
+    }
+
+    pub fn end_statistics(&self, commandbuffer: vk::CommandBuffer) {
+        self.queries
+            .borrow_mut()
+            .end_statistics(self.logical_device, commandbuffer);
+    }
+
+    pub fn begin_conditional(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        label: &str,
+        inverted: bool,
+    ) -> bool {
+        self.queries
+            .borrow_mut()
+            .begin_conditional(commandbuffer, label, inverted)
+    }
+
+    pub fn end_conditional(&self, commandbuffer: vk::CommandBuffer) {
+        self.queries.borrow_mut().end_conditional(commandbuffer);
+    }
+
+    pub fn image(&self, name: &str) -> vk::Image {
+        self.graph_image(name).image
+    }
+
+    pub fn image_view(&self, name: &str) -> vk::ImageView {
+        self.graph_image(name).view
+    }
+
+    pub fn buffer(&self, name: &str) -> vk::Buffer {
+        let index = self
+            .graph
+            .buffer_index(name)
+            .unwrap_or_else(|| panic!("Buffer {} is not declared.", name));
+        self.graph.buffers[index].buffer
+    }
+
+    fn graph_image(&self, name: &str) -> &GraphImage {
+        let index = self
+            .graph
+            .image_index(name)
+            .unwrap_or_else(|| panic!("Image {} is not declared.", name));
+        &self.graph.images[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_nothing(_: &Vulkan, _: &PassResources, _: vk::CommandBuffer) {}
+
+    fn transient_desc() -> TransientImageDesc {
+        TransientImageDesc {
+            format: vk::Format::R8G8B8A8_UNORM,
+            extent: vk::Extent2D {
+                width: 16,
+                height: 16,
+            },
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            samples: vk::SampleCountFlags::TYPE_1,
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+        }
+    }
+
+    fn dependencies(graph: &mut RenderGraph) -> Vec<Vec<usize>> {
+        graph.resolve_accesses().unwrap();
+        graph
+            .dependencies()
+            .into_iter()
+            .map(|dependencies| dependencies.into_iter().collect())
+            .collect()
+    }
+
+    fn schedule(graph: &mut RenderGraph) -> Result<Vec<&str>, anyhow::Error> {
+        graph.resolve_accesses()?;
+        let dependencies = graph.dependencies();
+        let needed = graph.needed_passes(&dependencies);
+        let schedule = graph.sort_passes(&dependencies, &needed)?;
+        Ok(schedule
+            .into_iter()
+            .map(|index| graph.passes[index].name.as_str())
+            .collect())
+    }
+
+    #[test]
+    fn reads_depend_on_the_last_write_declared_before_them() {
+        let mut graph = RenderGraph::default();
+        graph.import_buffer("particles");
+        graph
+            .add_pass("simulate")
+            .write_buffer("particles", BufferUsage::StorageWrite)
+            .execute(record_nothing);
+        graph
+            .add_pass("draw")
+            .read_buffer("particles", BufferUsage::VertexInput)
+            .execute(record_nothing);
+        graph
+            .add_pass("advance")
+            .write_buffer("particles", BufferUsage::StorageWrite)
+            .execute(record_nothing);
+        graph
+            .add_pass("draw_again")
+            .read_buffer("particles", BufferUsage::VertexInput)
+            .execute(record_nothing);
+        assert_eq!(
+            dependencies(&mut graph),
+            vec![vec![], vec![0], vec![0, 1], vec![2]]
+        );
+    }
+
+    #[test]
+    fn writes_wait_for_the_reads_before_them() {
+        let mut graph = RenderGraph::default();
+        graph.import_image("history", None);
+        graph
+            .add_pass("resolve")
+            .read_image("history", ImageUsage::ShaderRead)
+            .side_effects()
+            .execute(record_nothing);
+        graph
+            .add_pass("sample")
+            .read_image("history", ImageUsage::ShaderRead)
+            .side_effects()
+            .execute(record_nothing);
+        graph
+            .add_pass("update")
+            .write_image("history", ImageUsage::Storage)
+            .execute(record_nothing);
+        assert_eq!(dependencies(&mut graph), vec![vec![], vec![], vec![0, 1]]);
+    }
+
+    #[test]
+    fn writes_follow_the_previous_write() {
+        let mut graph = RenderGraph::default();
+        graph.import_image("target", None);
+        for name in ["first", "second", "third"] {
+            graph
+                .add_pass(name)
+                .write_image("target", ImageUsage::ColorAttachment)
+                .execute(record_nothing);
+        }
+        assert_eq!(dependencies(&mut graph), vec![vec![], vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn passes_reading_and_writing_a_resource_do_not_depend_on_themselves() {
+        let mut graph = RenderGraph::default();
+        graph.import_buffer("counters");
+        for name in ["first", "second"] {
+            graph
+                .add_pass(name)
+                .read_buffer("counters", BufferUsage::StorageRead)
+                .write_buffer("counters", BufferUsage::StorageWrite)
+                .execute(record_nothing);
+        }
+        assert_eq!(dependencies(&mut graph), vec![vec![], vec![0]]);
+    }
+
+    #[test]
+    fn extended_passes_are_ordered_by_when_accesses_were_declared() {
+        let mut graph = RenderGraph::default();
+        graph.import_image("swapchain", Some(ImageUsage::Present));
+        graph.import_buffer("particles");
+        graph
+            .add_pass("scene")
+            .clear_image("swapchain", ImageUsage::ColorAttachment)
+            .execute(record_nothing);
+        graph
+            .add_pass("simulate")
+            .write_buffer("particles", BufferUsage::StorageWrite)
+            .execute(record_nothing);
+        graph
+            .extend_pass("scene")
+            .read_buffer("particles", BufferUsage::VertexInput);
+        assert_eq!(dependencies(&mut graph), vec![vec![1], vec![]]);
+        assert_eq!(schedule(&mut graph).unwrap(), vec!["simulate", "scene"]);
+    }
+
+    #[test]
+    fn passes_whose_results_are_never_used_are_culled() {
+        let mut graph = RenderGraph::default();
+        graph.import_image("swapchain", Some(ImageUsage::Present));
+        graph.create_image("bloom", transient_desc());
+        graph.create_image("unused", transient_desc());
+        graph
+            .add_pass("bloom")
+            .clear_image("bloom", ImageUsage::ColorAttachment)
+            .execute(record_nothing);
+        graph
+            .add_pass("debug_view")
+            .clear_image("unused", ImageUsage::ColorAttachment)
+            .execute(record_nothing);
+        graph
+            .add_pass("composite")
+            .read_image("bloom", ImageUsage::ShaderRead)
+            .write_image("swapchain", ImageUsage::ColorAttachment)
+            .execute(record_nothing);
+        graph
+            .add_pass("readback")
+            .side_effects()
+            .execute(record_nothing);
+        assert_eq!(
+            schedule(&mut graph).unwrap(),
+            vec!["bloom", "composite", "readback"]
+        );
+    }
+
+    #[test]
+    fn independent_passes_run_in_the_order_they_were_added() {
+        let mut graph = RenderGraph::default();
+        graph.import_image("swapchain", Some(ImageUsage::Present));
+        graph.import_buffer("lights");
+        graph.import_buffer("particles");
+        graph
+            .add_pass("scene")
+            .clear_image("swapchain", ImageUsage::ColorAttachment)
+            .read_buffer("lights", BufferUsage::StorageRead)
+            .execute(record_nothing);
+        graph
+            .add_pass("simulate")
+            .write_buffer("particles", BufferUsage::StorageWrite)
+            .execute(record_nothing);
+        graph
+            .add_pass("cull_lights")
+            .write_buffer("lights", BufferUsage::StorageWrite)
+            .execute(record_nothing);
+        assert_eq!(
+            schedule(&mut graph).unwrap(),
+            vec!["scene", "simulate", "cull_lights"]
+        );
+    }
+
+    #[test]
+    fn dependency_cycles_are_errors() {
+        let mut graph = RenderGraph::default();
+        graph.import_buffer("x");
+        graph.import_buffer("y");
+        graph
+            .add_pass("a")
+            .read_buffer("x", BufferUsage::StorageRead)
+            .execute(record_nothing);
+        graph
+            .add_pass("b")
+            .write_buffer("x", BufferUsage::StorageWrite)
+            .read_buffer("y", BufferUsage::StorageRead)
+            .execute(record_nothing);
+        graph
+            .extend_pass("a")
+            .write_buffer("y", BufferUsage::StorageWrite);
+        let err = schedule(&mut graph).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Render graph has a dependency cycle between passes a, b."
+        );
+    }
+}
@@ -0,0 +1,215 @@
+// Transient per-frame data: uniforms, streamed vertices and the like, written by the host and read
+// by the frame being recorded.  One persistently mapped, host-coherent buffer is split into a
+// region per frame in flight, and allocations bump through the current frame's region.  A region
+// is reclaimed whole once the frame timeline shows the frame that last used it has finished.
+//
+// Allocation only needs `&Vulkan`, so scene draws can allocate while they record.  The slices
+// handed out borrow `Vulkan`, which keeps them from outliving the frame: it can't be rendered
+// while they are held.
+
+use anyhow::anyhow;
+use ash::vk;
+use ash::Instance;
+use std::cell::Cell;
+
+use super::allocator::Allocator;
+use super::buffer::Buffer;
+use super::{Vulkan, FRAMES_IN_FLIGHT};
+
+pub struct TransientAllocation<'a> {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    // Mapped and coherent; visible to the GPU once the frame is submitted.
+    pub data: &'a mut [u8],
+}
+
+pub struct TransientRing {
+    buffer: Buffer,
+    mapped: *mut u8,
+    frame_size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+    // The frame allocations are currently made for, and how far into its region they have reached.
+    frame: Cell<u64>,
+    head: Cell<vk::DeviceSize>,
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment) * alignment
+}
+
+// Offsets may be bound as uniform or storage buffers, or as 32-bit index buffers.
+fn transient_alignment(limits: &vk::PhysicalDeviceLimits) -> vk::DeviceSize {
+    limits
+        .min_uniform_buffer_offset_alignment
+        .max(limits.min_storage_buffer_offset_alignment)
+        .max(4)
+}
+
+// Where `size` bytes go in the ring when `head` bytes of `frame`'s region are used, and the head
+// after them, or None if they don't fit in the region.
+fn place_transient(
+    head: vk::DeviceSize,
+    size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+    frame_size: vk::DeviceSize,
+    frame: u64,
+) -> Option<(vk::DeviceSize, vk::DeviceSize)> {
+    let offset = align_up(head, alignment);
+    if offset + size > frame_size {
+        return None;
+    }
+    let region = (frame % FRAMES_IN_FLIGHT as u64) * frame_size;
+    Some((region + offset, offset + size))
+}
+
+impl TransientRing {
+    pub fn new(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+        allocator: &Allocator,
+        logical_device: &ash::Device,
+        frame_size: vk::DeviceSize,
+    ) -> Result<Self, anyhow::Error> {
+        let limits = unsafe { instance.get_physical_device_properties(*physical_device) }.limits;
+        let alignment = transient_alignment(&limits);
+        // Every region starts aligned.
+        let frame_size = align_up(frame_size, alignment);
+        let buffer = Buffer::new(
+            allocator,
+            logical_device,
+            frame_size * FRAMES_IN_FLIGHT as vk::DeviceSize,
+            vk::BufferUsageFlags::UNIFORM_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::VERTEX_BUFFER
+                | vk::BufferUsageFlags::INDEX_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let mapped = buffer
+            .allocation
+            .mapped()
+            .expect("Host-visible memory is mapped.");
+        Ok(Self {
+            buffer,
+            mapped,
+            frame_size,
+            alignment,
+            frame: Cell::new(0),
+            head: Cell::new(0),
+        })
+    }
+
+    // Only safe once the device is idle.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        self.buffer.destroy(logical_device);
+    }
+}
+
+impl Vulkan {
+    // `size` bytes for the next frame rendered, at an offset aligned for binding as a uniform or
+    // storage buffer, including as a dynamic offset.  The first allocation of a frame waits for the
+    // frame that last used its region if that is still running.
+    pub fn allocate_transient(
+        &self,
+        size: vk::DeviceSize,
+    ) -> Result<TransientAllocation<'_>, anyhow::Error> {
+        let ring = self
+            .transient_ring
+            .as_ref()
+            .ok_or_else(|| anyhow!("No transient buffer; see `Options::transient_buffer_size`."))?;
+        let frame = self.frame_timeline.last_value() + 1;
+        if ring.frame.get() != frame {
+            self.frame_timeline.wait(
+                &self.logical_device,
+                frame.saturating_sub(FRAMES_IN_FLIGHT as u64),
+                u64::MAX,
+            )?;
+            ring.frame.set(frame);
+            ring.head.set(0);
+        }
+
+        let (offset, head) = place_transient(
+            ring.head.get(),
+            size,
+            ring.alignment,
+            ring.frame_size,
+            frame,
+        )
+        .ok_or_else(|| {
+            anyhow!(
+                "Transient allocation of {} bytes doesn't fit; {} of {} bytes this frame are used.",
+                size,
+                ring.head.get(),
+                ring.frame_size
+            )
+        })?;
+        ring.head.set(head);
+        // Allocations within a frame never overlap, and the region isn't reused before another
+        // frame is rendered, which the borrow of `self` prevents.
+        let data = unsafe {
+            std::slice::from_raw_parts_mut(ring.mapped.add(offset as usize), size as usize)
+        };
+        Ok(TransientAllocation {
+            buffer: ring.buffer.buffer,
+            offset,
+            data,
+        })
+    }
+
+    // Copies `data` into a transient allocation and returns where it went.
+    pub fn write_transient(
+        &self,
+        data: &[u8],
+    ) -> Result<(vk::Buffer, vk::DeviceSize), anyhow::Error> {
+        let allocation = self.allocate_transient(data.len() as vk::DeviceSize)?;
+        allocation.data.copy_from_slice(data);
+        Ok((allocation.buffer, allocation.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alignment_covers_uniform_storage_and_index_offsets() {
+        let limits = |uniform, storage| vk::PhysicalDeviceLimits {
+            min_uniform_buffer_offset_alignment: uniform,
+            min_storage_buffer_offset_alignment: storage,
+            ..Default::default()
+        };
+        assert_eq!(transient_alignment(&limits(256, 16)), 256);
+        assert_eq!(transient_alignment(&limits(64, 128)), 128);
+        assert_eq!(transient_alignment(&limits(1, 1)), 4);
+    }
+
+    #[test]
+    fn allocations_start_aligned() {
+        assert_eq!(place_transient(0, 10, 256, 1024, 0), Some((0, 10)));
+        assert_eq!(place_transient(10, 10, 256, 1024, 0), Some((256, 266)));
+        assert_eq!(place_transient(256, 4, 256, 1024, 0), Some((256, 260)));
+    }
+
+    #[test]
+    fn frames_allocate_from_their_own_region() {
+        let frames = FRAMES_IN_FLIGHT as u64;
+        for frame in 0..frames {
+            let region = frame * 1024;
+            assert_eq!(place_transient(0, 16, 256, 1024, frame), Some((region, 16)));
+            assert_eq!(
+                place_transient(768, 256, 256, 1024, frame),
+                Some((region + 768, 1024))
+            );
+        }
+        // Regions are reused once every frame in flight has had one.
+        assert_eq!(place_transient(0, 16, 256, 1024, frames), Some((0, 16)));
+    }
+
+    #[test]
+    fn allocations_past_the_region_fail() {
+        assert_eq!(place_transient(0, 1025, 256, 1024, 0), None);
+        assert_eq!(place_transient(1024, 1, 256, 1024, 1), None);
+        // Alignment padding counts against the region too.
+        assert_eq!(place_transient(800, 200, 256, 1024, 0), None);
+        assert_eq!(place_transient(1024, 0, 256, 1024, 0), Some((1024, 1024)));
+    }
+}
@@ -0,0 +1,143 @@
+// Samplers are immutable and few distinct ones are ever needed, so they are created on first use
+// and shared: asking twice for the same settings returns the same `vk::Sampler`.  They live until
+// `Vulkan` is dropped.
+
+use ash::vk;
+use std::collections::HashMap;
+
+use super::Vulkan;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+    // Clamped to the device's limit; ignored when the device lacks anisotropic filtering.
+    pub max_anisotropy: Option<f32>,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            address_mode_w: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: None,
+        }
+    }
+}
+
+impl SamplerDesc {
+    // Every address mode set to `address_mode`.
+    pub fn with_address_mode(self, address_mode: vk::SamplerAddressMode) -> Self {
+        Self {
+            address_mode_u: address_mode,
+            address_mode_v: address_mode,
+            address_mode_w: address_mode,
+            ..self
+        }
+    }
+}
+
+// `SamplerDesc` with the anisotropy as bits, so that it can be hashed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SamplerKey {
+    mag_filter: vk::Filter,
+    min_filter: vk::Filter,
+    mipmap_mode: vk::SamplerMipmapMode,
+    address_modes: [vk::SamplerAddressMode; 3],
+    max_anisotropy: Option<u32>,
+}
+
+impl From<&SamplerDesc> for SamplerKey {
+    fn from(desc: &SamplerDesc) -> Self {
+        Self {
+            mag_filter: desc.mag_filter,
+            min_filter: desc.min_filter,
+            mipmap_mode: desc.mipmap_mode,
+            address_modes: [
+                desc.address_mode_u,
+                desc.address_mode_v,
+                desc.address_mode_w,
+            ],
+            max_anisotropy: desc.max_anisotropy.map(f32::to_bits),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SamplerCache {
+    samplers: HashMap<SamplerKey, vk::Sampler>,
+}
+
+impl SamplerCache {
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    // Only safe once the device is idle.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        for (_, sampler) in self.samplers.drain() {
+            unsafe { logical_device.destroy_sampler(sampler, None) };
+        }
+    }
+}
+
+impl Vulkan {
+    // The shared sampler with these settings, created if it doesn't exist yet.
+    pub fn sampler(&mut self, desc: &SamplerDesc) -> Result<vk::Sampler, anyhow::Error> {
+        let key = SamplerKey::from(desc);
+        if let Some(sampler) = self.samplers.samplers.get(&key) {
+            return Ok(*sampler);
+        }
+        let sampler = self.create_sampler_object(desc)?;
+        self.samplers.samplers.insert(key, sampler);
+        Ok(sampler)
+    }
+
+    // A sampler of its own, not shared through the cache.
+    pub(super) fn create_sampler_object(
+        &self,
+        desc: &SamplerDesc,
+    ) -> Result<vk::Sampler, anyhow::Error> {
+        let max_anisotropy = desc
+            .max_anisotropy
+            .filter(|_| self.device_features.sampler_anisotropy)
+            .map(|max_anisotropy| {
+                let limits = unsafe {
+                    self.instance
+                        .get_physical_device_properties(self.physical_device)
+                }
+                .limits;
+                max_anisotropy.clamp(1.0, limits.max_sampler_anisotropy)
+            });
+        let sampler_create_info = vk::SamplerCreateInfo::default()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_mode_u)
+            .address_mode_v(desc.address_mode_v)
+            .address_mode_w(desc.address_mode_w)
+            .anisotropy_enable(max_anisotropy.is_some())
+            .max_anisotropy(max_anisotropy.unwrap_or(1.0))
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .border_color(vk::BorderColor::FLOAT_TRANSPARENT_BLACK);
+        let sampler = unsafe {
+            self.logical_device
+                .create_sampler(&sampler_create_info, None)?
+        };
+        Ok(sampler)
+    }
+
+    // How many distinct samplers have been created.
+    pub fn sampler_count(&self) -> usize {
+        self.samplers.len()
+    }
+}
@@ -0,0 +1,115 @@
+// Offline rendering of frame sequences, for videos and regression images.  Frames are rendered
+// on a fixed timestep and each is read back before the next is recorded, so the output doesn't
+// depend on how fast the machine is.
+
+use anyhow::anyhow;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use super::{Clock, Vulkan};
+
+pub enum SequenceOutput {
+    // Numbered PNGs in `directory`: `frame-00000.png`, `frame-00001.png` and so on.
+    Images { directory: PathBuf },
+    // Raw RGBA frames piped to an `ffmpeg` on the PATH, which encodes them to `path`.
+    Ffmpeg { path: PathBuf },
+}
+
+fn spawn_ffmpeg(
+    path: &Path,
+    width: u32,
+    height: u32,
+    timestep: Duration,
+) -> Result<Child, anyhow::Error> {
+    let child = Command::new("ffmpeg")
+        .args([
+            "-loglevel",
+            "error",
+            "-y",
+            "-f",
+            "rawvideo",
+            "-pixel_format",
+            "rgba",
+        ])
+        .args(["-video_size", &format!("{}x{}", width, height)])
+        .args(["-framerate", &format!("1000000000/{}", timestep.as_nanos())])
+        .args(["-i", "-", "-pix_fmt", "yuv420p"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("Failed to start ffmpeg: {}", err))?;
+    Ok(child)
+}
+
+impl Vulkan {
+    // Renders `frame_count` frames `timestep` apart, starting from time zero, and writes each
+    // one to `output`.  The clock in use beforehand is restored afterwards.  Only headless
+    // renderers can render sequences: presenting would hold every frame to the display's refresh
+    // rate.
+    pub fn render_sequence(
+        &mut self,
+        frame_count: u64,
+        timestep: Duration,
+        output: &SequenceOutput,
+    ) -> Result<(), anyhow::Error> {
+        if self.presentation.is_some() {
+            return Err(anyhow!(
+                "Frame sequences can only be rendered headlessly; use Vulkan::new_headless."
+            ));
+        }
+        if timestep.is_zero() {
+            return Err(anyhow!(
+                "The timestep of a frame sequence must be positive."
+            ));
+        }
+        if let SequenceOutput::Images { directory } = output {
+            std::fs::create_dir_all(directory)?;
+        }
+        let previous_clock = self.clock.clock();
+        self.set_clock(Clock::Fixed(timestep));
+        let extent = self.extent;
+        let mut ffmpeg = match output {
+            SequenceOutput::Ffmpeg { path } => {
+                Some(spawn_ffmpeg(path, extent.width, extent.height, timestep)?)
+            }
+            SequenceOutput::Images { .. } => None,
+        };
+
+        let mut result = Ok(());
+        for index in 0..frame_count {
+            let _span = tracing::info_span!("sequence_frame", index).entered();
+            result = self.capture_frame().and_then(|screenshot| {
+                if (screenshot.width, screenshot.height) != (extent.width, extent.height) {
+                    return Err(anyhow!("The target was resized during a frame sequence."));
+                }
+                match (output, &mut ffmpeg) {
+                    (SequenceOutput::Images { directory }, _) => {
+                        screenshot.save_png(&directory.join(format!("frame-{:05}.png", index)))
+                    }
+                    (SequenceOutput::Ffmpeg { .. }, Some(ffmpeg)) => Ok(ffmpeg
+                        .stdin
+                        .as_mut()
+                        .expect("ffmpeg's stdin is piped")
+                        .write_all(&screenshot.pixels)?),
+                    (SequenceOutput::Ffmpeg { .. }, None) => unreachable!("ffmpeg is running"),
+                }
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+        self.set_clock(previous_clock);
+
+        if let Some(mut ffmpeg) = ffmpeg {
+            // Closing stdin tells ffmpeg there are no more frames.
+            drop(ffmpeg.stdin.take());
+            let status = ffmpeg.wait()?;
+            if result.is_ok() && !status.success() {
+                result = Err(anyhow!("ffmpeg failed: {}", status));
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,218 @@
+// The skybox: a cubemap drawn first in the scene pass, so everything else is drawn over it.  It
+// covers the target with one triangle on the far plane, without depth testing or writes, and looks
+// up each pixel's direction from the camera's inverse view-projection matrix.  Its pipeline is
+// built against the scene pass like any mesh pipeline, when the first skybox is set.
+
+use anyhow::anyhow;
+use ash::vk;
+
+use super::texture::{Texture, TextureSet};
+use super::{PipelineOptions, Vulkan};
+use crate::shader;
+
+// Column-major, as GLSL expects.
+const IDENTITY: [f32; 16] = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+pub struct Skybox {
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    set_layout: vk::DescriptorSetLayout,
+    // None while no skybox is shown.
+    texture_set: Option<TextureSet>,
+    inverse_view_projection: [f32; 16],
+}
+
+impl Skybox {
+    // Only safe once the device is idle.  The texture set goes with its pool.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe {
+            logical_device.destroy_pipeline(self.pipeline, None);
+            logical_device.destroy_pipeline_layout(self.layout, None);
+            logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+impl Vulkan {
+    // Draws `cubemap` behind the scene from the next frame on.  It is sampled until the skybox is
+    // replaced or cleared, so it must not be destroyed before then.
+    pub fn set_skybox(
+        &mut self,
+        cubemap: &Texture,
+        sampler: vk::Sampler,
+    ) -> Result<(), anyhow::Error> {
+        if cubemap.layers != 6 {
+            return Err(anyhow!("Skyboxes need a cubemap."));
+        }
+        if self.skybox.is_none() {
+            self.skybox = Some(self.create_skybox()?);
+        }
+        let set_layout = self.skybox.as_ref().expect("created above").set_layout;
+        let texture_set = self
+            .texture_set_pools
+            .allocate(&self.logical_device, set_layout)?;
+        let image_info = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)
+            .image_view(cubemap.view())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(texture_set.set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info);
+        unsafe { self.logical_device.update_descriptor_sets(&[write], &[]) };
+
+        let skybox = self.skybox.as_mut().expect("created above");
+        if let Some(previous) = skybox.texture_set.replace(texture_set) {
+            self.destroy_texture_set(previous);
+        }
+        Ok(())
+    }
+
+    // Stops drawing the skybox from the next frame on.
+    pub fn clear_skybox(&mut self) {
+        if let Some(texture_set) = self
+            .skybox
+            .as_mut()
+            .and_then(|skybox| skybox.texture_set.take())
+        {
+            self.destroy_texture_set(texture_set);
+        }
+    }
+
+    // The inverse of the camera's projection times its view, with the view's translation removed
+    // so the sky doesn't move with the camera.  Column-major; the identity until set.
+    pub fn set_skybox_view(&mut self, inverse_view_projection: [f32; 16]) {
+        if let Some(skybox) = &mut self.skybox {
+            skybox.inverse_view_projection = inverse_view_projection;
+        }
+    }
+
+    fn create_skybox(&self) -> Result<Skybox, anyhow::Error> {
+        let vertex_shader_code = shader::builtin("skybox.vert")?;
+        let fragment_shader_code = shader::builtin("skybox.frag")?;
+
+        let bindings = [vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)];
+        let set_layout = unsafe {
+            self.logical_device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings),
+                None,
+            )?
+        };
+        let set_layouts = [set_layout];
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .size(size_of::<[f32; 16]>() as u32)];
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let layout = match unsafe {
+            self.logical_device
+                .create_pipeline_layout(&layout_info, None)
+        } {
+            Ok(layout) => layout,
+            Err(err) => {
+                unsafe {
+                    self.logical_device
+                        .destroy_descriptor_set_layout(set_layout, None)
+                };
+                return Err(err.into());
+            }
+        };
+
+        // The modules are only needed while the pipeline is created.
+        let mut modules = Vec::new();
+        let pipeline = [&vertex_shader_code, &fragment_shader_code]
+            .into_iter()
+            .try_for_each(|code| {
+                let module = unsafe {
+                    self.logical_device.create_shader_module(
+                        &vk::ShaderModuleCreateInfo::default().code(code),
+                        None,
+                    )?
+                };
+                modules.push(module);
+                Ok::<_, anyhow::Error>(())
+            })
+            .and_then(|()| {
+                Self::create_graphics_pipeline(
+                    &self.logical_device,
+                    &self.render_pass,
+                    &self.attachment_formats,
+                    &PipelineOptions {
+                        depth_test: false,
+                        depth_write: false,
+                        ..Default::default()
+                    },
+                    (modules[0], modules[1]),
+                    &vk::PipelineVertexInputStateCreateInfo::default(),
+                    vk::PrimitiveTopology::TRIANGLE_LIST,
+                    layout,
+                )
+            });
+        for module in modules {
+            unsafe { self.logical_device.destroy_shader_module(module, None) };
+        }
+        match pipeline {
+            Ok(pipeline) => Ok(Skybox {
+                pipeline,
+                layout,
+                set_layout,
+                texture_set: None,
+                inverse_view_projection: IDENTITY,
+            }),
+            Err(err) => {
+                unsafe {
+                    self.logical_device.destroy_pipeline_layout(layout, None);
+                    self.logical_device
+                        .destroy_descriptor_set_layout(set_layout, None);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Expects the viewport and scissor to have been set.
+    pub(super) fn record_skybox(&self, commandbuffer: vk::CommandBuffer) {
+        let Some(skybox) = &self.skybox else {
+            return;
+        };
+        let Some(texture_set) = &skybox.texture_set else {
+            return;
+        };
+        let push_constants: Vec<u8> = skybox
+            .inverse_view_projection
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        unsafe {
+            self.logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                skybox.pipeline,
+            );
+            self.logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                skybox.layout,
+                0,
+                &[texture_set.set],
+                &[],
+            );
+            self.logical_device.cmd_push_constants(
+                commandbuffer,
+                skybox.layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                &push_constants,
+            );
+            self.logical_device.cmd_draw(commandbuffer, 3, 1, 0, 0);
+        }
+    }
+}
@@ -0,0 +1,529 @@
+// Sampled images.  A texture's pixels are staged and copied on the transfer queue, which leaves
+// the image in SHADER_READ_ONLY_OPTIMAL for the next frame, after any mip levels to be generated
+// have been blitted at its start.  Shaders see textures through texture sets: descriptor sets of
+// combined image samplers matching a mesh pipeline's set 0.
+
+use anyhow::anyhow;
+use ash::vk;
+use std::path::Path;
+
+use super::barriers::{self, ImageUsage};
+use super::image::Image;
+use super::mesh::MeshPipeline;
+use super::mipmap::{self, MipChain};
+use super::Vulkan;
+use crate::image_file::{self, ColorSpace, DecodedImage, Ktx2Image};
+
+// Descriptor sets allocated per pool before another pool is created.
+const SETS_PER_POOL: u32 = 64;
+const SAMPLERS_PER_POOL: u32 = SETS_PER_POOL * 4;
+
+pub struct Texture {
+    pub(super) image: Image,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub mip_levels: u32,
+    // 1, or 6 for cubemaps.
+    pub layers: u32,
+}
+
+impl Texture {
+    pub fn image(&self) -> vk::Image {
+        self.image.image
+    }
+
+    pub fn view(&self) -> vk::ImageView {
+        self.image.view
+    }
+}
+
+pub struct TextureSet {
+    pub set: vk::DescriptorSet,
+    // The pool the set was allocated from, which it is returned to.
+    pool: vk::DescriptorPool,
+}
+
+// Pools of combined image sampler descriptor sets, added as earlier ones fill up.
+#[derive(Default)]
+pub struct TextureSetPools {
+    pools: Vec<vk::DescriptorPool>,
+}
+
+impl TextureSetPools {
+    fn create_pool(logical_device: &ash::Device) -> Result<vk::DescriptorPool, vk::Result> {
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(SAMPLERS_PER_POOL)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .max_sets(SETS_PER_POOL)
+            .pool_sizes(&pool_sizes);
+        unsafe { logical_device.create_descriptor_pool(&pool_create_info, None) }
+    }
+
+    pub(super) fn allocate(
+        &mut self,
+        logical_device: &ash::Device,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<TextureSet, anyhow::Error> {
+        let layouts = [layout];
+        if let Some(pool) = self.pools.last() {
+            let allocate_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(*pool)
+                .set_layouts(&layouts);
+            match unsafe { logical_device.allocate_descriptor_sets(&allocate_info) } {
+                Ok(sets) => {
+                    return Ok(TextureSet {
+                        set: sets[0],
+                        pool: *pool,
+                    })
+                }
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let pool = Self::create_pool(logical_device)?;
+        self.pools.push(pool);
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info)? };
+        Ok(TextureSet { set: sets[0], pool })
+    }
+
+    // Only safe once the device is idle.  Destroying the pools frees every set.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        for pool in self.pools.drain(..) {
+            unsafe { logical_device.destroy_descriptor_pool(pool, None) };
+        }
+    }
+}
+
+// Texel blocks of the formats textures can be created in: width and height in texels, and size
+// in bytes.  Uncompressed formats have 1x1 blocks.
+fn block_size(format: vk::Format) -> Option<(u32, u32, u32)> {
+    let texel = |size| Some((1, 1, size));
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SRGB => texel(1),
+        vk::Format::R8G8_UNORM | vk::Format::R8G8_SRGB => texel(2),
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB => texel(4),
+        vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SFLOAT => texel(8),
+        vk::Format::R32G32B32A32_SFLOAT => texel(16),
+        vk::Format::BC1_RGB_UNORM_BLOCK
+        | vk::Format::BC1_RGB_SRGB_BLOCK
+        | vk::Format::BC1_RGBA_UNORM_BLOCK
+        | vk::Format::BC1_RGBA_SRGB_BLOCK
+        | vk::Format::BC4_UNORM_BLOCK
+        | vk::Format::BC4_SNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK
+        | vk::Format::EAC_R11_UNORM_BLOCK
+        | vk::Format::EAC_R11_SNORM_BLOCK => Some((4, 4, 8)),
+        vk::Format::BC2_UNORM_BLOCK
+        | vk::Format::BC2_SRGB_BLOCK
+        | vk::Format::BC3_UNORM_BLOCK
+        | vk::Format::BC3_SRGB_BLOCK
+        | vk::Format::BC5_UNORM_BLOCK
+        | vk::Format::BC5_SNORM_BLOCK
+        | vk::Format::BC6H_UFLOAT_BLOCK
+        | vk::Format::BC6H_SFLOAT_BLOCK
+        | vk::Format::BC7_UNORM_BLOCK
+        | vk::Format::BC7_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK
+        | vk::Format::EAC_R11G11_UNORM_BLOCK
+        | vk::Format::EAC_R11G11_SNORM_BLOCK => Some((4, 4, 16)),
+        _ if is_astc(format) => {
+            // The LDR ASTC formats come in UNORM and SRGB pairs, in this order of block size.
+            const ASTC_BLOCKS: [(u32, u32); 14] = [
+                (4, 4),
+                (5, 4),
+                (5, 5),
+                (6, 5),
+                (6, 6),
+                (8, 5),
+                (8, 6),
+                (8, 8),
+                (10, 5),
+                (10, 6),
+                (10, 8),
+                (10, 10),
+                (12, 10),
+                (12, 12),
+            ];
+            let index = (format.as_raw() - vk::Format::ASTC_4X4_UNORM_BLOCK.as_raw()) / 2;
+            let (width, height) = ASTC_BLOCKS[index as usize];
+            Some((width, height, 16))
+        }
+        _ => None,
+    }
+}
+
+fn is_astc(format: vk::Format) -> bool {
+    (vk::Format::ASTC_4X4_UNORM_BLOCK.as_raw()..=vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw())
+        .contains(&format.as_raw())
+}
+
+fn is_bc(format: vk::Format) -> bool {
+    (vk::Format::BC1_RGB_UNORM_BLOCK.as_raw()..=vk::Format::BC7_SRGB_BLOCK.as_raw())
+        .contains(&format.as_raw())
+}
+
+fn is_etc2(format: vk::Format) -> bool {
+    (vk::Format::ETC2_R8G8B8_UNORM_BLOCK.as_raw()..=vk::Format::EAC_R11G11_SNORM_BLOCK.as_raw())
+        .contains(&format.as_raw())
+}
+
+// Bytes of a `extent` image in a format with blocks of `block_size`.
+fn level_size(
+    extent: vk::Extent2D,
+    (block_width, block_height, block_bytes): (u32, u32, u32),
+) -> usize {
+    extent.width.div_ceil(block_width) as usize
+        * extent.height.div_ceil(block_height) as usize
+        * block_bytes as usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mipmaps {
+    // Only the base level.
+    None,
+    // A full chain blitted from the base level on the GPU.  Formats that can't be blitted with
+    // linear filtering, such as compressed ones, get only the base level.
+    Generate,
+}
+
+impl Vulkan {
+    // Whether textures can be created in `format`: cinder knows its layout, the device can sample
+    // it, and for compressed formats the device feature is enabled.
+    pub fn supports_texture_format(&self, format: vk::Format) -> bool {
+        let enabled = if is_bc(format) {
+            self.device_features.texture_compression_bc
+        } else if is_etc2(format) {
+            self.device_features.texture_compression_etc2
+        } else if is_astc(format) {
+            self.device_features.texture_compression_astc_ldr
+        } else {
+            true
+        };
+        let format_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
+        };
+        block_size(format).is_some()
+            && enabled
+            && format_properties
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+    }
+
+    // A texture holding `pixels`, tightly packed rows top to bottom.  It can be sampled from the
+    // next frame rendered.
+    pub fn create_texture(
+        &mut self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        pixels: &[u8],
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        self.create_texture_with_levels(extent, format, &[pixels], mipmaps)
+    }
+
+    // A texture whose mip levels, largest first, are all given.  With `Mipmaps::Generate` and a
+    // single level the rest of the chain is generated.
+    pub fn create_texture_with_levels(
+        &mut self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        levels: &[&[u8]],
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        self.create_layered_texture(extent, format, 1, levels, mipmaps)
+    }
+
+    // As `create_texture_with_levels`, with each level holding `layers` images back to back.  Six
+    // layers make a cubemap, whose faces are in the order +X, -X, +Y, -Y, +Z, -Z.
+    pub(super) fn create_layered_texture(
+        &mut self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        layers: u32,
+        levels: &[&[u8]],
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        let block_size = block_size(format)
+            .ok_or_else(|| anyhow!("Can't create textures in format {:?}.", format))?;
+        if !self.supports_texture_format(format) {
+            return Err(anyhow!("Textures in format {:?} can't be sampled.", format));
+        }
+        if levels.is_empty() || levels.len() as u32 > mipmap::full_mip_levels(extent) {
+            return Err(anyhow!(
+                "A {}x{} texture can't have {} mip levels.",
+                extent.width,
+                extent.height,
+                levels.len()
+            ));
+        }
+        // Copies on a transfer-only queue need offsets that are multiples of 4 as well as of the
+        // block size, so levels are padded in the staging buffer.
+        let alignment = block_size.2.max(4) as usize;
+        let mut regions = Vec::with_capacity(levels.len());
+        let mut data = Vec::new();
+        for (level, pixels) in levels.iter().enumerate() {
+            let level_extent = mipmap::mip_extent(extent, level as u32);
+            let expected_size = level_size(level_extent, block_size) * layers as usize;
+            if pixels.len() != expected_size || expected_size == 0 {
+                return Err(anyhow!(
+                    "Level {} of a {}x{} {:?} texture needs {} bytes of pixels, not {}.",
+                    level,
+                    extent.width,
+                    extent.height,
+                    format,
+                    expected_size,
+                    pixels.len()
+                ));
+            }
+            regions.push(
+                vk::BufferImageCopy::default()
+                    .buffer_offset(data.len() as vk::DeviceSize)
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::default()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .mip_level(level as u32)
+                            .layer_count(layers),
+                    )
+                    .image_extent(vk::Extent3D {
+                        width: level_extent.width,
+                        height: level_extent.height,
+                        depth: 1,
+                    }),
+            );
+            data.extend_from_slice(pixels);
+            data.resize(data.len().next_multiple_of(alignment), 0);
+        }
+
+        let generate_mipmaps = mipmaps == Mipmaps::Generate
+            && levels.len() == 1
+            && mipmap::full_mip_levels(extent) > 1
+            && self.can_generate_mipmaps(format);
+        if mipmaps == Mipmaps::Generate && levels.len() == 1 && !generate_mipmaps {
+            tracing::warn!(
+                "Mipmaps can't be generated for {:?} textures; using only the base level.",
+                format
+            );
+        }
+        let mip_levels = if generate_mipmaps {
+            mipmap::full_mip_levels(extent)
+        } else {
+            levels.len() as u32
+        };
+        let mut usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        if generate_mipmaps {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
+
+        let mut image = self.create_texture_image(extent, format, layers, mip_levels, usage)?;
+
+        let subresource_range = barriers::full_subresource_range(vk::ImageAspectFlags::COLOR);
+        let released_usage = if generate_mipmaps {
+            ImageUsage::TransferDst
+        } else {
+            ImageUsage::ShaderRead
+        };
+        let uploaded = self.upload_to_image(
+            image.image,
+            subresource_range,
+            &regions,
+            &data,
+            released_usage,
+        );
+        if let Err(err) = uploaded {
+            image.destroy(&self.logical_device);
+            return Err(err);
+        }
+        if generate_mipmaps {
+            self.transfers.push_mip_chain(MipChain {
+                image: image.image,
+                extent,
+                levels: mip_levels,
+                layers,
+            });
+        }
+        self.resource_states.register(
+            image.image,
+            subresource_range,
+            ImageUsage::ShaderRead.state(),
+        );
+        Ok(Texture {
+            image,
+            format,
+            extent,
+            mip_levels,
+            layers,
+        })
+    }
+
+    // An image for a texture, cube-compatible when it has six layers.
+    pub(super) fn create_texture_image(
+        &self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        layers: u32,
+        mip_levels: u32,
+        usage: vk::ImageUsageFlags,
+    ) -> Result<Image, anyhow::Error> {
+        let flags = if layers == 6 {
+            if extent.width != extent.height {
+                return Err(anyhow!(
+                    "Cubemap faces must be square, not {}x{}.",
+                    extent.width,
+                    extent.height
+                ));
+            }
+            vk::ImageCreateFlags::CUBE_COMPATIBLE
+        } else {
+            vk::ImageCreateFlags::empty()
+        };
+        let image_create_info = vk::ImageCreateInfo::default()
+            .flags(flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        Image::new(
+            &self.allocator,
+            &self.logical_device,
+            &image_create_info,
+            vk::ImageAspectFlags::COLOR,
+        )
+    }
+
+    pub fn create_texture_from_image(
+        &mut self,
+        image: &DecodedImage,
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        self.create_texture(image.extent, image.format, &image.pixels, mipmaps)
+    }
+
+    // Decodes a PNG, JPEG or HDR file and uploads it.  To decode without blocking, see
+    // `load_image_async`.
+    pub fn load_texture(
+        &mut self,
+        path: &Path,
+        color_space: ColorSpace,
+        mipmaps: Mipmaps,
+    ) -> Result<Texture, anyhow::Error> {
+        let image = image_file::load_image(path, color_space)?;
+        self.create_texture_from_image(&image, mipmaps)
+    }
+
+    // Fails if the device can't sample the file's format; check `supports_texture_format` first
+    // to choose between files compressed for different devices.
+    pub fn create_texture_from_ktx2(
+        &mut self,
+        image: &Ktx2Image,
+    ) -> Result<Texture, anyhow::Error> {
+        let levels: Vec<&[u8]> = image.levels.iter().map(Vec::as_slice).collect();
+        let mipmaps = if image.generate_mipmaps {
+            Mipmaps::Generate
+        } else {
+            Mipmaps::None
+        };
+        self.create_layered_texture(image.extent, image.format, image.faces, &levels, mipmaps)
+    }
+
+    pub fn load_ktx2_texture(&mut self, path: &Path) -> Result<Texture, anyhow::Error> {
+        let image = image_file::load_ktx2(path)?;
+        self.create_texture_from_ktx2(&image)
+    }
+
+    // The texture is destroyed once the frames that might use it have finished.
+    pub fn destroy_texture(&mut self, texture: Texture) {
+        let mut image = texture.image;
+        self.resource_states.forget(image.image);
+        self.retire(move |logical_device| image.destroy(logical_device));
+    }
+
+    // A set for `pipeline` sampling each texture with its sampler, in binding order.
+    pub fn create_texture_set(
+        &mut self,
+        pipeline: &MeshPipeline,
+        textures: &[(&Texture, vk::Sampler)],
+    ) -> Result<TextureSet, anyhow::Error> {
+        if textures.len() != pipeline.textures as usize {
+            return Err(anyhow!(
+                "The pipeline samples {} textures, not {}.",
+                pipeline.textures,
+                textures.len()
+            ));
+        }
+        let texture_set = self
+            .texture_set_pools
+            .allocate(&self.logical_device, pipeline.texture_set_layout)?;
+        let image_infos: Vec<_> = textures
+            .iter()
+            .map(|(texture, sampler)| {
+                [vk::DescriptorImageInfo::default()
+                    .sampler(*sampler)
+                    .image_view(texture.view())
+                    .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)]
+            })
+            .collect();
+        let writes: Vec<_> = image_infos
+            .iter()
+            .enumerate()
+            .map(|(binding, image_info)| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(texture_set.set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(image_info)
+            })
+            .collect();
+        unsafe { self.logical_device.update_descriptor_sets(&writes, &[]) };
+        Ok(texture_set)
+    }
+
+    // The set is freed once the frames that might use it have finished.
+    pub fn destroy_texture_set(&mut self, texture_set: TextureSet) {
+        self.retire(move |logical_device| unsafe {
+            logical_device
+                .free_descriptor_sets(texture_set.pool, &[texture_set.set])
+                .expect("Failed to free a texture set.");
+        });
+    }
+
+    pub fn bind_texture_set(
+        &self,
+        commandbuffer: vk::CommandBuffer,
+        pipeline: &MeshPipeline,
+        texture_set: &TextureSet,
+    ) {
+        unsafe {
+            self.logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[texture_set.set],
+                &[],
+            );
+        }
+    }
+}
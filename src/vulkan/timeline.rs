@@ -0,0 +1,104 @@
+// Timeline semaphores (core in Vulkan 1.2) and work deferred until a timeline reaches a value.
+
+use ash::vk;
+use std::collections::VecDeque;
+
+// A counter advanced by the GPU as submissions complete.  Each submission signals the value
+// returned by `next_value`, so values are handed out in submission order.
+pub struct Timeline {
+    pub semaphore: vk::Semaphore,
+    last_value: u64,
+}
+
+impl Timeline {
+    pub fn new(logical_device: &ash::Device) -> Result<Self, vk::Result> {
+        let mut type_create_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore_create_info =
+            vk::SemaphoreCreateInfo::default().push_next(&mut type_create_info);
+        let semaphore = unsafe { logical_device.create_semaphore(&semaphore_create_info, None)? };
+        Ok(Self {
+            semaphore,
+            last_value: 0,
+        })
+    }
+
+    pub fn next_value(&mut self) -> u64 {
+        self.last_value += 1;
+        self.last_value
+    }
+
+    // The value signalled by the most recent submission; zero before the first one.
+    pub fn last_value(&self) -> u64 {
+        self.last_value
+    }
+
+    pub fn completed_value(&self, logical_device: &ash::Device) -> Result<u64, vk::Result> {
+        unsafe { logical_device.get_semaphore_counter_value(self.semaphore) }
+    }
+
+    pub fn is_complete(
+        &self,
+        logical_device: &ash::Device,
+        value: u64,
+    ) -> Result<bool, vk::Result> {
+        Ok(self.completed_value(logical_device)? >= value)
+    }
+
+    // Returns false if `timeout` nanoseconds pass before the timeline reaches `value`.
+    pub fn wait(
+        &self,
+        logical_device: &ash::Device,
+        value: u64,
+        timeout: u64,
+    ) -> Result<bool, vk::Result> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(&semaphores)
+            .values(&values);
+        match unsafe { logical_device.wait_semaphores(&wait_info, timeout) } {
+            Ok(()) => Ok(true),
+            Err(vk::Result::TIMEOUT) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        unsafe { logical_device.destroy_semaphore(self.semaphore, None) };
+    }
+}
+
+type Retired = Box<dyn FnOnce(&ash::Device)>;
+
+// Destruction of resources the GPU may still be using, run once a timeline passes the value the
+// resource was last used at.
+#[derive(Default)]
+pub struct RetireQueue {
+    pending: VecDeque<(u64, Retired)>,
+}
+
+impl RetireQueue {
+    // Values must not decrease between calls.
+    pub fn push(&mut self, value: u64, destroy: impl FnOnce(&ash::Device) + 'static) {
+        self.pending.push_back((value, Box::new(destroy)));
+    }
+
+    pub fn collect(&mut self, logical_device: &ash::Device, completed_value: u64) {
+        while let Some((value, _)) = self.pending.front() {
+            if *value > completed_value {
+                break;
+            }
+            let (_, destroy) = self.pending.pop_front().expect("checked above");
+            destroy(logical_device);
+        }
+    }
+
+    // Only safe once the device is idle.
+    pub fn destroy_all(&mut self, logical_device: &ash::Device) {
+        for (_, destroy) in self.pending.drain(..) {
+            destroy(logical_device);
+        }
+    }
+}
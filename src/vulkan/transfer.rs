@@ -0,0 +1,265 @@
+// Uploads on the dedicated transfer queue.  Each submission signals the transfer timeline; the
+// next frame waits for every transfer submitted before it.  The transfer queue family usually
+// differs from the graphics one, so written resources are released by the transfer queue and
+// acquired again at the start of that frame.  On devices with a single queue family both queues
+// are the same, and the release and acquire are ordinary barriers.
+
+use anyhow::anyhow;
+use ash::vk;
+
+use super::allocator::BufferRelocation;
+use super::barriers::ImageUsage;
+use super::buffer::Buffer;
+use super::cubemap::EquirectConversion;
+use super::mipmap::MipChain;
+use super::timeline::{RetireQueue, Timeline};
+use super::Vulkan;
+
+// Ownership acquisitions the next graphics submission must record before anything else, then the
+// cubemap conversions and mip chains to generate from the images acquired and the copies of
+// buffers being moved, in that order.
+#[derive(Default)]
+pub struct Acquires {
+    pub buffers: Vec<vk::BufferMemoryBarrier2<'static>>,
+    pub images: Vec<vk::ImageMemoryBarrier2<'static>>,
+    pub equirect_conversions: Vec<EquirectConversion>,
+    pub mip_chains: Vec<MipChain>,
+    pub buffer_relocations: Vec<BufferRelocation>,
+}
+
+pub struct Transfers {
+    pub timeline: Timeline,
+    // Staging buffers and command buffers, freed once the transfer timeline passes them.
+    retired: RetireQueue,
+    pending_acquires: Acquires,
+}
+
+impl Transfers {
+    pub fn new(logical_device: &ash::Device) -> Result<Self, vk::Result> {
+        Ok(Self {
+            timeline: Timeline::new(logical_device)?,
+            retired: RetireQueue::default(),
+            pending_acquires: Acquires::default(),
+        })
+    }
+
+    pub fn push_equirect_conversion(&mut self, conversion: EquirectConversion) {
+        self.pending_acquires.equirect_conversions.push(conversion);
+    }
+
+    pub fn push_mip_chain(&mut self, mip_chain: MipChain) {
+        self.pending_acquires.mip_chains.push(mip_chain);
+    }
+
+    pub fn push_buffer_relocation(&mut self, relocation: BufferRelocation) {
+        self.pending_acquires.buffer_relocations.push(relocation);
+    }
+
+    pub fn pending_acquires(&self) -> &Acquires {
+        &self.pending_acquires
+    }
+
+    // Once the acquires have been submitted.
+    pub fn clear_acquires(&mut self) {
+        self.pending_acquires = Acquires::default();
+    }
+
+    pub fn collect(&mut self, logical_device: &ash::Device) -> Result<(), vk::Result> {
+        let completed_value = self.timeline.completed_value(logical_device)?;
+        self.retired.collect(logical_device, completed_value);
+        Ok(())
+    }
+
+    // Only safe once the device is idle.
+    pub fn destroy(&mut self, logical_device: &ash::Device) {
+        self.retired.destroy_all(logical_device);
+        self.timeline.destroy(logical_device);
+    }
+}
+
+impl Vulkan {
+    // Copies `data` into `buffer` at `offset`, returning the transfer timeline value signalled
+    // once the copy has completed.  `buffer` needs TRANSFER_DST usage and exclusive sharing.
+    //
+    // The copy runs on the transfer queue, unordered with frames already submitted.  If one of them
+    // may still read the range written, the caller must wait for it first, e.g. with
+    // `wait_for_frame(frame_number())`; buffers not yet drawn from need no wait.
+    pub fn upload_to_buffer(
+        &mut self,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> Result<u64, anyhow::Error> {
+        if data.is_empty() {
+            return Err(anyhow!("Buffer uploads can't be empty."));
+        }
+        let size = data.len() as vk::DeviceSize;
+        let region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: offset,
+            size,
+        };
+        let ownership_transfer = vk::BufferMemoryBarrier2::default()
+            .buffer(buffer)
+            .offset(offset)
+            .size(size)
+            .src_queue_family_index(self.queue_family_indices.transfer)
+            .dst_queue_family_index(self.queue_family_indices.graphics);
+        let release = [ownership_transfer
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)];
+        let value = self.submit_upload(data, |vulkan, commandbuffer, staging| unsafe {
+            vulkan
+                .logical_device
+                .cmd_copy_buffer(commandbuffer, staging, buffer, &[region]);
+            let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(&release);
+            vulkan
+                .synchronization2
+                .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+        })?;
+
+        self.transfers.pending_acquires.buffers.push(
+            ownership_transfer
+                .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                .dst_access_mask(vk::AccessFlags2::MEMORY_READ),
+        );
+        Ok(value)
+    }
+
+    // Copies `data` into the subresources of `image` in `regions`, discarding the previous contents
+    // of all of `subresource_range`, and hands it to the graphics queue in the state of `usage`:
+    // `ImageUsage::ShaderRead`, or `ImageUsage::TransferDst` for mip levels still to be generated.
+    // `image` needs TRANSFER_DST usage and exclusive sharing, and must not be tracked by the state
+    // tracker until the upload has been acquired: callers register it afterwards.
+    pub(super) fn upload_to_image(
+        &mut self,
+        image: vk::Image,
+        subresource_range: vk::ImageSubresourceRange,
+        regions: &[vk::BufferImageCopy],
+        data: &[u8],
+        usage: ImageUsage,
+    ) -> Result<u64, anyhow::Error> {
+        let released = usage.state();
+        let to_transfer_dst = [vk::ImageMemoryBarrier2::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .dst_stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER)
+            .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)];
+        let ownership_transfer = vk::ImageMemoryBarrier2::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(released.layout)
+            .src_queue_family_index(self.queue_family_indices.transfer)
+            .dst_queue_family_index(self.queue_family_indices.graphics);
+        let release = [ownership_transfer
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)];
+        let value = self.submit_upload(data, |vulkan, commandbuffer, staging| unsafe {
+            let dependency_info =
+                vk::DependencyInfo::default().image_memory_barriers(&to_transfer_dst);
+            vulkan
+                .synchronization2
+                .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+            vulkan.logical_device.cmd_copy_buffer_to_image(
+                commandbuffer,
+                staging,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                regions,
+            );
+            let dependency_info = vk::DependencyInfo::default().image_memory_barriers(&release);
+            vulkan
+                .synchronization2
+                .cmd_pipeline_barrier2(commandbuffer, &dependency_info);
+        })?;
+
+        // Without an ownership transfer the release has already changed the layout, and the
+        // acquire is only there to make the writes visible.
+        let mut acquire = ownership_transfer
+            .dst_stage_mask(released.stage)
+            .dst_access_mask(released.access);
+        if self.queue_family_indices.transfer == self.queue_family_indices.graphics {
+            acquire = acquire.old_layout(released.layout);
+        }
+        self.transfers.pending_acquires.images.push(acquire);
+        Ok(value)
+    }
+
+    // Stages `data` and submits the commands `record` makes on the transfer queue to copy it out
+    // of the staging buffer, returning the transfer timeline value signalled once they complete.
+    fn submit_upload(
+        &mut self,
+        data: &[u8],
+        record: impl FnOnce(&Self, vk::CommandBuffer, vk::Buffer),
+    ) -> Result<u64, anyhow::Error> {
+        self.transfers.collect(&self.logical_device)?;
+
+        let size = data.len() as vk::DeviceSize;
+        let mut staging = Buffer::new(
+            &self.allocator,
+            &self.logical_device,
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        staging.write(0, data);
+
+        let commandbuf_allocate_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.command_pools.command_pool_transfer)
+            .command_buffer_count(1);
+        let commandbuffer = unsafe {
+            self.logical_device
+                .allocate_command_buffers(&commandbuf_allocate_info)
+        }?[0];
+        let commandbuffer_begininfo = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            self.logical_device
+                .begin_command_buffer(commandbuffer, &commandbuffer_begininfo)?;
+        }
+        record(self, commandbuffer, staging.buffer);
+        unsafe {
+            self.logical_device.end_command_buffer(commandbuffer)?;
+        }
+
+        let value = self.transfers.timeline.next_value();
+        let commandbuffer_infos =
+            [vk::CommandBufferSubmitInfo::default().command_buffer(commandbuffer)];
+        let signal_infos = [vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.transfers.timeline.semaphore)
+            .value(value)
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)];
+        let submit_info = vk::SubmitInfo2::default()
+            .command_buffer_infos(&commandbuffer_infos)
+            .signal_semaphore_infos(&signal_infos);
+        unsafe {
+            self.synchronization2.queue_submit2(
+                self.queues.transfer_queue,
+                &[submit_info],
+                vk::Fence::null(),
+            )?;
+        }
+
+        let command_pool = self.command_pools.command_pool_transfer;
+        self.transfers
+            .retired
+            .push(value, move |logical_device: &ash::Device| {
+                unsafe { logical_device.free_command_buffers(command_pool, &[commandbuffer]) };
+                staging.destroy(logical_device);
+            });
+        Ok(value)
+    }
+
+    // Whether the upload that returned `value` has completed, without blocking.
+    pub fn is_transfer_finished(&self, value: u64) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .transfers
+            .timeline
+            .is_complete(&self.logical_device, value)?)
+    }
+}
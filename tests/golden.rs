@@ -0,0 +1,212 @@
+// Golden-image tests.  Reference scenes are rendered headlessly on lavapipe, Mesa's software
+// rasterizer, so results don't depend on the GPU, and compared with the PNGs in `tests/golden`.
+// Tests are skipped when no lavapipe ICD can be found, unless `CI` or `CINDER_REQUIRE_LAVAPIPE` is
+// set, in which case they fail; set `CINDER_LAVAPIPE_ICD` to its JSON manifest if it lives
+// somewhere unusual.  Setting `CINDER_UPDATE_GOLDENS=1` rewrites the goldens
+// from what is rendered instead of comparing.  On failure the rendered image and a diff are
+// written to `target/golden-diffs`.
+
+use cinder::ash::vk;
+use cinder::{Options, Screenshot, Vulkan};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const GOLDEN_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+const DIFF_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/golden-diffs");
+const ICD_DIRECTORIES: &[&str] = &[
+    "/usr/share/vulkan/icd.d",
+    "/usr/local/share/vulkan/icd.d",
+    "/etc/vulkan/icd.d",
+];
+
+struct GoldenScene {
+    name: &'static str,
+    extent: vk::Extent2D,
+    options: Options,
+    // The largest difference allowed in any channel of any pixel.
+    tolerance: u8,
+}
+
+fn find_lavapipe_icd() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("CINDER_LAVAPIPE_ICD") {
+        return Some(PathBuf::from(path));
+    }
+    ICD_DIRECTORIES
+        .iter()
+        .filter_map(|directory| std::fs::read_dir(directory).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("lvp_icd") && name.ends_with(".json"))
+        })
+}
+
+// Restricts the Vulkan loader to lavapipe for the rest of the process.  Returns false if it can't
+// be found.
+fn use_lavapipe() -> bool {
+    static LAVAPIPE_ICD: OnceLock<Option<PathBuf>> = OnceLock::new();
+    LAVAPIPE_ICD
+        .get_or_init(|| {
+            let icd = find_lavapipe_icd()?;
+            // Older loaders only know the second name.
+            std::env::set_var("VK_DRIVER_FILES", &icd);
+            std::env::set_var("VK_ICD_FILENAMES", &icd);
+            Some(icd)
+        })
+        .is_some()
+}
+
+fn load_png(path: &Path) -> Result<Screenshot, anyhow::Error> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    buffer.truncate(info.buffer_size());
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], u8::MAX])
+            .collect(),
+        color_type => {
+            return Err(anyhow::anyhow!(
+                "{} has unsupported color type {:?}.",
+                path.display(),
+                color_type
+            ))
+        }
+    };
+    Ok(Screenshot {
+        width: info.width,
+        height: info.height,
+        pixels,
+        srgb: true,
+    })
+}
+
+struct Comparison {
+    mismatched_pixels: usize,
+    max_difference: u8,
+    // Mismatched pixels in red over a darkened copy of the rendered image.
+    diff: Screenshot,
+}
+
+fn compare(actual: &Screenshot, expected: &Screenshot, tolerance: u8) -> Comparison {
+    let mut mismatched_pixels = 0;
+    let mut max_difference = 0;
+    let mut diff_pixels = Vec::with_capacity(actual.pixels.len());
+    for (actual, expected) in actual
+        .pixels
+        .chunks_exact(4)
+        .zip(expected.pixels.chunks_exact(4))
+    {
+        let difference = actual
+            .iter()
+            .zip(expected)
+            .map(|(actual, expected)| actual.abs_diff(*expected))
+            .max()
+            .unwrap_or(0);
+        max_difference = max_difference.max(difference);
+        if difference > tolerance {
+            mismatched_pixels += 1;
+            diff_pixels.extend([u8::MAX, 0, 0, u8::MAX]);
+        } else {
+            diff_pixels.extend([actual[0] / 4, actual[1] / 4, actual[2] / 4, u8::MAX]);
+        }
+    }
+    Comparison {
+        mismatched_pixels,
+        max_difference,
+        diff: Screenshot {
+            width: actual.width,
+            height: actual.height,
+            pixels: diff_pixels,
+            srgb: actual.srgb,
+        },
+    }
+}
+
+fn check(scene: GoldenScene) {
+    if !use_lavapipe() {
+        // A missing driver must not pass silently where the tests are meant to run.
+        let required = ["CI", "CINDER_REQUIRE_LAVAPIPE"]
+            .iter()
+            .any(|name| std::env::var_os(name).is_some());
+        assert!(
+            !required,
+            "Golden test {} needs lavapipe, which was not found.",
+            scene.name
+        );
+        println!("Skipping golden test {}: lavapipe not found.", scene.name);
+        return;
+    }
+    let mut vulkan = Vulkan::new_headless(scene.extent, scene.options)
+        .expect("Failed to initialise Vulkan on lavapipe.");
+    let actual = vulkan.capture_frame().expect("Failed to capture a frame.");
+    drop(vulkan);
+
+    let golden_path = Path::new(GOLDEN_DIRECTORY).join(format!("{}.png", scene.name));
+    if std::env::var_os("CINDER_UPDATE_GOLDENS").is_some() {
+        std::fs::create_dir_all(GOLDEN_DIRECTORY).expect("Failed to create the golden directory.");
+        actual
+            .save_png(&golden_path)
+            .expect("Failed to write the golden image.");
+        println!("Updated {}.", golden_path.display());
+        return;
+    }
+    let expected = load_png(&golden_path).unwrap_or_else(|err| {
+        panic!("Failed to load {}: {}", golden_path.display(), err);
+    });
+    assert_eq!(
+        (actual.width, actual.height),
+        (expected.width, expected.height),
+        "{} was rendered at a different size to its golden image.",
+        scene.name
+    );
+
+    let comparison = compare(&actual, &expected, scene.tolerance);
+    if comparison.mismatched_pixels > 0 {
+        let diff_directory = Path::new(DIFF_DIRECTORY);
+        std::fs::create_dir_all(diff_directory).expect("Failed to create the diff directory.");
+        let actual_path = diff_directory.join(format!("{}.actual.png", scene.name));
+        let diff_path = diff_directory.join(format!("{}.diff.png", scene.name));
+        actual
+            .save_png(&actual_path)
+            .expect("Failed to write the rendered image.");
+        comparison
+            .diff
+            .save_png(&diff_path)
+            .expect("Failed to write the diff image.");
+        panic!(
+            "{}: {} pixels differ from the golden image by more than {} (at most {}); see {} and {}.",
+            scene.name,
+            comparison.mismatched_pixels,
+            scene.tolerance,
+            comparison.max_difference,
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn red_point() {
+    check(GoldenScene {
+        name: "red_point",
+        extent: vk::Extent2D {
+            width: 64,
+            height: 64,
+        },
+        // Without MSAA the point covers exactly four pixels.
+        options: Options {
+            msaa_samples: 1,
+            ..Default::default()
+        },
+        tolerance: 2,
+    });
+}